@@ -1,4 +1,6 @@
-use std::{env, fs, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashMap, fs, net::SocketAddr, num::NonZeroU64, path::PathBuf, time::Duration,
+};
 
 use anyhow::Context;
 use axum::async_trait;
@@ -8,7 +10,14 @@ use serde::Deserialize;
 
 use crate::{
     podman::podman_is_remote,
-    registry::{AuthProvider, UnverifiedCredentials},
+    registry::{
+        gc::{self, GcOptions},
+        quota::Quotas,
+        storage::s3::S3Options,
+        tag_rules::TagRule,
+        token::{self, Action, TokenIssuer},
+        AuthProvider, Role, UnverifiedCredentials,
+    },
 };
 
 #[derive(Debug, Default, Deserialize)]
@@ -68,9 +77,21 @@ impl AuthProvider for MasterKey {
         }
     }
 
-    /// Check if the given user has access to the given repo.
+    /// The master key grants access to everything.
     #[inline]
-    async fn has_access_to(&self, _username: &str, _namespace: &str, _image: &str) -> bool {
+    async fn role(&self, _username: &str) -> Role {
+        Role::Admin
+    }
+
+    /// Check if the given user may perform `action` on the given repo.
+    #[inline]
+    async fn has_access_to(
+        &self,
+        _username: &str,
+        _namespace: &str,
+        _image: &str,
+        _action: Action,
+    ) -> bool {
         true
     }
 }
@@ -105,20 +126,201 @@ impl Default for RockslideConfig {
 pub(crate) struct RegistryConfig {
     #[serde(default = "default_storage_path")]
     pub storage_path: PathBuf,
+    #[serde(default = "default_upload_ttl")]
+    pub upload_ttl: u64,
+    #[serde(default)]
+    pub backend: StorageBackend,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub token: TokenConfig,
+    #[serde(default)]
+    pub tag_rules: Vec<TagRule>,
+}
+
+impl RegistryConfig {
+    pub(crate) fn upload_ttl(&self) -> Duration {
+        Duration::from_secs(self.upload_ttl)
+    }
 }
 
 impl Default for RegistryConfig {
     fn default() -> Self {
         Self {
             storage_path: default_storage_path(),
+            upload_ttl: default_upload_ttl(),
+            backend: Default::default(),
+            s3: None,
+            gc: Default::default(),
+            quotas: Default::default(),
+            token: Default::default(),
+            tag_rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StorageBackend {
+    #[default]
+    Filesystem,
+    S3,
+    Memory,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: Secret<String>,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_s3_path_style")]
+    pub path_style: bool,
+}
+
+impl S3Config {
+    pub(crate) fn options(&self) -> anyhow::Result<S3Options> {
+        let endpoint = self
+            .endpoint
+            .parse()
+            .context("invalid S3 endpoint")
+            .context(self.endpoint.clone())?;
+
+        let mut prefix = self.prefix.trim_start_matches('/').to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
         }
+
+        Ok(S3Options {
+            endpoint,
+            bucket: self.bucket.clone(),
+            region: self.region.clone(),
+            access_key: self.access_key.clone(),
+            secret_key: self.secret_key.clone(),
+            prefix,
+            path_style: self.path_style,
+        })
     }
 }
 
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_s3_path_style() -> bool {
+    true
+}
+
+fn default_upload_ttl() -> u64 {
+    24 * 60 * 60
+}
+
 fn default_storage_path() -> PathBuf {
     "./rockslide-storage".into()
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GcConfig {
+    /// Zero is rejected, there is no such thing as continuous garbage collection.
+    #[serde(default)]
+    pub interval: Option<NonZeroU64>,
+    #[serde(default = "default_gc_grace_period")]
+    pub grace_period: u64,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl GcConfig {
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.interval.map(|secs| Duration::from_secs(secs.get()))
+    }
+
+    pub(crate) fn options(&self) -> GcOptions {
+        GcOptions {
+            dry_run: self.dry_run,
+            grace_period: Duration::from_secs(self.grace_period),
+        }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            grace_period: default_gc_grace_period(),
+            dry_run: false,
+        }
+    }
+}
+
+fn default_gc_grace_period() -> u64 {
+    gc::DEFAULT_GRACE_PERIOD.as_secs()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QuotaConfig {
+    #[serde(default)]
+    pub default: Option<u64>,
+    #[serde(default)]
+    pub repositories: HashMap<String, u64>,
+}
+
+impl QuotaConfig {
+    pub(crate) fn quotas(&self) -> Quotas {
+        Quotas::new(self.default, self.repositories.clone())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TokenConfig {
+    #[serde(default = "default_token_ttl")]
+    pub ttl: u64,
+    #[serde(default = "default_token_service")]
+    pub service: String,
+    #[serde(default)]
+    pub realm: Option<String>,
+}
+
+impl TokenConfig {
+    pub(crate) fn issuer(&self) -> TokenIssuer {
+        TokenIssuer::new(
+            self.service.clone(),
+            self.realm.clone(),
+            Duration::from_secs(self.ttl),
+        )
+    }
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            ttl: default_token_ttl(),
+            service: default_token_service(),
+            realm: None,
+        }
+    }
+}
+
+fn default_token_ttl() -> u64 {
+    token::DEFAULT_TOKEN_TTL.as_secs()
+}
+
+fn default_token_service() -> String {
+    token::DEFAULT_SERVICE.to_owned()
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct ContainerConfig {
@@ -161,14 +363,14 @@ fn default_http_bind() -> SocketAddr {
     }
 }
 
-pub(crate) fn load_config() -> anyhow::Result<Config> {
-    match env::args().len() {
-        0 | 1 => Ok(Default::default()),
-        2 => {
-            let arg = env::args().nth(1).expect("should have arg 1");
-            let contents = fs::read_to_string(&arg)
+/// Loads the configuration from the file named in `args`, if any.
+pub(crate) fn load_config(args: &[String]) -> anyhow::Result<Config> {
+    match args {
+        [] => Ok(Default::default()),
+        [arg] => {
+            let contents = fs::read_to_string(arg)
                 .context("could not read configuration file")
-                .context(arg)?;
+                .context(arg.clone())?;
             let cfg = toml::from_str(&contents).context("failed to parse configuration")?;
 
             Ok(cfg)
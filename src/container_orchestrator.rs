@@ -9,7 +9,11 @@ use std::{net::SocketAddr, path::Path, sync::Arc};
 use crate::podman::podman_is_remote;
 use crate::{
     podman::Podman,
-    registry::{storage::ImageLocation, ManifestReference, Reference, RegistryHooks},
+    registry::{
+        storage::{Digest, ImageLocation},
+        types::Manifest,
+        ManifestReference, Reference, RegistryHooks,
+    },
     reverse_proxy::ReverseProxy,
 };
 
@@ -247,17 +251,9 @@ impl ContainerOrchestrator {
         &self,
         manifest_reference: &ManifestReference,
     ) -> anyhow::Result<bool> {
-        // TODO: Make configurable?
-        let production_tag = "prod";
-
-        if matches!(manifest_reference.reference(), Reference::Tag(tag) if tag == production_tag) {
+        if is_production_reference(manifest_reference) {
             let location = manifest_reference.location();
-
-            let name = format!(
-                "rockslide---{}---{}",
-                location.repository(),
-                location.image()
-            );
+            let name = container_name(location);
 
             debug!(%name, "removing (potentially nonexistant) container");
 
@@ -266,13 +262,13 @@ impl ContainerOrchestrator {
                 .await
                 .context("failed to remove container")?;
 
-            let image_url = format!(
-                "{}/{}/{}:{}",
+            let image_name = format!(
+                "{}/{}/{}",
                 self.local_addr,
                 location.repository(),
                 location.image(),
-                production_tag
             );
+            let image_url = format!("{}:{}", image_name, PRODUCTION_TAG);
 
             debug!(%name, "loggging in");
 
@@ -281,7 +277,7 @@ impl ContainerOrchestrator {
                     &self.registry_credentials.0,
                     self.registry_credentials.1.as_str(),
                     self.local_addr.to_string().as_ref(),
-                    false,
+                    LOCAL_REGISTRY_TLS_VERIFY,
                 )
                 .await
                 .context("failed to login to local registry")?;
@@ -289,10 +285,30 @@ impl ContainerOrchestrator {
             // We always pull the container to ensure we have the latest version.
             debug!(%name, "pulling container");
 
-            self.podman
-                .pull(&image_url)
-                .await
-                .context("failed to pull container")?;
+            match self.resolve_platform_image(&image_url).await? {
+                Some(digest) => {
+                    // Multi-platform image, pull the matching image and tag it locally, so that
+                    // the container still refers to the production tag.
+                    let platform_url = format!("{}@sha256:{}", image_name, digest);
+
+                    debug!(%name, %platform_url, "pulling platform specific image");
+
+                    self.podman
+                        .pull(&platform_url, LOCAL_REGISTRY_TLS_VERIFY)
+                        .await
+                        .context("failed to pull container")?;
+                    self.podman
+                        .tag(&platform_url, &image_url)
+                        .await
+                        .context("failed to tag platform specific image")?;
+                }
+                None => {
+                    self.podman
+                        .pull(&image_url, LOCAL_REGISTRY_TLS_VERIFY)
+                        .await
+                        .context("failed to pull container")?;
+                }
+            }
 
             // Prepare volumes.
             let volume_base = manifest_reference.namespaced_dir(&self.volumes_dir);
@@ -305,7 +321,7 @@ impl ContainerOrchestrator {
             let image_json: Vec<ImageJson> = serde_json::from_value(image_json_raw)
                 .context("failed to deserialize image information")?;
             let volumes = image_json
-                .get(0)
+                .first()
                 .context("no information via inspect")?
                 .config
                 .volume_iter();
@@ -333,7 +349,7 @@ impl ContainerOrchestrator {
                 .rm()
                 .rmi()
                 .name(&name)
-                .tls_verify(false)
+                .tls_verify(LOCAL_REGISTRY_TLS_VERIFY)
                 .publish("127.0.0.1::8000")
                 .env("PORT", "8000")
                 .execute()
@@ -347,6 +363,70 @@ impl ContainerOrchestrator {
         }
     }
 
+    /// Determines the image to run if the given image is an image index.
+    ///
+    /// Returns the digest of the manifest matching the platform podman runs on, or `None` if the
+    /// image is not an index.
+    async fn resolve_platform_image(&self, image_url: &str) -> anyhow::Result<Option<Digest>> {
+        let raw = self
+            .podman
+            .manifest_inspect(image_url, LOCAL_REGISTRY_TLS_VERIFY)
+            .await
+            .context("failed to fetch image manifest")?;
+
+        let Manifest::Index(index) =
+            Manifest::from_slice(&raw).context("failed to parse image manifest")?
+        else {
+            return Ok(None);
+        };
+
+        let info: InfoJson = serde_json::from_value(
+            self.podman
+                .info()
+                .await
+                .context("failed to fetch podman host information")?,
+        )
+        .context("failed to deserialize podman host information")?;
+
+        let host = &info.host;
+        let variant = Some(host.variant.as_str()).filter(|variant| !variant.is_empty());
+        let digest = index
+            .platform_manifest(&host.os, &host.arch, variant)
+            .with_context(|| {
+                format!(
+                    "image index contains no image for {}/{}{}",
+                    host.os,
+                    host.arch,
+                    variant
+                        .map(|variant| format!("/{}", variant))
+                        .unwrap_or_default()
+                )
+            })?;
+
+        Ok(Some(digest))
+    }
+
+    async fn remove_container(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> anyhow::Result<bool> {
+        if is_production_reference(manifest_reference) {
+            let name = container_name(manifest_reference.location());
+
+            debug!(%name, "removing container of deleted image");
+
+            self.podman
+                .rm(&name, true)
+                .await
+                .context("failed to remove container")?;
+
+            info!(%name, %manifest_reference, "production image removed");
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub(crate) async fn synchronize_all(&self) -> anyhow::Result<()> {
         info!("synchronizing rockslide managed containers");
         for container in self.fetch_managed_containers(true).await? {
@@ -362,6 +442,24 @@ impl ContainerOrchestrator {
     }
 }
 
+// TODO: Make configurable?
+const PRODUCTION_TAG: &str = "prod";
+
+/// Whether podman verifies TLS when talking to the registry, which is local and served via HTTP.
+const LOCAL_REGISTRY_TLS_VERIFY: bool = false;
+
+fn is_production_reference(manifest_reference: &ManifestReference) -> bool {
+    matches!(manifest_reference.reference(), Reference::Tag(tag) if tag == PRODUCTION_TAG)
+}
+
+fn container_name(location: &ImageLocation) -> String {
+    format!(
+        "rockslide---{}---{}",
+        location.repository(),
+        location.image()
+    )
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 #[allow(dead_code)]
@@ -373,6 +471,20 @@ struct ContainerJson {
     ports: Vec<PortMapping>,
 }
 
+#[derive(Debug, Deserialize)]
+struct InfoJson {
+    host: HostJson,
+}
+
+#[derive(Debug, Deserialize)]
+struct HostJson {
+    arch: String,
+    os: String,
+    /// Empty or missing unless the architecture has variants, e.g. `v7` for `arm`.
+    #[serde(default)]
+    variant: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct ImageJson {
@@ -486,7 +598,7 @@ impl ContainerJson {
     }
 
     fn active_published_port(&self) -> Option<&PortMapping> {
-        self.ports.get(0)
+        self.ports.first()
     }
 }
 
@@ -499,6 +611,14 @@ impl RegistryHooks for Arc<ContainerOrchestrator> {
 
         self.updated_published_set().await;
     }
+
+    async fn on_manifest_deleted(&self, manifest_reference: &ManifestReference) {
+        if let Err(err) = self.remove_container(manifest_reference).await {
+            warn!(%manifest_reference, %err, "could not remove container of deleted manifest");
+        }
+
+        self.updated_published_set().await;
+    }
 }
 
 fn nullable_array<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
@@ -5,6 +5,7 @@ pub(crate) mod registry;
 mod reverse_proxy;
 
 use std::{
+    env,
     net::{IpAddr, SocketAddr, ToSocketAddrs},
     sync::Arc,
 };
@@ -13,33 +14,49 @@ use anyhow::Context;
 use axum::{extract::DefaultBodyLimit, Router};
 
 use gethostname::gethostname;
-use registry::ContainerRegistry;
+use registry::{
+    deploy_tokens::DeployTokens,
+    fsck::{self, FsckOptions},
+    storage::{memory::MemoryStorage, s3::S3Storage, FilesystemStorage, RegistryStorage},
+    tag_rules::TagRules,
+    users::UserStore,
+    ContainerRegistry,
+};
 use reverse_proxy::ReverseProxy;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, info};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{debug, info, warn};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    config::load_config, container_orchestrator::ContainerOrchestrator, podman::podman_is_remote,
+    config::{load_config, Config, RegistryConfig, StorageBackend},
+    container_orchestrator::ContainerOrchestrator,
+    podman::podman_is_remote,
 };
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("fsck") {
+        return fsck(&args[1..]).await;
+    }
+
     // Parse configuration, if available, otherwise use a default.
-    let cfg = load_config().context("could not load configuration")?;
+    let cfg = load_config(&args).context("could not load configuration")?;
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| (&cfg.rockslide.log).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    init_tracing(&cfg, std::io::stdout);
 
     info!(?cfg, "loaded configuration");
 
     let rockslide_pw = cfg.rockslide.master_key.as_secret_string();
-    let auth_provider = Arc::new(cfg.rockslide.master_key);
+    let users = Arc::new(
+        UserStore::open(&cfg.registry.storage_path, cfg.rockslide.master_key)
+            .context("could not open user database")?,
+    );
+    let deploy_tokens = Arc::new(
+        DeployTokens::open(&cfg.registry.storage_path, users.clone())
+            .context("could not open deploy token database")?,
+    );
 
     let local_ip: IpAddr = if podman_is_remote() {
         debug!("podman instance is remote, trying to guess our external IP address");
@@ -65,7 +82,7 @@ async fn main() -> anyhow::Result<()> {
 
     info!(%local_addr, "guessed local registry (i.e. our) address");
 
-    let reverse_proxy = ReverseProxy::new(auth_provider.clone());
+    let reverse_proxy = ReverseProxy::new(deploy_tokens.clone());
 
     let credentials = ("rockslide-podman".to_owned(), rockslide_pw);
     let orchestrator = Arc::new(ContainerOrchestrator::new(
@@ -81,10 +98,28 @@ async fn main() -> anyhow::Result<()> {
     orchestrator.synchronize_all().await?;
     orchestrator.updated_published_set().await;
 
-    let registry = ContainerRegistry::new(&cfg.registry.storage_path, orchestrator, auth_provider)?;
+    let storage = open_storage(&cfg.registry, true)?;
+    let registry = ContainerRegistry::new(
+        storage,
+        orchestrator,
+        deploy_tokens.clone(),
+        cfg.registry.quotas.quotas(),
+        cfg.registry.token.issuer(),
+        TagRules::new(cfg.registry.tag_rules.clone()),
+    );
+
+    registry.spawn_upload_reaper(cfg.registry.upload_ttl());
+    deploy_tokens.spawn_usage_writer();
+
+    if let Some(interval) = cfg.registry.gc.interval() {
+        info!(?interval, "scheduling registry garbage collection");
+        registry.spawn_scheduled_gc(interval, cfg.registry.gc.options());
+    }
 
     let app = Router::new()
         .merge(registry.make_router())
+        .merge(users.make_router())
+        .merge(deploy_tokens.make_router())
         .merge(reverse_proxy.make_router())
         .layer(DefaultBodyLimit::max(1024 * 1024)) // See #43.
         .layer(TraceLayer::new_for_http());
@@ -98,3 +133,71 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Opens the configured registry storage.
+///
+/// With `recover` set, leftovers of a previous crash are cleaned up, which must only happen when
+/// no other instance is using the storage.
+fn open_storage(cfg: &RegistryConfig, recover: bool) -> anyhow::Result<Box<dyn RegistryStorage>> {
+    match cfg.backend {
+        StorageBackend::Filesystem => {
+            let storage = FilesystemStorage::new(&cfg.storage_path)?;
+            if recover {
+                storage.recover()?;
+            }
+            Ok(Box::new(storage))
+        }
+        StorageBackend::S3 => {
+            let s3 = cfg
+                .s3
+                .as_ref()
+                .context("the s3 backend requires a `[registry.s3]` section")?;
+            info!(endpoint = %s3.endpoint, bucket = %s3.bucket, "using S3 storage backend");
+            Ok(Box::new(S3Storage::new(s3.options()?)))
+        }
+        StorageBackend::Memory => {
+            warn!("using in-memory storage backend, registry contents are lost on exit");
+            Ok(Box::new(MemoryStorage::new()))
+        }
+    }
+}
+
+fn init_tracing<W>(cfg: &Config, writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| (&cfg.rockslide.log).into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
+        .init();
+}
+
+/// Checks registry storage integrity, invoked as `rockslide fsck [--repair] [CONFIG]`.
+///
+/// Meant to be run while rockslide is stopped. The report is printed to stdout as JSON.
+async fn fsck(args: &[String]) -> anyhow::Result<()> {
+    let repair = args.iter().any(|arg| arg == "--repair");
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--repair")
+        .cloned()
+        .collect();
+
+    let cfg = load_config(&args).context("could not load configuration")?;
+    // Keep stdout clean for the report.
+    init_tracing(&cfg, std::io::stderr);
+
+    let storage = open_storage(&cfg.registry, false)?;
+    let report = fsck::check_storage(storage.as_ref(), FsckOptions { repair }).await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if !report.is_clean() {
+        anyhow::bail!("storage check found {} problem(s)", report.problems());
+    }
+
+    Ok(())
+}
@@ -39,6 +39,13 @@ impl Podman {
         fetch_json(cmd).await
     }
 
+    pub(crate) async fn info(&self) -> Result<serde_json::Value, CommandError> {
+        let mut cmd = self.mk_podman_command();
+        cmd.arg("info");
+        cmd.args(["--format", "json"]);
+        fetch_json(cmd).await
+    }
+
     pub(crate) async fn login(
         &self,
         username: &str,
@@ -69,6 +76,24 @@ impl Podman {
         Ok(())
     }
 
+    /// Retrieves the raw manifest of an image from its registry.
+    pub(crate) async fn manifest_inspect(
+        &self,
+        image: &str,
+        tls_verify: bool,
+    ) -> Result<Vec<u8>, CommandError> {
+        let mut cmd = self.mk_podman_command();
+        cmd.args(["manifest", "inspect"]);
+
+        if !tls_verify {
+            cmd.arg("--tls-verify=false");
+        }
+
+        cmd.arg(image);
+
+        Ok(checked_output(cmd).await?.stdout)
+    }
+
     pub(crate) async fn ps(&self, all: bool) -> Result<serde_json::Value, CommandError> {
         let mut cmd = self.mk_podman_command();
         cmd.arg("ps");
@@ -82,18 +107,20 @@ impl Podman {
         fetch_json(cmd).await
     }
 
-    pub(crate) async fn pull(&self, image: &str) -> Result<(), CommandError> {
-        // TODO: Make `--tls-verify` configurable.
+    pub(crate) async fn pull(&self, image: &str, tls_verify: bool) -> Result<(), CommandError> {
         let mut cmd = self.mk_podman_command();
         cmd.arg("pull");
         cmd.arg(image);
-        cmd.arg("--tls-verify=false");
+
+        if !tls_verify {
+            cmd.arg("--tls-verify=false");
+        }
 
         checked_output(cmd).await?;
         Ok(())
     }
 
-    pub(crate) fn run(&self, image_url: &str) -> RunCommand {
+    pub(crate) fn run(&self, image_url: &str) -> RunCommand<'_> {
         RunCommand {
             podman: self,
             image_url: image_url.to_owned(),
@@ -121,6 +148,16 @@ impl Podman {
         checked_output(cmd).await
     }
 
+    pub(crate) async fn tag(&self, image: &str, target: &str) -> Result<(), CommandError> {
+        let mut cmd = self.mk_podman_command();
+        cmd.arg("tag");
+        cmd.arg(image);
+        cmd.arg(target);
+
+        checked_output(cmd).await?;
+        Ok(())
+    }
+
     fn mk_podman_command(&self) -> Command {
         let mut cmd = Command::new(&self.podman_path);
 
@@ -285,7 +322,7 @@ async fn checked_output(mut cmd: Command) -> Result<Output, CommandError> {
 
     if !output.status.success() {
         return Err(CommandError {
-            err: io::Error::new(io::ErrorKind::Other, "non-zero exit status"),
+            err: io::Error::other("non-zero exit status"),
             stdout: Some(output.stdout),
             stderr: Some(output.stderr),
         });
@@ -305,8 +342,8 @@ async fn fetch_json(cmd: Command) -> Result<serde_json::Value, CommandError> {
 
     trace!(raw = %String::from_utf8_lossy(&output.stdout), "parsing JSON");
 
-    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout)
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let parsed: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(io::Error::other)?;
 
     Ok(parsed)
 }
@@ -6,51 +6,82 @@
 //! * Manifest: https://github.com/opencontainers/image-spec/blob/main/manifest.md
 
 mod auth;
+pub(crate) mod database;
+pub(crate) mod deploy_tokens;
+pub(crate) mod fsck;
+pub(crate) mod gc;
 pub(crate) mod hooks;
+pub(crate) mod pattern;
+pub(crate) mod quota;
 pub(crate) mod storage;
-mod types;
+pub(crate) mod tag_rules;
+pub(crate) mod token;
+pub(crate) mod types;
+pub(crate) mod users;
 mod www_authenticate;
 
 use std::{
     fmt::{self, Display},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use self::{
     auth::ValidUser,
-    storage::{FilesystemStorage, ImageLocation, RegistryStorage},
-    types::{ImageManifest, OciError, OciErrors},
+    fsck::FsckOptions,
+    gc::GcOptions,
+    quota::Quotas,
+    storage::{ImageLocation, RegistryStorage},
+    tag_rules::TagRules,
+    token::{Access, Action, TokenIssuer},
+    types::{Catalog, ErrorCode, ImageIndex, Manifest, OciError, TagList},
 };
 use axum::{
+    async_trait,
     body::Body,
-    extract::{Path, Query, State},
+    extract::{self, rejection::PathRejection, FromRequestParts, Query, RawPathParams, State},
     http::{
-        header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, RANGE},
-        StatusCode,
+        header::{
+            ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LINK,
+            LOCATION, RANGE,
+        },
+        request::Parts,
+        HeaderMap, StatusCode,
     },
     response::{IntoResponse, Response},
-    routing::{get, head, patch, post, put},
+    routing::{delete, get, head, patch, post, put},
     Router,
 };
 use futures::stream::StreamExt;
 use hex::FromHex;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_util::io::ReaderStream;
-use tracing::info;
+use tracing::{error, info};
 use uuid::Uuid;
 
 pub(crate) use {
-    auth::{AuthProvider, UnverifiedCredentials},
+    auth::{AuthProvider, Role, UnverifiedCredentials},
     hooks::RegistryHooks,
-    storage::{FilesystemStorageError, ManifestReference, Reference},
+    storage::{ManifestReference, Reference},
 };
 
+/// Realm of basic authentication challenges.
+pub(crate) const REALM: &str = "ContainerRegistry";
+
+/// Upper bound for how often idle uploads are looked for.
+const MAX_UPLOAD_REAPER_PERIOD: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Debug)]
 enum AppError {
-    NotFound,
+    BlobUnknown(storage::Digest),
+    ManifestUnknown(ManifestReference),
+    NameUnknown(ImageLocation),
+    RangeNotSatisfiable,
+    Oci(OciError),
+    Storage(storage::Error),
     Internal(anyhow::Error),
 }
 
@@ -58,7 +89,12 @@ impl Display for AppError {
     #[inline(always)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AppError::NotFound => f.write_str("missing item"),
+            AppError::BlobUnknown(digest) => write!(f, "unknown blob {}", digest),
+            AppError::ManifestUnknown(reference) => write!(f, "unknown manifest {}", reference),
+            AppError::NameUnknown(location) => write!(f, "unknown repository {}", location),
+            AppError::RangeNotSatisfiable => f.write_str("chunk does not continue upload"),
+            AppError::Oci(err) => Display::fmt(err, f),
+            AppError::Storage(err) => Display::fmt(err, f),
             AppError::Internal(err) => Display::fmt(err, f),
         }
     }
@@ -78,54 +114,145 @@ impl IntoResponse for AppError {
     #[inline(always)]
     fn into_response(self) -> Response {
         match self {
-            // TODO: Need better OciError handling here. Not everything is blob unknown.
-            AppError::NotFound => (
-                StatusCode::NOT_FOUND,
-                OciErrors::single(OciError::new(types::ErrorCode::BlobUnknown)),
+            AppError::BlobUnknown(digest) => OciError::new(ErrorCode::BlobUnknown)
+                .with_detail(ImageDigest::new(digest).to_string())
+                .into_response(),
+            AppError::ManifestUnknown(reference) => OciError::new(ErrorCode::ManifestUnknown)
+                .with_detail(reference.to_string())
+                .into_response(),
+            AppError::NameUnknown(location) => OciError::new(ErrorCode::NameUnknown)
+                .with_detail(location.to_string())
+                .into_response(),
+            AppError::RangeNotSatisfiable => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                OciError::new(ErrorCode::BlobUploadInvalid)
+                    .with_message("chunk does not continue upload"),
             )
                 .into_response(),
-            AppError::Internal(err) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
-            }
+            AppError::Oci(err) => err.into_response(),
+            AppError::Storage(err) => err.into_response(),
+            // Storage errors passed through `?` end up here, but still deserve a proper answer.
+            AppError::Internal(err) => match err.downcast::<storage::Error>() {
+                Ok(err) => err.into_response(),
+                Err(err) => {
+                    error!(err = format!("{:#}", err), "internal error");
+                    OciError::new(ErrorCode::Unknown)
+                        .with_message(err.to_string())
+                        .into_response()
+                }
+            },
         }
     }
 }
 
+/// Path extractor, rejecting malformed names, references and digests with OCI errors.
+///
+/// Shadows axum's `Path`, which would answer with a plain text error instead.
+struct Path<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let err = match extract::Path::<T>::from_request_parts(parts, state).await {
+            Ok(extract::Path(value)) => return Ok(Path(value)),
+            Err(PathRejection::FailedToDeserializePathParams(err)) => err,
+            Err(err) => return Err(anyhow::anyhow!(err).into()),
+        };
+
+        // The deserialization error does not reliably tell which parameter was at fault, so we
+        // check the raw parameters ourselves.
+        let params = RawPathParams::from_request_parts(parts, state).await?;
+        let code = params
+            .iter()
+            .find_map(|(key, value)| match key {
+                "digest" if ImageDigest::from_str(value).is_err() => Some(ErrorCode::DigestInvalid),
+                "reference" if value.contains(':') && ImageDigest::from_str(value).is_err() => {
+                    Some(ErrorCode::DigestInvalid)
+                }
+                "upload" if Uuid::parse_str(value).is_err() => Some(ErrorCode::BlobUploadUnknown),
+                _ => None,
+            })
+            .unwrap_or(ErrorCode::NameInvalid);
+
+        Err(AppError::Oci(
+            OciError::new(code).with_detail(err.body_text()),
+        ))
+    }
+}
+
 pub(crate) struct ContainerRegistry {
     realm: String,
     auth_provider: Arc<dyn AuthProvider>,
     storage: Box<dyn RegistryStorage>,
     hooks: Box<dyn RegistryHooks>,
+    quotas: Quotas,
+    token_issuer: TokenIssuer,
+    tag_rules: TagRules,
+    /// Serializes manifest pushes checked against what is stored, i.e. subject to a quota or to
+    /// an immutable tag, and manifest deletions, so that concurrent changes cannot both pass the
+    /// checks.
+    manifest_lock: tokio::sync::Mutex<()>,
+    /// Held exclusively by garbage collection and shared by manifest pushes, which would
+    /// otherwise be able to reference a blob between it being marked unreferenced and removed.
+    gc_lock: tokio::sync::RwLock<()>,
+    /// Bytes written to uploads into repositories with a quota, which count towards it before
+    /// being finalized.
+    pending_uploads: quota::PendingUploads,
 }
 
 impl ContainerRegistry {
-    pub(crate) fn new<P: AsRef<std::path::Path>, T: RegistryHooks + 'static>(
-        storage_path: P,
+    pub(crate) fn new<T: RegistryHooks + 'static>(
+        storage: Box<dyn RegistryStorage>,
         orchestrator: T,
         auth_provider: Arc<dyn AuthProvider>,
-    ) -> Result<Arc<Self>, FilesystemStorageError> {
-        Ok(Arc::new(ContainerRegistry {
-            realm: "ContainerRegistry".to_string(),
-            auth_provider: auth_provider,
-            storage: Box::new(FilesystemStorage::new(storage_path)?),
+        quotas: Quotas,
+        token_issuer: TokenIssuer,
+        tag_rules: TagRules,
+    ) -> Arc<Self> {
+        Arc::new(ContainerRegistry {
+            realm: REALM.to_string(),
+            auth_provider,
+            storage,
             hooks: Box::new(orchestrator),
-        }))
+            quotas,
+            token_issuer,
+            tag_rules,
+            manifest_lock: Default::default(),
+            gc_lock: Default::default(),
+            pending_uploads: Default::default(),
+        })
     }
 
     pub(crate) fn make_router(self: Arc<ContainerRegistry>) -> Router {
         Router::new()
             .route("/v2/", get(index_v2))
+            .route("/v2/_catalog", get(catalog))
+            .route(token::TOKEN_PATH, get(token_get))
             .route("/v2/:repository/:image/blobs/:digest", head(blob_check))
             .route("/v2/:repository/:image/blobs/:digest", get(blob_get))
             .route("/v2/:repository/:image/blobs/uploads/", post(upload_new))
             .route(
-                "/v2/:repository/:image/uploads/:upload",
+                "/v2/:repository/:image/blobs/uploads/:upload",
+                get(upload_status),
+            )
+            .route(
+                "/v2/:repository/:image/blobs/uploads/:upload",
                 patch(upload_add_chunk),
             )
             .route(
-                "/v2/:repository/:image/uploads/:upload",
+                "/v2/:repository/:image/blobs/uploads/:upload",
                 put(upload_finalize),
             )
+            .route(
+                "/v2/:repository/:image/blobs/uploads/:upload",
+                delete(upload_cancel),
+            )
             .route(
                 "/v2/:repository/:image/manifests/:reference",
                 put(manifest_put),
@@ -134,96 +261,476 @@ impl ContainerRegistry {
                 "/v2/:repository/:image/manifests/:reference",
                 get(manifest_get),
             )
+            .route(
+                "/v2/:repository/:image/manifests/:reference",
+                head(manifest_head),
+            )
+            .route(
+                "/v2/:repository/:image/manifests/:reference",
+                delete(manifest_delete),
+            )
+            .route("/v2/:repository/:image/tags/list", get(tags_list))
+            .route(
+                "/v2/:repository/:image/referrers/:digest",
+                get(referrers_list),
+            )
+            .route("/_rockslide/registry/gc", post(admin_gc))
+            .route("/_rockslide/registry/fsck", post(admin_fsck))
+            .route("/_rockslide/registry/usage", get(admin_usage))
             .with_state(self)
     }
+
+    /// Runs a full garbage collection cycle, holding back manifest pushes until it has finished.
+    async fn collect_garbage(&self, options: GcOptions) -> Result<gc::GcReport, storage::Error> {
+        let _guard = self.gc_lock.write().await;
+        gc::collect_garbage(self.storage.as_ref(), options).await
+    }
+
+    /// Runs garbage collection every `interval` in the background.
+    pub(crate) fn spawn_scheduled_gc(self: &Arc<Self>, interval: Duration, options: GcOptions) {
+        let registry = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            // The first tick completes immediately, we do not want to collect right at startup.
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                match registry.collect_garbage(options).await {
+                    Ok(report) => info!(
+                        objects_removed = report.objects_removed(),
+                        bytes_freed = report.bytes_freed(),
+                        dry_run = options.dry_run,
+                        "scheduled garbage collection finished"
+                    ),
+                    Err(err) => error!(%err, "scheduled garbage collection failed"),
+                }
+            }
+        });
+    }
+
+    /// Periodically removes uploads that have been idle for longer than `ttl` in the background.
+    pub(crate) fn spawn_upload_reaper(self: &Arc<Self>, ttl: Duration) {
+        let registry = self.clone();
+
+        // Checking more often than necessary is cheap, but there is no need to be too eager.
+        let period = (ttl / 2).clamp(Duration::from_secs(1), MAX_UPLOAD_REAPER_PERIOD);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                match registry.storage.expire_uploads(ttl).await {
+                    Ok(expired) if expired.is_empty() => {}
+                    Ok(expired) => {
+                        for upload in &expired {
+                            registry.pending_uploads.remove(*upload);
+                        }
+                        info!(count = expired.len(), "expired idle uploads");
+                    }
+                    Err(err) => error!(%err, "failed to expire idle uploads"),
+                }
+            }
+        });
+    }
+}
+
+async fn index_v2(auth: Result<ValidUser, Response>) -> Response<Body> {
+    match auth {
+        Ok(_) => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::empty())
+            .unwrap(),
+        // Return `UNAUTHORIZED`, since we want the client to supply credentials.
+        Err(challenge) => challenge,
+    }
 }
 
-async fn index_v2(
+/// Token endpoint, exchanging credentials for a bearer token.
+///
+/// Every requested scope the user has access to is granted, others are silently dropped.
+async fn token_get(
     State(registry): State<Arc<ContainerRegistry>>,
+    Query(params): Query<Vec<(String, String)>>,
     credentials: Option<UnverifiedCredentials>,
 ) -> Response<Body> {
-    let realm = &registry.realm;
+    let credentials = match credentials {
+        Some(creds) if registry.auth_provider.check_credentials(&creds).await => creds,
+        _ => return auth::unauthorized(auth::basic_challenge(&registry.realm)),
+    };
 
-    if let Some(creds) = credentials {
-        if registry.auth_provider.check_credentials(&creds).await {
-            return Response::builder()
-                .status(StatusCode::OK)
-                .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
-                .body(Body::empty())
-                .unwrap();
+    let role = registry.auth_provider.role(&credentials.username).await;
+
+    let mut granted = Vec::new();
+    // Scopes may be given multiple times, or separated by spaces.
+    for raw in params
+        .iter()
+        .filter(|(key, _)| key == "scope")
+        .flat_map(|(_, value)| value.split_whitespace())
+    {
+        let Some(mut access) = Access::parse_scope(raw) else {
+            continue;
+        };
+        let Some((repository, image)) = access.repository_name() else {
+            continue;
+        };
+        let (repository, image) = (repository.to_owned(), image.to_owned());
+
+        // Actions the user may not perform are silently dropped, as the distribution spec suggests.
+        let mut permitted = Vec::new();
+        for &action in access.actions() {
+            if role >= Role::for_action(action)
+                && registry
+                    .auth_provider
+                    .has_access_to(&credentials.username, &repository, &image, action)
+                    .await
+            {
+                permitted.push(action);
+            }
+        }
+        access.retain_actions(|action| permitted.contains(&action));
+
+        if !access.actions().is_empty() {
+            granted.push(access);
         }
     }
 
-    // Return `UNAUTHORIZED`, since we want the client to supply credentials.
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
-        .body(Body::empty())
-        .unwrap()
+    info!(username = %credentials.username, scopes = granted.len(), "issued token");
+    types::json_response(&registry.token_issuer.issue(&credentials.username, granted))
 }
 
 async fn blob_check(
     State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
     Path((_, _, image)): Path<(String, String, ImageDigest)>,
     _auth: ValidUser,
 ) -> Result<Response, AppError> {
-    if let Some(metadata) = registry.storage.get_blob_metadata(image.digest).await? {
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header(CONTENT_LENGTH, metadata.size())
-            .header("Docker-Content-Digest", image.to_string())
-            .header(CONTENT_TYPE, "application/octet-stream")
-            .body(Body::empty())
-            .unwrap())
-    } else {
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty())
-            .unwrap())
-    }
+    let metadata = blob_metadata(&registry, &location, image.digest).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_LENGTH, metadata.size())
+        .header("Docker-Content-Digest", image.to_string())
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, format!("\"{}\"", image))
+        .body(Body::empty())
+        .unwrap())
 }
 
 async fn blob_get(
     State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
     Path((_, _, image)): Path<(String, String, ImageDigest)>,
+    headers: HeaderMap,
     _auth: ValidUser,
 ) -> Result<Response, AppError> {
-    // TODO: Get size for `Content-length` header.
+    let size = blob_metadata(&registry, &location, image.digest)
+        .await?
+        .size();
+
+    // Blobs are content addressed, so the digest makes for a perfect entity tag.
+    let etag = format!("\"{}\"", image);
+    let builder = Response::builder()
+        .header("Docker-Content-Digest", image.to_string())
+        .header(ETAG, &etag)
+        .header(ACCEPT_RANGES, "bytes");
+
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let matches = if_none_match
+            .to_str()?
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+
+        if matches {
+            return Ok(builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+
+    let range = match headers.get(RANGE) {
+        Some(raw) => match parse_range(raw.to_str()?, size) {
+            RangeRequest::Single(start, end) => Some((start, end)),
+            // Serving the whole blob is always a valid answer to a range request.
+            RangeRequest::Unsupported => None,
+            RangeRequest::Unsatisfiable => {
+                return Ok(builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        },
+        None => None,
+    };
+
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let length = if size == 0 { 0 } else { end - start + 1 };
 
     let reader = registry
         .storage
-        .get_blob_reader(image.digest)
+        .get_blob_reader(image.digest, start)
         .await?
-        .ok_or(AppError::NotFound)?;
+        .ok_or(AppError::BlobUnknown(image.digest))?;
 
-    let stream = ReaderStream::new(reader);
+    let stream = ReaderStream::new(reader.take(length));
     let body = Body::from_stream(stream);
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(body)
-        .unwrap())
+    let builder = builder
+        .header(CONTENT_LENGTH, length)
+        .header(CONTENT_TYPE, "application/octet-stream");
+
+    let builder = if range.is_some() {
+        builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+    } else {
+        builder.status(StatusCode::OK)
+    };
+
+    Ok(builder.body(body).unwrap())
+}
+
+/// Looks up a blob, which must belong to `location`.
+///
+/// Blobs of other locations are reported as unknown, otherwise access to one repository would
+/// allow reading any blob by its digest.
+async fn blob_metadata(
+    registry: &ContainerRegistry,
+    location: &ImageLocation,
+    digest: storage::Digest,
+) -> Result<storage::BlobMetadata, AppError> {
+    let metadata = registry
+        .storage
+        .get_blob_metadata(digest)
+        .await?
+        .ok_or(AppError::BlobUnknown(digest))?;
+
+    if !storage::is_blob_in_location(registry.storage.as_ref(), location, digest).await? {
+        return Err(AppError::BlobUnknown(digest));
+    }
+
+    Ok(metadata)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum RangeRequest {
+    /// A single range, both ends inclusive and within the blob.
+    Single(u64, u64),
+    /// Multiple ranges or other units, which we do not support.
+    Unsupported,
+    /// A range that lies outside of the blob.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header of a blob download, given the size of the blob.
+fn parse_range(raw: &str, size: u64) -> RangeRequest {
+    let Some(spec) = raw.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Unsupported;
+    };
+
+    if spec.contains(',') {
+        return RangeRequest::Unsupported;
+    }
+
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Unsupported;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix range, i.e. the last `n` bytes.
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return RangeRequest::Unsupported,
+        },
+        (start, "") => match start.parse() {
+            Ok(start) => (start, size.saturating_sub(1)),
+            Err(_) => return RangeRequest::Unsupported,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            _ => return RangeRequest::Unsupported,
+        },
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Single(start, end)
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadNewQuery {
+    digest: Option<ImageDigest>,
+    mount: Option<ImageDigest>,
+    from: Option<String>,
 }
 
 async fn upload_new(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(location): Path<ImageLocation>,
-    _auth: ValidUser,
-) -> Result<UploadState, AppError> {
+    Query(UploadNewQuery {
+        digest,
+        mount,
+        from,
+    }): Query<UploadNewQuery>,
+    auth: ValidUser,
+    request: axum::extract::Request,
+) -> Result<Response, AppError> {
+    // Blobs are stored independent of their location, so mounting requires no copying at all.
+    if let (Some(mount), Some(from)) = (mount, from) {
+        if can_mount(&registry, &auth, mount.digest, &from).await? {
+            registry.storage.link_blob(&location, mount.digest).await?;
+            info!(%location, %from, digest = %mount, "mounted blob");
+            return Ok(blob_created(&location, &mount));
+        }
+    }
+
     // Initiate a new upload
     let upload = registry.storage.begin_new_upload().await?;
 
+    // With a digest given, the entire blob is contained in the body (monolithic upload).
+    if let Some(digest) = digest {
+        append_to_upload(&registry, &location, upload, 0, request.into_body()).await?;
+        registry
+            .storage
+            .finalize_upload(upload, digest.digest)
+            .await?;
+        registry.storage.link_blob(&location, digest.digest).await?;
+        registry.pending_uploads.remove(upload);
+
+        info!(%upload, %digest, "new image uploaded");
+        return Ok(blob_created(&location, &digest));
+    }
+
     Ok(UploadState {
         location,
         completed: None,
         upload,
-    })
+    }
+    .into_response())
+}
+
+/// Checks whether a blob exists and may be mounted from the `from` location by the user.
+///
+/// The blob must be referenced by a manifest of `from`, being stored for any other location
+/// does not count.
+async fn can_mount(
+    registry: &ContainerRegistry,
+    auth: &ValidUser,
+    digest: storage::Digest,
+    from: &str,
+) -> Result<bool, AppError> {
+    let Some(from) = ImageLocation::parse(from) else {
+        return Ok(false);
+    };
+
+    if !auth.token_allows(&from.to_string(), Action::Pull) {
+        return Ok(false);
+    }
+
+    if !registry
+        .auth_provider
+        .has_access_to(
+            auth.username(),
+            from.repository(),
+            from.image(),
+            Action::Pull,
+        )
+        .await
+    {
+        return Ok(false);
+    }
+
+    if registry.storage.get_blob_metadata(digest).await?.is_none() {
+        return Ok(false);
+    }
+
+    Ok(storage::is_blob_referenced(registry.storage.as_ref(), &from, digest).await?)
+}
+
+/// Writes a request body into an upload, starting at `offset`.
+///
+/// Returns the total number of bytes uploaded afterwards. Uploads into a repository with a quota
+/// are checked as the body is written, exceeding the quota cancels the upload and clients have to
+/// start over after freeing space.
+async fn append_to_upload(
+    registry: &ContainerRegistry,
+    location: &ImageLocation,
+    upload: Uuid,
+    offset: u64,
+    body: Body,
+) -> Result<u64, AppError> {
+    let quota = match registry.quotas.limit(location) {
+        Some(limit) => {
+            let usage = quota::measure_usage(registry.storage.as_ref(), location).await?;
+            Some((limit, usage.bytes()))
+        }
+        None => None,
+    };
+
+    let mut writer = registry.storage.get_upload_writer(offset, upload).await?;
+
+    let mut body = body.into_data_stream();
+
+    let mut completed: u64 = offset;
+    while let Some(result) = body.next().await {
+        let chunk = result?;
+
+        if let Some((limit, stored)) = quota {
+            let reserved = registry.pending_uploads.reserve(
+                location,
+                upload,
+                offset,
+                chunk.len() as u64,
+                stored,
+                limit,
+            );
+            if let Err(bytes) = reserved {
+                info!(%location, %upload, bytes, limit, "upload exceeds quota");
+                drop(writer);
+                registry.pending_uploads.remove(upload);
+                registry.storage.cancel_upload(upload).await?;
+                return Err(quota_exceeded(location, bytes, limit));
+            }
+        }
+
+        completed += chunk.len() as u64;
+        writer.write_all(chunk.as_ref()).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(completed)
+}
+
+fn blob_created(location: &ImageLocation, digest: &ImageDigest) -> Response {
+    let repository = location.repository();
+    let image = location.image();
+
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, format!("/v2/{repository}/{image}/blobs/{digest}"))
+        .header(CONTENT_LENGTH, 0)
+        .header("Docker-Content-Digest", digest.to_string())
+        .body(Body::empty())
+        .unwrap()
 }
 
 fn mk_upload_location(location: &ImageLocation, uuid: Uuid) -> String {
     let repository = &location.repository();
     let image = &location.image();
-    format!("/v2/{repository}/{image}/uploads/{uuid}")
+    format!("/v2/{repository}/{image}/blobs/uploads/{uuid}")
 }
 
 #[derive(Debug)]
@@ -241,8 +748,9 @@ impl IntoResponse for UploadState {
             .header("Docker-Upload-UUID", self.upload.to_string());
 
         if let Some(completed) = self.completed {
+            // The range is inclusive, an empty upload is reported as `0-0`.
             builder = builder
-                .header(RANGE, format!("0-{}", completed))
+                .header(RANGE, format!("0-{}", completed.saturating_sub(1)))
                 .status(StatusCode::ACCEPTED)
         } else {
             builder = builder
@@ -345,24 +853,35 @@ async fn upload_add_chunk(
     _auth: ValidUser,
     request: axum::extract::Request,
 ) -> Result<UploadState, AppError> {
-    // Check if we have a range - if so, its an unsupported feature, namely monolit uploads.
-    if request.headers().contains_key(RANGE) {
-        return Err(anyhow::anyhow!("unsupported feature: chunked uploads").into());
-    }
+    let offset = registry.storage.get_upload_progress(upload).await?;
 
-    let mut writer = registry.storage.get_upload_writer(0, upload).await?;
+    // Chunks must be sent in order, without gaps or overlap. Without a range, the body is simply
+    // appended, which is what clients streaming the entire blob in a single `PATCH` do.
+    if let Some(content_range) = request.headers().get(CONTENT_RANGE) {
+        let (start, end) = content_range
+            .to_str()
+            .ok()
+            .and_then(parse_content_range)
+            .ok_or(AppError::RangeNotSatisfiable)?;
 
-    // We'll get the entire file in one go, no range header == monolithic uploads.
-    let mut body = request.into_body().into_data_stream();
+        if start != offset {
+            return Err(AppError::RangeNotSatisfiable);
+        }
 
-    let mut completed: u64 = 0;
-    while let Some(result) = body.next().await {
-        let chunk = result?;
-        completed += chunk.len() as u64;
-        writer.write_all(chunk.as_ref()).await?;
+        if let Some(content_length) = request.headers().get(CONTENT_LENGTH) {
+            let content_length: u64 = content_length
+                .to_str()
+                .ok()
+                .and_then(|raw| raw.parse().ok())
+                .ok_or_else(|| AppError::Oci(OciError::new(ErrorCode::SizeInvalid)))?;
+            if end - start + 1 != content_length {
+                return Err(AppError::RangeNotSatisfiable);
+            }
+        }
     }
 
-    writer.flush().await?;
+    let completed =
+        append_to_upload(&registry, &location, upload, offset, request.into_body()).await?;
 
     Ok(UploadState {
         location,
@@ -371,6 +890,44 @@ async fn upload_add_chunk(
     })
 }
 
+/// Parses a `Content-Range` header of a chunk upload.
+///
+/// The spec demands `<start>-<end>`, but the HTTP style `bytes <start>-<end>/<size>` is accepted
+/// as well. Both ends are inclusive.
+fn parse_content_range(raw: &str) -> Option<(u64, u64)> {
+    let raw = raw.trim();
+    let raw = raw.strip_prefix("bytes ").unwrap_or(raw);
+    let range = raw
+        .split_once('/')
+        .map(|(range, _size)| range)
+        .unwrap_or(raw);
+
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = end.trim().parse().ok()?;
+
+    (start <= end).then_some((start, end))
+}
+
+async fn upload_status(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    Path(UploadId { upload }): Path<UploadId>,
+    _auth: ValidUser,
+) -> Result<Response, AppError> {
+    let completed = registry.storage.get_upload_progress(upload).await?;
+
+    let mut response = UploadState {
+        location,
+        completed: Some(completed),
+        upload,
+    }
+    .into_response();
+    *response.status_mut() = StatusCode::NO_CONTENT;
+
+    Ok(response)
+}
+
 #[derive(Debug, Deserialize)]
 struct DigestQuery {
     digest: ImageDigest,
@@ -378,48 +935,90 @@ struct DigestQuery {
 
 async fn upload_finalize(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((_, _, upload)): Path<(String, String, Uuid)>,
+    Path(location): Path<ImageLocation>,
+    Path(UploadId { upload }): Path<UploadId>,
     Query(DigestQuery { digest }): Query<DigestQuery>,
     _auth: ValidUser,
     request: axum::extract::Request,
 ) -> Result<Response<Body>, AppError> {
-    // We do not support the final chunk in the `PUT` call, so ensure that's not the case.
-    match request.headers().get(CONTENT_LENGTH) {
-        Some(value) => {
-            let num_bytes: u64 = value.to_str()?.parse()?;
-            if num_bytes != 0 {
-                return Err(anyhow::anyhow!("missing content length not implemented").into());
-            }
-
-            // 0 is the only acceptable value here.
-        }
-        None => {
-            // Omitting is fine, indicating no body.
-        }
-    }
+    // The final chunk may be sent along with the `PUT`, an empty body simply appends nothing.
+    let offset = registry.storage.get_upload_progress(upload).await?;
+    append_to_upload(&registry, &location, upload, offset, request.into_body()).await?;
 
     registry
         .storage
         .finalize_upload(upload, digest.digest)
         .await?;
+    registry.storage.link_blob(&location, digest.digest).await?;
+    registry.pending_uploads.remove(upload);
 
     info!(%upload, %digest, "new image uploaded");
+    Ok(blob_created(&location, &digest))
+}
+
+async fn upload_cancel(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(UploadId { upload }): Path<UploadId>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    registry.storage.cancel_upload(upload).await?;
+    registry.pending_uploads.remove(upload);
+
+    info!(%upload, "upload cancelled");
     Ok(Response::builder()
-        .status(StatusCode::CREATED)
-        .header("Docker-Content-Digest", digest.to_string())
-        .body(Body::empty())?)
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
 }
 
 async fn manifest_put(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(manifest_reference): Path<ManifestReference>,
-    _auth: ValidUser,
+    auth: ValidUser,
     image_manifest_json: String,
 ) -> Result<Response<Body>, AppError> {
+    let location = manifest_reference.location();
+    let tag = manifest_reference.reference().as_tag();
+    let limit = registry.quotas.limit(location);
+
+    let _gc_guard = registry.gc_lock.read().await;
+
+    // Checks depending on what is currently stored must not race other pushes.
+    let guard = if limit.is_some()
+        || tag.is_some_and(|tag| registry.tag_rules.is_immutable(location, tag))
+    {
+        Some(registry.manifest_lock.lock().await)
+    } else {
+        None
+    };
+
+    if let Some(tag) = tag {
+        let overwrite = registry
+            .storage
+            .get_manifest_digest(&manifest_reference)
+            .await?
+            .is_some_and(|current| {
+                current != storage::Digest::from_contents(image_manifest_json.as_bytes())
+            });
+        check_tag_change(&registry, &auth, location, tag, overwrite).await?;
+    }
+
+    if let Some(limit) = limit {
+        check_quota(
+            &registry,
+            &manifest_reference,
+            image_manifest_json.as_bytes(),
+            limit,
+        )
+        .await?;
+    }
+
     let digest = registry
         .storage
         .put_manifest(&manifest_reference, image_manifest_json.as_bytes())
-        .await?;
+        .await
+        .map_err(AppError::Storage)?;
+    drop(guard);
 
     info!(%manifest_reference, %digest, "new manifest received");
     // Completed upload, call hook:
@@ -428,50 +1027,422 @@ async fn manifest_put(
         .on_manifest_uploaded(&manifest_reference)
         .await;
 
-    // TODO: Return manifest URL.
-    Ok(Response::builder()
+    let digest = ImageDigest::new(digest);
+    let mut builder = Response::builder()
         .status(StatusCode::CREATED)
-        .header(LOCATION, "http://localhost:3000/TODO")
-        .header(CONTENT_LENGTH, 0)
         .header(
-            "Docker-Content-Digest",
-            ImageDigest::new(digest).to_string(),
+            LOCATION,
+            format!(
+                "/v2/{}/{}/manifests/{}",
+                location.repository(),
+                location.image(),
+                digest
+            ),
         )
-        .body(Body::empty())
-        .unwrap())
+        .header(CONTENT_LENGTH, 0)
+        .header("Docker-Content-Digest", digest.to_string());
+
+    // Signals to clients that we track subjects, so they need not fall back to tag schemes.
+    if let Some(subject) = Manifest::from_slice(image_manifest_json.as_bytes())
+        .ok()
+        .as_ref()
+        .and_then(Manifest::subject)
+        .and_then(|subject| subject.digest())
+    {
+        builder = builder.header("OCI-Subject", ImageDigest::new(subject).to_string());
+    }
+
+    Ok(builder.body(Body::empty()).unwrap())
 }
 
-async fn manifest_get(
-    State(registry): State<Arc<ContainerRegistry>>,
-    Path(manifest_reference): Path<ManifestReference>,
-    _auth: ValidUser,
-) -> Result<Response<Body>, AppError> {
+/// Rejects changes to tags the user may not touch, either due to their credentials, e.g. a
+/// deploy token limited to `prod`, or due to tag protection rules.
+///
+/// `overwrite` is set if the tag exists and would be moved to another manifest or removed.
+async fn check_tag_change(
+    registry: &ContainerRegistry,
+    auth: &ValidUser,
+    location: &ImageLocation,
+    tag: &str,
+    overwrite: bool,
+) -> Result<(), AppError> {
+    if !registry
+        .auth_provider
+        .may_change_tag(
+            auth.username(),
+            location.repository(),
+            location.image(),
+            tag,
+        )
+        .await
+    {
+        return Err(AppError::Oci(
+            OciError::new(ErrorCode::Denied)
+                .with_message("not allowed to change tag")
+                .with_detail(format!("{}:{}", location, tag)),
+        ));
+    }
+
+    registry
+        .tag_rules
+        .check(location, tag, auth.username(), overwrite)
+        .map_err(AppError::Oci)
+}
+
+/// Rejects a manifest push that would make its repository use more than `limit` bytes.
+async fn check_quota(
+    registry: &ContainerRegistry,
+    manifest_reference: &ManifestReference,
+    manifest: &[u8],
+    limit: u64,
+) -> Result<(), AppError> {
+    let location = manifest_reference.location();
+
+    let mut usage = quota::measure_usage(registry.storage.as_ref(), location).await?;
+    usage
+        .count_new_manifest(registry.storage.as_ref(), location, manifest)
+        .await?;
+
+    if usage.bytes() > limit {
+        info!(%manifest_reference, bytes = usage.bytes(), limit, "push exceeds quota");
+        return Err(quota_exceeded(location, usage.bytes(), limit));
+    }
+
+    Ok(())
+}
+
+fn quota_exceeded(location: &ImageLocation, bytes: u64, limit: u64) -> AppError {
+    AppError::Oci(
+        OciError::new(ErrorCode::Denied)
+            .with_message("repository quota exceeded")
+            .with_detail(format!(
+                "{} would use {} of {} bytes",
+                location, bytes, limit
+            )),
+    )
+}
+
+async fn manifest_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(manifest_reference): Path<ManifestReference>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    manifest_response(&registry, &manifest_reference, true).await
+}
+
+async fn manifest_head(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(manifest_reference): Path<ManifestReference>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    manifest_response(&registry, &manifest_reference, false).await
+}
+
+/// Builds the response for a manifest request, with or without the manifest itself.
+async fn manifest_response(
+    registry: &ContainerRegistry,
+    manifest_reference: &ManifestReference,
+    include_body: bool,
+) -> Result<Response<Body>, AppError> {
+    // Resolve first and read by digest afterwards, as the tag might be moved in between.
+    let digest = registry
+        .storage
+        .get_manifest_digest(manifest_reference)
+        .await?
+        .ok_or_else(|| AppError::ManifestUnknown(manifest_reference.clone()))?;
+
+    // Tags always belong to their location, digests only if pushed there.
+    if manifest_reference.reference().as_tag().is_none()
+        && !storage::is_manifest_in_location(
+            registry.storage.as_ref(),
+            manifest_reference.location(),
+            digest,
+        )
+        .await?
+    {
+        return Err(AppError::ManifestUnknown(manifest_reference.clone()));
+    }
+
     let manifest_json = registry
         .storage
-        .get_manifest(&manifest_reference)
+        .get_manifest(&ManifestReference::new(
+            manifest_reference.location().clone(),
+            Reference::new_digest(digest),
+        ))
         .await?
-        .ok_or(AppError::NotFound)?;
+        .ok_or_else(|| AppError::ManifestUnknown(manifest_reference.clone()))?;
 
-    let manifest: ImageManifest = serde_json::from_slice(&manifest_json)?;
+    let manifest = Manifest::from_slice(&manifest_json)?;
 
-    Ok(Response::builder()
+    let builder = Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_LENGTH, manifest_json.len())
         .header(CONTENT_TYPE, manifest.media_type())
-        .body(manifest_json.into())
+        .header(
+            "Docker-Content-Digest",
+            ImageDigest::new(digest).to_string(),
+        );
+
+    let body = if include_body {
+        Body::from(manifest_json)
+    } else {
+        Body::empty()
+    };
+
+    Ok(builder.body(body).unwrap())
+}
+
+async fn manifest_delete(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(manifest_reference): Path<ManifestReference>,
+    auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let location = manifest_reference.location();
+
+    // Tags must not be pushed between checking and deleting them.
+    let _guard = registry.manifest_lock.lock().await;
+
+    let affected_tags = match manifest_reference.reference() {
+        Reference::Tag(tag) => vec![tag.clone()],
+        Reference::Digest(digest) => {
+            storage::tags_pointing_to(registry.storage.as_ref(), location, *digest).await?
+        }
+    };
+    for tag in &affected_tags {
+        check_tag_change(&registry, &auth, location, tag, true).await?;
+    }
+
+    let removed_tags = registry
+        .storage
+        .delete_manifest(&manifest_reference)
+        .await?
+        .ok_or_else(|| AppError::ManifestUnknown(manifest_reference.clone()))?;
+
+    info!(%manifest_reference, "manifest deleted");
+
+    // Every removed tag counts as a deletion, even when deleting by digest.
+    for tag in removed_tags {
+        let tag_reference = ManifestReference::new(
+            manifest_reference.location().clone(),
+            Reference::new_tag(tag),
+        );
+        registry.hooks.on_manifest_deleted(&tag_reference).await;
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::empty())
         .unwrap())
 }
 
+#[derive(Debug, Deserialize)]
+struct GcQuery {
+    #[serde(default)]
+    dry_run: bool,
+    /// Grace period in seconds.
+    grace_period: Option<u64>,
+}
+
+async fn admin_gc(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(GcQuery {
+        dry_run,
+        grace_period,
+    }): Query<GcQuery>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let options = GcOptions {
+        dry_run,
+        grace_period: grace_period
+            .map(Duration::from_secs)
+            .unwrap_or(gc::DEFAULT_GRACE_PERIOD),
+    };
+
+    let report = registry.collect_garbage(options).await?;
+
+    info!(
+        objects_removed = report.objects_removed(),
+        bytes_freed = report.bytes_freed(),
+        dry_run,
+        "garbage collection finished"
+    );
+
+    Ok(types::json_response(&report))
+}
+
+#[derive(Debug, Deserialize)]
+struct FsckQuery {
+    #[serde(default)]
+    repair: bool,
+}
+
+async fn admin_fsck(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(FsckQuery { repair }): Query<FsckQuery>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let report = fsck::check_storage(registry.storage.as_ref(), FsckOptions { repair }).await?;
+
+    info!(
+        problems = report.problems(),
+        repair, "storage check finished"
+    );
+
+    Ok(types::json_response(&report))
+}
+
+async fn admin_usage(
+    State(registry): State<Arc<ContainerRegistry>>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let report = quota::usage_report(registry.storage.as_ref(), &registry.quotas).await?;
+
+    Ok(types::json_response(&report))
+}
+
+#[derive(Debug, Deserialize)]
+struct PaginationQuery {
+    n: Option<usize>,
+    last: Option<String>,
+}
+
+impl PaginationQuery {
+    /// Selects the requested page from a lexically sorted list of entries.
+    ///
+    /// Returns the page, along with a `Link` header value pointing to the next page, if there is
+    /// one. `base` is the path of the paginated endpoint.
+    fn paginate(&self, entries: Vec<String>, base: &str) -> (Vec<String>, Option<String>) {
+        let mut remaining = entries
+            .into_iter()
+            .skip_while(|entry| matches!(self.last, Some(ref last) if entry <= last))
+            .peekable();
+
+        let Some(n) = self.n else {
+            return (remaining.collect(), None);
+        };
+
+        let page: Vec<_> = remaining.by_ref().take(n).collect();
+
+        let next = match (page.last(), remaining.peek()) {
+            (Some(last), Some(_)) => Some(format!("<{base}?n={n}&last={last}>; rel=\"next\"")),
+            _ => None,
+        };
+
+        (page, next)
+    }
+}
+
+async fn catalog(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(pagination): Query<PaginationQuery>,
+    auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let mut repositories = Vec::new();
+    for location in registry.storage.list_locations().await? {
+        if auth.token_allows(&location.to_string(), Action::Pull)
+            && registry
+                .auth_provider
+                .has_access_to(
+                    auth.username(),
+                    location.repository(),
+                    location.image(),
+                    Action::Pull,
+                )
+                .await
+        {
+            repositories.push(location.to_string());
+        }
+    }
+    repositories.sort();
+
+    let (page, next) = pagination.paginate(repositories, "/v2/_catalog");
+
+    let mut response = Catalog::new(page).into_response();
+    if let Some(next) = next {
+        response.headers_mut().insert(LINK, next.parse()?);
+    }
+
+    Ok(response)
+}
+
+async fn tags_list(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    Query(pagination): Query<PaginationQuery>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let tags = registry
+        .storage
+        .list_tags(&location)
+        .await?
+        .ok_or_else(|| AppError::NameUnknown(location.clone()))?;
+
+    let base = format!(
+        "/v2/{}/{}/tags/list",
+        location.repository(),
+        location.image()
+    );
+    let (page, next) = pagination.paginate(tags, &base);
+
+    let mut response = TagList::new(location.to_string(), page).into_response();
+    if let Some(next) = next {
+        response.headers_mut().insert(LINK, next.parse()?);
+    }
+
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+struct ReferrersQuery {
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+async fn referrers_list(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    Path((_, _, subject)): Path<(String, String, ImageDigest)>,
+    Query(query): Query<ReferrersQuery>,
+    _auth: ValidUser,
+) -> Result<Response<Body>, AppError> {
+    let mut referrers = registry
+        .storage
+        .list_referrers(&location, subject.digest)
+        .await?;
+
+    if let Some(ref artifact_type) = query.artifact_type {
+        referrers.retain(|descriptor| descriptor.artifact_type() == Some(artifact_type));
+    }
+
+    // An unknown subject is not an error, it simply has no referrers.
+    let mut response = types::json_response(&ImageIndex::new(referrers));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, types::OCI_IMAGE_INDEX.parse()?);
+    if query.artifact_type.is_some() {
+        response
+            .headers_mut()
+            .insert("OCI-Filters-Applied", "artifactType".parse()?);
+    }
+
+    Ok(response)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{path::Path, sync::Arc, time::Duration};
 
     use axum::{
+        async_trait,
         body::Body,
         http::{
-            header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, LOCATION},
+            header::{
+                AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+                LINK, LOCATION, RANGE, WWW_AUTHENTICATE,
+            },
             Request, StatusCode,
         },
+        response::Response,
         routing::RouterIntoService,
     };
     use base64::Engine;
@@ -484,23 +1455,49 @@ mod tests {
     use crate::{
         config::MasterKey,
         registry::{
-            storage::{ImageLocation, ManifestReference, Reference},
-            ImageDigest,
+            deploy_tokens::{DeployTokens, NewToken},
+            fsck::{self, FsckOptions},
+            gc::{self, GcOptions},
+            parse_content_range, parse_range,
+            quota::Quotas,
+            storage::{
+                self, memory::MemoryStorage, FilesystemStorage, ImageLocation, ManifestReference,
+                Reference, RegistryStorage,
+            },
+            tag_rules::{TagRule, TagRules},
+            token::{Action, TokenIssuer},
+            users::{Grant, UserStore},
+            AuthProvider, ImageDigest, RangeRequest, Role, UnverifiedCredentials,
         },
     };
 
     use super::{storage::Digest, ContainerRegistry};
 
     struct Context {
-        _tmp: TempDir,
+        tmp: Option<TempDir>,
         password: String,
         registry: Arc<ContainerRegistry>,
     }
 
     impl Context {
+        /// Returns the storage directory of apps created by `mk_filesystem_test_app`.
+        fn storage_path(&self) -> &Path {
+            self.tmp
+                .as_ref()
+                .expect("app does not use filesystem storage")
+                .path()
+        }
+
         fn basic_auth(&self) -> String {
             let encoded = base64::prelude::BASE64_STANDARD
-                .encode(&format!("user:{}", self.password).as_bytes());
+                .encode(format!("user:{}", self.password).as_bytes());
+            format!("Basic {}", encoded)
+        }
+
+        /// Returns basic authentication for a user other than the master key user.
+        fn basic_auth_as(&self, username: &str, password: &str) -> String {
+            let encoded = base64::prelude::BASE64_STANDARD
+                .encode(format!("{username}:{password}").as_bytes());
             format!("Basic {}", encoded)
         }
 
@@ -509,19 +1506,141 @@ mod tests {
             let encoded = base64::prelude::BASE64_STANDARD.encode(not_the_password.as_bytes());
             format!("Basic {}", encoded)
         }
+
+        /// Stores a blob directly, bypassing the HTTP API, as if uploaded to `location`.
+        async fn store_blob(&self, location: &str, contents: &[u8]) -> Digest {
+            let digest = Digest::from_contents(contents);
+            let upload = self
+                .registry
+                .storage
+                .begin_new_upload()
+                .await
+                .expect("could not start upload");
+            let mut writer = self
+                .registry
+                .storage
+                .get_upload_writer(0, upload)
+                .await
+                .expect("could not create upload writer");
+            writer
+                .write_all(contents)
+                .await
+                .expect("failed to write blob");
+            writer.shutdown().await.expect("failed to close writer");
+            drop(writer);
+
+            self.registry
+                .storage
+                .finalize_upload(upload, digest)
+                .await
+                .expect("failed to finalize upload");
+
+            let (repository, image) = location.split_once('/').expect("invalid location");
+            self.registry
+                .storage
+                .link_blob(
+                    &ImageLocation::new(repository.to_owned(), image.to_owned()),
+                    digest,
+                )
+                .await
+                .expect("failed to link blob");
+            digest
+        }
+
+        /// Stores all blobs referenced by `RAW_MANIFEST`, as if uploaded to `location`.
+        async fn store_image_blobs(&self, location: &str) {
+            self.store_blob(location, RAW_CONFIG).await;
+            self.store_blob(location, RAW_IMAGE).await;
+        }
     }
 
     fn mk_test_app() -> (Context, RouterIntoService<Body>) {
+        mk_app(
+            Box::new(MemoryStorage::new()),
+            None,
+            Quotas::default(),
+            TagRules::default(),
+        )
+    }
+
+    /// Creates an app backed by filesystem storage, for tests inspecting the storage directory.
+    fn mk_filesystem_test_app() -> (Context, RouterIntoService<Body>) {
         let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let storage = FilesystemStorage::new(tmp.as_ref()).expect("could not open storage");
 
+        mk_app(
+            Box::new(storage),
+            Some(tmp),
+            Quotas::default(),
+            TagRules::default(),
+        )
+    }
+
+    fn mk_app(
+        storage: Box<dyn RegistryStorage>,
+        tmp: Option<TempDir>,
+        quotas: Quotas,
+        tag_rules: TagRules,
+    ) -> (Context, RouterIntoService<Body>) {
         let password = "random-test-password".to_owned();
         let master_key = Arc::new(MasterKey::new_key(password.clone()));
 
-        let registry = ContainerRegistry::new(tmp.as_ref(), (), master_key)
-            .expect("should not fail to create app");
+        let registry = ContainerRegistry::new(
+            storage,
+            (),
+            master_key,
+            quotas,
+            TokenIssuer::default(),
+            tag_rules,
+        );
+        let router = registry
+            .clone()
+            .make_router()
+            .layer(TraceLayer::new_for_http());
+
+        let service = router.into_service::<Body>();
+
+        (
+            Context {
+                registry,
+                tmp,
+                password,
+            },
+            service,
+        )
+    }
+
+    /// Accounts of an app created by `mk_accounts_test_app`.
+    struct Accounts {
+        users: Arc<UserStore>,
+        deploy_tokens: Arc<DeployTokens>,
+    }
+
+    /// Creates an app authenticating through users and deploy tokens, on top of the master key.
+    fn mk_accounts_test_app() -> (Context, Accounts, RouterIntoService<Body>) {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let password = "random-test-password".to_owned();
+        let users = Arc::new(
+            UserStore::open(tmp.path(), MasterKey::new_key(password.clone()))
+                .expect("could not open user store"),
+        );
+        let deploy_tokens = Arc::new(
+            DeployTokens::open(tmp.path(), users.clone()).expect("could not open deploy tokens"),
+        );
+
+        let registry = ContainerRegistry::new(
+            Box::new(MemoryStorage::new()),
+            (),
+            deploy_tokens.clone(),
+            Quotas::default(),
+            TokenIssuer::default(),
+            TagRules::default(),
+        );
         let router = registry
             .clone()
             .make_router()
+            .merge(users.clone().make_router())
+            .merge(deploy_tokens.clone().make_router())
             .layer(TraceLayer::new_for_http());
 
         let service = router.into_service::<Body>();
@@ -529,9 +1648,13 @@ mod tests {
         (
             Context {
                 registry,
-                _tmp: tmp,
+                tmp: Some(tmp),
                 password,
             },
+            Accounts {
+                users,
+                deploy_tokens,
+            },
             service,
         )
     }
@@ -541,58 +1664,97 @@ mod tests {
         let (ctx, mut service) = mk_test_app();
         let app = service.ready().await.expect("could not launch service");
 
-        let targets = [("GET", "/v2/")];
-        // TODO: Verify all remaining endpoints return `UNAUTHORIZED` without credentials.
+        let blob = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
+        let upload = format!("/v2/tests/sample/blobs/uploads/{}", uuid::Uuid::new_v4());
+        let manifest = "/v2/tests/sample/manifests/latest".to_owned();
+        let targets = [
+            ("GET", "/v2/".to_owned()),
+            ("GET", "/v2/_catalog".to_owned()),
+            ("HEAD", blob.clone()),
+            ("GET", blob),
+            ("POST", "/v2/tests/sample/blobs/uploads/".to_owned()),
+            ("GET", upload.clone()),
+            ("PATCH", upload.clone()),
+            ("PUT", format!("{}?digest={}", upload, IMAGE_DIGEST)),
+            ("DELETE", upload),
+            ("PUT", manifest.clone()),
+            ("GET", manifest.clone()),
+            ("HEAD", manifest.clone()),
+            ("DELETE", manifest),
+            ("GET", "/v2/tests/sample/tags/list".to_owned()),
+            (
+                "GET",
+                format!("/v2/tests/sample/referrers/{}", MANIFEST_DIGEST),
+            ),
+            ("POST", "/_rockslide/registry/gc".to_owned()),
+            ("POST", "/_rockslide/registry/fsck".to_owned()),
+            ("GET", "/_rockslide/registry/usage".to_owned()),
+        ];
 
-        for (method, endpoint) in targets.into_iter() {
+        for (method, endpoint) in targets {
             // API should refuse requests without credentials.
             let response = app
                 .call(
                     Request::builder()
                         .method(method)
-                        .uri(endpoint)
+                        .uri(&endpoint)
                         .body(Body::empty())
                         .unwrap(),
                 )
                 .await
                 .unwrap();
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                endpoint
+            );
 
             // Wrong credentials should also not grant access.
             let response = app
                 .call(
                     Request::builder()
                         .method(method)
-                        .uri(endpoint)
+                        .uri(&endpoint)
                         .header(AUTHORIZATION, ctx.invalid_basic_auth())
                         .body(Body::empty())
                         .unwrap(),
                 )
                 .await
                 .unwrap();
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-
-            // Finally a valid set should grant access.
-            let response = app
-                .call(
-                    Request::builder()
-                        .uri("/v2/")
-                        .header(AUTHORIZATION, ctx.basic_auth())
-                        .body(Body::empty())
-                        .unwrap(),
-                )
-                .await
-                .unwrap();
-            assert_ne!(response.status(), StatusCode::UNAUTHORIZED)
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                endpoint
+            );
         }
+
+        // Finally a valid set should grant access.
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/v2/")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_ne!(response.status(), StatusCode::UNAUTHORIZED)
     }
 
     // Fixtures.
     const RAW_IMAGE: &[u8] = include_bytes!(
         "../fixtures/596a7d877b33569d199046aaf293ecf45026445be36de1818d50b4f1850762ad"
     );
+    const RAW_CONFIG: &[u8] = include_bytes!(
+        "../fixtures/eda5434ef81cd8062f87188f61cc45f75e89230a7d231c39b8ca6272ccaa20f0"
+    );
     const RAW_MANIFEST: &[u8] = include_bytes!(
-        "../fixtures/9ce67038e4f1297a0b1ce23be1b768ce3649fe9bd496ba8efe9ec1676d153430"
+        "../fixtures/7afbb9dda380e16481f74de3029d9f94fcb7fc9fd5eb379086ee063d5406f08e"
     );
 
     const IMAGE_DIGEST: ImageDigest = ImageDigest::new(Digest::new([
@@ -602,9 +1764,9 @@ mod tests {
     ]));
 
     const MANIFEST_DIGEST: ImageDigest = ImageDigest::new(Digest::new([
-        0x9c, 0xe6, 0x70, 0x38, 0xe4, 0xf1, 0x29, 0x7a, 0x0b, 0x1c, 0xe2, 0x3b, 0xe1, 0xb7, 0x68,
-        0xce, 0x36, 0x49, 0xfe, 0x9b, 0xd4, 0x96, 0xba, 0x8e, 0xfe, 0x9e, 0xc1, 0x67, 0x6d, 0x15,
-        0x34, 0x30,
+        0x7a, 0xfb, 0xb9, 0xdd, 0xa3, 0x80, 0xe1, 0x64, 0x81, 0xf7, 0x4d, 0xe3, 0x02, 0x9d, 0x9f,
+        0x94, 0xfc, 0xb7, 0xfc, 0x9f, 0xd5, 0xeb, 0x37, 0x90, 0x86, 0xee, 0x06, 0x3d, 0x54, 0x06,
+        0xf0, 0x8e,
     ]));
 
     #[tokio::test]
@@ -640,7 +1802,7 @@ mod tests {
         let mut sent = 0;
         for chunk in RAW_IMAGE.chunks(32) {
             assert!(!chunk.is_empty());
-            let range = format!("{sent}-{}", chunk.len() - 1);
+            let range = format!("{sent}-{}", sent + chunk.len() - 1);
             sent += chunk.len();
 
             let response = app
@@ -660,7 +1822,7 @@ mod tests {
             assert_eq!(response.status(), StatusCode::ACCEPTED);
         }
 
-        // Step 3: PUT without (!) final body -- we do not support putting the final piece in `PUT`.
+        // Step 3: PUT without (!) final body.
         let response = app
             .call(
                 Request::builder()
@@ -679,7 +1841,7 @@ mod tests {
         assert!(&ctx
             .registry
             .storage
-            .get_blob_reader(IMAGE_DIGEST.digest)
+            .get_blob_reader(IMAGE_DIGEST.digest, 0)
             .await
             .expect("could not access stored blob")
             .is_some());
@@ -708,7 +1870,8 @@ mod tests {
             IMAGE_DIGEST.to_string()
         );
 
-        // Step 5: Upload the manifest
+        // Step 5: Upload the manifest, after the config it references.
+        ctx.store_blob("tests/sample", RAW_CONFIG).await;
         let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
 
         let response = app
@@ -733,6 +1896,10 @@ mod tests {
                 .unwrap(),
             MANIFEST_DIGEST.to_string()
         );
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap().to_str().unwrap(),
+            format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST)
+        );
 
         // Should contain image under given tag.
         assert_eq!(
@@ -763,116 +1930,2696 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn image_download() {
+    async fn resumable_chunked_upload() {
         let (ctx, mut service) = mk_test_app();
         let app = service.ready().await.expect("could not launch service");
 
-        let manifest_ref_by_tag = ManifestReference::new(
-            ImageLocation::new("tests".to_owned(), "sample".to_owned()),
-            Reference::new_tag("latest"),
-        );
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/blobs/uploads/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
 
-        let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
-        let manifest_by_digest_location = format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST);
+        let upload_location = response
+            .headers()
+            .get(LOCATION)
+            .expect("expected location header for blob upload")
+            .to_str()
+            .unwrap()
+            .to_owned();
 
-        // Insert blob data.
-        let upload = ctx
-            .registry
+        let (head, tail) = RAW_IMAGE.split_at(64);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PATCH")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, head.len())
+                    .header(CONTENT_RANGE, format!("0-{}", head.len() - 1))
+                    .uri(&upload_location)
+                    .body(Body::from(head))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
+
+        // Sending a chunk that does not continue the upload is refused.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PATCH")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, tail.len())
+                    .header(CONTENT_RANGE, format!("70-{}", 70 + tail.len() - 1))
+                    .uri(&upload_location)
+                    .body(Body::from(tail))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // After an interruption, the client can query the progress and resume from there.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(&upload_location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PATCH")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, tail.len())
+                    .header(CONTENT_RANGE, format!("64-{}", RAW_IMAGE.len() - 1))
+                    .uri(&upload_location)
+                    .body(Body::from(tail))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers().get(RANGE).unwrap().to_str().unwrap(),
+            format!("0-{}", RAW_IMAGE.len() - 1)
+        );
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(upload_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn monolithic_upload() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, RAW_IMAGE.len())
+                    .uri(format!(
+                        "/v2/tests/sample/blobs/uploads/?digest={}",
+                        IMAGE_DIGEST
+                    ))
+                    .body(Body::from(RAW_IMAGE))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap().to_str().unwrap(),
+            format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST)
+        );
+
+        assert!(ctx
+            .registry
             .storage
-            .begin_new_upload()
+            .get_blob_metadata(IMAGE_DIGEST.digest)
             .await
-            .expect("could not start upload");
-        let mut writer = ctx
+            .expect("failed to get blob metadata")
+            .is_some());
+
+        // A wrong digest must not result in a stored blob.
+        let bogus = b"this is not the image";
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!(
+                        "/v2/tests/sample/blobs/uploads/?digest={}",
+                        ImageDigest::new(Digest::from_contents(b"something else"))
+                    ))
+                    .body(Body::from(&bogus[..]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!response.status().is_success());
+        assert!(ctx
             .registry
             .storage
-            .get_upload_writer(0, upload)
+            .get_blob_metadata(Digest::from_contents(bogus))
             .await
-            .expect("could not create upload writer");
-        writer
-            .write_all(RAW_IMAGE)
+            .expect("failed to get blob metadata")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn final_chunk_in_put() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/blobs/uploads/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
             .await
-            .expect("failed to write image blob");
-        ctx.registry
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let upload_location = response
+            .headers()
+            .get(LOCATION)
+            .expect("expected location header for blob upload")
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let (head, tail) = RAW_IMAGE.split_at(64);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PATCH")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, head.len())
+                    .header(CONTENT_RANGE, format!("0-{}", head.len() - 1))
+                    .uri(&upload_location)
+                    .body(Body::from(head))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_LENGTH, tail.len())
+                    .uri(upload_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
+                    .body(Body::from(tail))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response
+                .headers()
+                .get("Docker-Content-Digest")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            IMAGE_DIGEST.to_string()
+        );
+
+        let blob = ctx
+            .registry
             .storage
-            .finalize_upload(upload, IMAGE_DIGEST.digest)
+            .get_blob_metadata(IMAGE_DIGEST.digest)
             .await
-            .expect("failed to finalize upload");
+            .expect("failed to get blob metadata")
+            .expect("blob missing");
+        assert_eq!(blob.size(), RAW_IMAGE.len() as u64);
+    }
 
-        // Insert manifest data.
+    #[tokio::test]
+    async fn cross_repository_mount() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!(
+                        "/v2/tests/sample/blobs/uploads/?digest={}",
+                        IMAGE_DIGEST
+                    ))
+                    .body(Body::from(RAW_IMAGE))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Uploaded, but not referenced by any manifest of the source yet.
+        let mount_uri = format!(
+            "/v2/tests/other/blobs/uploads/?mount={}&from=tests/sample",
+            IMAGE_DIGEST
+        );
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(&mount_uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        ctx.store_blob("tests/sample", RAW_CONFIG).await;
         ctx.registry
             .storage
-            .put_manifest(&manifest_ref_by_tag, RAW_MANIFEST)
+            .put_manifest(
+                &ManifestReference::new(
+                    ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                    Reference::new_tag("latest"),
+                ),
+                RAW_MANIFEST,
+            )
             .await
             .expect("failed to store manifest");
 
-        // Retrieve manifest via HTTP, both by tag and by digest.
+        // Sources that do not reference the blob cannot be used to mount it.
         let response = app
             .call(
                 Request::builder()
-                    .method("GET")
+                    .method("POST")
                     .header(AUTHORIZATION, ctx.basic_auth())
-                    .uri(manifest_by_tag_location)
+                    .uri(format!(
+                        "/v2/tests/third/blobs/uploads/?mount={}&from=tests/other",
+                        IMAGE_DIGEST
+                    ))
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        let response_body = collect_body(response.into_body()).await;
-
-        assert_eq!(response_body, RAW_MANIFEST);
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
 
+        // Mounting a blob referenced by the source succeeds immediately.
         let response = app
             .call(
                 Request::builder()
-                    .method("GET")
+                    .method("POST")
                     .header(AUTHORIZATION, ctx.basic_auth())
-                    .uri(manifest_by_digest_location)
+                    .uri(&mount_uri)
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        let response_body = collect_body(response.into_body()).await;
-
-        assert_eq!(response_body, RAW_MANIFEST);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap().to_str().unwrap(),
+            format!("/v2/tests/other/blobs/{}", IMAGE_DIGEST)
+        );
 
-        // Download blob.
+        // Unknown blobs result in a regular upload session.
         let response = app
             .call(
                 Request::builder()
-                    .method("GET")
+                    .method("POST")
                     .header(AUTHORIZATION, ctx.basic_auth())
-                    .uri(format!("/v2/testing/sample/blobs/{}", IMAGE_DIGEST))
+                    .uri(format!(
+                        "/v2/tests/other/blobs/uploads/?mount={}&from=tests/sample",
+                        ImageDigest::new(Digest::from_contents(b"unknown"))
+                    ))
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response
+            .headers()
+            .get(LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("/v2/tests/other/blobs/uploads/"));
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let response_body = collect_body(response.into_body()).await;
-        assert_eq!(response_body, RAW_IMAGE);
+    #[tokio::test]
+    async fn uploads_are_rehashed_after_restart() {
+        let (ctx, _service) = mk_filesystem_test_app();
+        let (head, tail) = RAW_IMAGE.split_at(64);
+
+        let upload = ctx
+            .registry
+            .storage
+            .begin_new_upload()
+            .await
+            .expect("could not start upload");
+        let mut writer = ctx
+            .registry
+            .storage
+            .get_upload_writer(0, upload)
+            .await
+            .expect("could not create upload writer");
+        writer.write_all(head).await.expect("failed to write head");
+        writer.shutdown().await.expect("failed to close writer");
+        drop(writer);
+
+        // A fresh storage instance has lost all in-memory hashing state, so it has to read back
+        // what has been uploaded so far before continuing.
+        let restarted =
+            FilesystemStorage::new(ctx.storage_path()).expect("could not reopen storage");
+        let mut writer = restarted
+            .get_upload_writer(64, upload)
+            .await
+            .expect("could not create upload writer");
+        writer.write_all(tail).await.expect("failed to write tail");
+        writer.shutdown().await.expect("failed to close writer");
+        drop(writer);
+
+        assert!(matches!(
+            restarted
+                .finalize_upload(upload, MANIFEST_DIGEST.digest)
+                .await,
+            Err(storage::Error::DigestMismatch)
+        ));
+        restarted
+            .finalize_upload(upload, IMAGE_DIGEST.digest)
+            .await
+            .expect("failed to finalize upload");
     }
 
     #[tokio::test]
-    async fn missing_manifest_returns_404() {
+    async fn upload_locking_cancellation_and_expiry() {
         let (ctx, mut service) = mk_test_app();
         let app = service.ready().await.expect("could not launch service");
+        let storage = &ctx.registry.storage;
+
+        let upload = storage
+            .begin_new_upload()
+            .await
+            .expect("could not start upload");
+
+        // Only a single writer may be active at a time.
+        let writer = storage
+            .get_upload_writer(0, upload)
+            .await
+            .expect("could not create upload writer");
+        assert!(matches!(
+            storage.get_upload_writer(0, upload).await,
+            Err(storage::Error::UploadLocked)
+        ));
+        assert!(matches!(
+            storage.finalize_upload(upload, IMAGE_DIGEST.digest).await,
+            Err(storage::Error::UploadLocked)
+        ));
+
+        // Uploads in use are never expired, idle ones are.
+        let idle = storage
+            .begin_new_upload()
+            .await
+            .expect("could not start upload");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let expired = storage
+            .expire_uploads(Duration::ZERO)
+            .await
+            .expect("failed to expire uploads");
+        assert_eq!(expired, vec![idle]);
+
+        drop(writer);
 
+        let upload_location = format!("/v2/tests/sample/blobs/uploads/{}", upload);
         let response = app
             .call(
                 Request::builder()
-                    .method("GET")
+                    .method("DELETE")
                     .header(AUTHORIZATION, ctx.basic_auth())
-                    .uri("/v2/doesnot/exist/manifests/latest")
+                    .uri(&upload_location)
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(&upload_location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn startup_removes_leftovers_of_interrupted_writes() {
+        let (ctx, _service) = mk_filesystem_test_app();
+        ctx.store_image_blobs("tests/sample").await;
+
+        let location = ImageLocation::new("tests".to_owned(), "sample".to_owned());
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        // Simulate a crash in the middle of writing a manifest and moving a tag.
+        let root = ctx.storage_path();
+        let leftover_file = root
+            .join("tmp")
+            .join("4b8cb1a6-06f4-4bd6-94a6-1cdb7b1e0b4c");
+        let leftover_tag = root
+            .join("tags")
+            .join("0d8f8bd2-3f21-4c8b-8f3b-7e5e7a8a3a7e");
+        std::fs::write(&leftover_file, &RAW_MANIFEST[..10]).unwrap();
+        std::os::unix::fs::symlink("../../../manifests/does-not-exist", &leftover_tag).unwrap();
+
+        let restarted = FilesystemStorage::new(root).expect("could not reopen storage");
+        restarted.recover().expect("recovery failed");
+
+        assert!(!leftover_file.exists());
+        assert!(leftover_tag.symlink_metadata().is_err());
+        assert_eq!(
+            restarted.list_tags(&location).await.unwrap(),
+            Some(vec!["latest".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn fsck_finds_and_quarantines_corrupt_objects() {
+        let (ctx, app) = mk_filesystem_test_app();
+        ctx.store_image_blobs("tests/sample").await;
+
+        let location = ImageLocation::new("tests".to_owned(), "sample".to_owned());
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        let report = fsck::check_storage(ctx.registry.storage.as_ref(), FsckOptions::default())
+            .await
+            .expect("storage check failed");
+        assert!(report.is_clean());
+
+        // Flip some bits in the layer and leave a tag pointing nowhere.
+        let root = ctx.storage_path();
+        let layer_path = root.join("blobs").join(IMAGE_DIGEST.digest.to_string());
+        std::fs::write(&layer_path, b"definitely not the layer").unwrap();
+        std::os::unix::fs::symlink(
+            "../../../manifests/does-not-exist",
+            root.join("tags").join("tests").join("sample").join("stale"),
+        )
+        .unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/_rockslide/registry/fsck")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        assert_eq!(report["corrupt_blobs"].as_array().unwrap().len(), 1);
+        assert_eq!(report["missing_references"].as_array().unwrap().len(), 1);
+        assert_eq!(report["dangling_tags"][0], "tests/sample:stale");
+        assert!(layer_path.exists(), "check without repair must not modify");
+
+        let report =
+            fsck::check_storage(ctx.registry.storage.as_ref(), FsckOptions { repair: true })
+                .await
+                .expect("storage repair failed");
+        assert_eq!(report.corrupt_blobs()[0].digest, IMAGE_DIGEST.digest);
+        assert_eq!(report.dangling_tags(), ["tests/sample:stale"]);
+
+        assert!(!layer_path.exists());
+        assert!(root
+            .join("quarantine")
+            .join(format!("blob-{}", IMAGE_DIGEST.digest))
+            .exists());
+        assert_eq!(
+            ctx.registry.storage.list_tags(&location).await.unwrap(),
+            Some(vec!["latest".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn fsck_records_pushes_of_older_tags() {
+        let (ctx, _app) = mk_filesystem_test_app();
+        ctx.store_image_blobs("tests/sample").await;
+        ctx.store_image_blobs("tests/other").await;
+
+        let location = ImageLocation::new("tests".to_owned(), "sample".to_owned());
+        let other = ImageLocation::new("tests".to_owned(), "other".to_owned());
+        let digest = ctx
+            .registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        // Tags written by earlier versions come without any record of the push.
+        let root = ctx.storage_path();
+        std::fs::remove_file(
+            root.join("revisions")
+                .join("tests")
+                .join("sample")
+                .join(digest.to_string()),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(root.join("pushes").join(digest.to_string())).unwrap();
+
+        let report = fsck::check_storage(ctx.registry.storage.as_ref(), FsckOptions::default())
+            .await
+            .expect("storage check failed");
+        assert_eq!(report.unrecorded_tags(), ["tests/sample:latest"]);
+
+        let report =
+            fsck::check_storage(ctx.registry.storage.as_ref(), FsckOptions { repair: true })
+                .await
+                .expect("storage repair failed");
+        assert_eq!(report.unrecorded_tags(), ["tests/sample:latest"]);
+        assert!(
+            fsck::check_storage(ctx.registry.storage.as_ref(), FsckOptions::default())
+                .await
+                .expect("storage check failed")
+                .is_clean()
+        );
+
+        // Once recorded, deleting the manifest from another location leaves the tag intact.
+        let other_reference = ManifestReference::new(other, Reference::new_digest(digest));
+        ctx.registry
+            .storage
+            .put_manifest(&other_reference, RAW_MANIFEST)
+            .await
+            .expect("failed to store manifest");
+        ctx.registry
+            .storage
+            .delete_manifest(&other_reference)
+            .await
+            .expect("failed to delete manifest");
+        assert_eq!(
+            ctx.registry
+                .storage
+                .get_manifest(&ManifestReference::new(
+                    location,
+                    Reference::new_tag("latest")
+                ))
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(RAW_MANIFEST)
+        );
+    }
+
+    #[test]
+    fn content_range_parsing() {
+        assert_eq!(parse_content_range("0-31"), Some((0, 31)));
+        assert_eq!(parse_content_range("bytes 32-63/*"), Some((32, 63)));
+        assert_eq!(parse_content_range("bytes 32-63/110"), Some((32, 63)));
+        assert_eq!(parse_content_range("63-32"), None);
+        assert_eq!(parse_content_range("32-"), None);
+        assert_eq!(parse_content_range("garbage"), None);
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("bytes=0-31", 110), RangeRequest::Single(0, 31));
+        assert_eq!(
+            parse_range("bytes=100-", 110),
+            RangeRequest::Single(100, 109)
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 110),
+            RangeRequest::Single(100, 109)
+        );
+        assert_eq!(
+            parse_range("bytes=100-200", 110),
+            RangeRequest::Single(100, 109)
+        );
+        assert_eq!(parse_range("bytes=110-", 110), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 110), RangeRequest::Unsupported);
+        assert_eq!(parse_range("items=0-1", 110), RangeRequest::Unsupported);
+        assert_eq!(parse_range("bytes=5-1", 110), RangeRequest::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn image_download() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let manifest_ref_by_tag = ManifestReference::new(
+            ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+            Reference::new_tag("latest"),
+        );
+
+        let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
+        let manifest_by_digest_location = format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST);
+
+        // Insert blob data.
+        ctx.store_image_blobs("tests/sample").await;
+
+        // Insert manifest data.
+        ctx.registry
+            .storage
+            .put_manifest(&manifest_ref_by_tag, RAW_MANIFEST)
+            .await
+            .expect("failed to store manifest");
+
+        // Retrieve manifest via HTTP, both by tag and by digest.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(manifest_by_tag_location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response_body = collect_body(response.into_body()).await;
+
+        assert_eq!(response_body, RAW_MANIFEST);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(manifest_by_digest_location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response_body = collect_body(response.into_body()).await;
+
+        assert_eq!(response_body, RAW_MANIFEST);
+
+        // Download blob.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_LENGTH).unwrap(),
+            &RAW_IMAGE.len().to_string()
+        );
+        assert_eq!(
+            response.headers().get("Docker-Content-Digest").unwrap(),
+            &IMAGE_DIGEST.to_string()
+        );
+        let etag = response.headers().get(ETAG).unwrap().clone();
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(response_body, RAW_IMAGE);
+
+        // Resume an interrupted download.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(RANGE, "bytes=64-")
+                    .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers().get(CONTENT_RANGE).unwrap(),
+            &format!("bytes 64-{}/{}", RAW_IMAGE.len() - 1, RAW_IMAGE.len())
+        );
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(response_body, &RAW_IMAGE[64..]);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(RANGE, format!("bytes={}-", RAW_IMAGE.len()))
+                    .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // Cached copies are still valid.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(IF_NONE_MATCH, etag)
+                    .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(collect_body(response.into_body()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn digests_are_scoped_to_repositories() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(
+                    ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                    Reference::new_tag("latest"),
+                ),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        // Knowing the digest of another repository's content is not enough to read it.
+        for (method, uri, status, code) in [
+            (
+                "GET",
+                format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST),
+                StatusCode::OK,
+                None,
+            ),
+            (
+                "GET",
+                format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST),
+                StatusCode::OK,
+                None,
+            ),
+            (
+                "GET",
+                format!("/v2/tests/other/manifests/{}", MANIFEST_DIGEST),
+                StatusCode::NOT_FOUND,
+                Some("MANIFEST_UNKNOWN"),
+            ),
+            (
+                "HEAD",
+                format!("/v2/tests/other/manifests/{}", MANIFEST_DIGEST),
+                StatusCode::NOT_FOUND,
+                None,
+            ),
+            (
+                "GET",
+                format!("/v2/tests/other/blobs/{}", IMAGE_DIGEST),
+                StatusCode::NOT_FOUND,
+                Some("BLOB_UNKNOWN"),
+            ),
+            (
+                "HEAD",
+                format!(
+                    "/v2/tests/other/blobs/{}",
+                    ImageDigest::new(Digest::from_contents(RAW_CONFIG))
+                ),
+                StatusCode::NOT_FOUND,
+                None,
+            ),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method(method)
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(&uri)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{} {}", method, uri);
+            if let Some(code) = code {
+                assert_eq!(response_error_code(response).await, code);
+            }
+        }
+
+        // Neither can it be referenced from a manifest pushed to another repository.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/other/manifests/latest")
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response_error_code(response).await, "MANIFEST_BLOB_UNKNOWN");
+    }
+
+    #[tokio::test]
+    async fn manifest_head() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(
+                    ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                    Reference::new_tag("latest"),
+                ),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        for uri in [
+            "/v2/tests/sample/manifests/latest".to_owned(),
+            format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("HEAD")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers();
+            assert_eq!(
+                headers
+                    .get("Docker-Content-Digest")
+                    .unwrap()
+                    .to_str()
+                    .unwrap(),
+                MANIFEST_DIGEST.to_string()
+            );
+            assert_eq!(
+                headers.get(CONTENT_TYPE).unwrap(),
+                "application/vnd.docker.distribution.manifest.v2+json"
+            );
+            assert_eq!(
+                headers.get(CONTENT_LENGTH).unwrap().to_str().unwrap(),
+                RAW_MANIFEST.len().to_string()
+            );
+            assert!(collect_body(response.into_body()).await.is_empty());
+        }
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("HEAD")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/missing")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn mk_index(child: &ImageDigest, size: usize) -> String {
+        format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [
+                    {{
+                        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                        "size": {size},
+                        "digest": "{child}",
+                        "platform": {{ "architecture": "amd64", "os": "linux" }}
+                    }}
+                ]
+            }}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn image_index_upload() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        let index = mk_index(&MANIFEST_DIGEST, RAW_MANIFEST.len());
+
+        // The index is refused as long as its manifests are missing.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::from(index.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.status().is_client_error());
+
+        // Platform manifests are pushed by digest first.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST))
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // A size mismatch is not acceptable either.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::from(mk_index(&MANIFEST_DIGEST, 1)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.status().is_client_error());
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::from(index.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/vnd.oci.image.index.v1+json"
+        );
+        assert_eq!(collect_body(response.into_body()).await, index.as_bytes());
+
+        // The untagged platform manifest survives garbage collection through the index.
+        let report = gc::collect_garbage(
+            ctx.registry.storage.as_ref(),
+            GcOptions {
+                dry_run: false,
+                grace_period: Duration::ZERO,
+            },
+        )
+        .await
+        .expect("garbage collection failed");
+        assert!(report.manifests_removed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn referrers_are_listed_by_subject() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_blob("tests/sample", b"{}").await;
+
+        let artifact = format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.example.sbom",
+                "config": {{
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                    "size": 2
+                }},
+                "layers": [],
+                "subject": {{
+                    "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                    "digest": "{MANIFEST_DIGEST}",
+                    "size": {}
+                }}
+            }}"#,
+            RAW_MANIFEST.len()
+        );
+        let artifact_digest = ImageDigest::new(Digest::from_contents(artifact.as_bytes()));
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{}", artifact_digest))
+                    .body(Body::from(artifact.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers().get("OCI-Subject").unwrap(),
+            &MANIFEST_DIGEST.to_string()
+        );
+
+        for (query, expected) in [
+            ("", 1),
+            ("?artifactType=application/vnd.example.sbom", 1),
+            ("?artifactType=application/vnd.example.signature", 0),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("GET")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(format!(
+                            "/v2/tests/sample/referrers/{}{}",
+                            MANIFEST_DIGEST, query
+                        ))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(CONTENT_TYPE).unwrap(),
+                "application/vnd.oci.image.index.v1+json"
+            );
+            assert_eq!(
+                response.headers().contains_key("OCI-Filters-Applied"),
+                !query.is_empty()
+            );
+
+            let index: serde_json::Value =
+                serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+            let manifests = index["manifests"].as_array().unwrap();
+            assert_eq!(manifests.len(), expected);
+            if let Some(referrer) = manifests.first() {
+                assert_eq!(referrer["digest"], artifact_digest.to_string());
+                assert_eq!(referrer["size"], artifact.len());
+                assert_eq!(referrer["artifactType"], "application/vnd.example.sbom");
+            }
+        }
+
+        // Once the referrer is gone, it is no longer listed.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("DELETE")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{}", artifact_digest))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let referrers = ctx
+            .registry
+            .storage
+            .list_referrers(
+                &ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                MANIFEST_DIGEST.digest,
+            )
+            .await
+            .unwrap();
+        assert!(referrers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn manifest_with_missing_blobs_is_refused() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        // Only the layer is present, the config is missing.
+        ctx.store_blob("tests/sample", RAW_IMAGE).await;
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let errors: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        assert_eq!(
+            errors,
+            serde_json::json!({
+                "errors": [{
+                    "code": "MANIFEST_BLOB_UNKNOWN",
+                    "message": "blob unknown to registry",
+                    "detail": ImageDigest::new(Digest::from_contents(RAW_CONFIG)).to_string(),
+                }]
+            })
+        );
+
+        // Once the config has been uploaded, the manifest is accepted.
+        ctx.store_blob("tests/sample", RAW_CONFIG).await;
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn manifest_put_by_wrong_digest_fails() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{}", IMAGE_DIGEST))
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn missing_manifest_returns_404() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/doesnot/exist/manifests/latest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn errors_are_reported_as_oci_errors() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        let unknown_upload = "/v2/tests/sample/blobs/uploads/7d8a2e3a-7c4b-4a5f-9a47-2dd1f47b3b3e";
+        let cases = [
+            (
+                "GET",
+                "/v2/tests/Sample/tags/list",
+                StatusCode::BAD_REQUEST,
+                "NAME_INVALID",
+            ),
+            (
+                "GET",
+                "/v2/tests/../tags/list",
+                StatusCode::BAD_REQUEST,
+                "NAME_INVALID",
+            ),
+            (
+                "GET",
+                "/v2/tests/sample/blobs/sha256:abc",
+                StatusCode::BAD_REQUEST,
+                "DIGEST_INVALID",
+            ),
+            (
+                "GET",
+                "/v2/tests/sample/manifests/sha256:abc",
+                StatusCode::BAD_REQUEST,
+                "DIGEST_INVALID",
+            ),
+            (
+                "GET",
+                "/v2/tests/sample/blobs/uploads/nope",
+                StatusCode::NOT_FOUND,
+                "BLOB_UPLOAD_UNKNOWN",
+            ),
+            (
+                "GET",
+                unknown_upload,
+                StatusCode::NOT_FOUND,
+                "BLOB_UPLOAD_UNKNOWN",
+            ),
+            (
+                "GET",
+                "/v2/tests/sample/manifests/latest",
+                StatusCode::NOT_FOUND,
+                "MANIFEST_UNKNOWN",
+            ),
+            (
+                "GET",
+                "/v2/tests/sample/tags/list",
+                StatusCode::NOT_FOUND,
+                "NAME_UNKNOWN",
+            ),
+            (
+                "GET",
+                &format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST),
+                StatusCode::NOT_FOUND,
+                "BLOB_UNKNOWN",
+            ),
+        ];
+
+        for (method, uri, status, code) in cases {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method(method)
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{} {}", method, uri);
+            assert_eq!(
+                response_error_code(response).await,
+                code,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+
+        // Authentication failures carry a challenge.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri("/v2/tests/sample/tags/list")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        assert_eq!(response_error_code(response).await, "UNAUTHORIZED");
+
+        // Finalizing an upload with the wrong digest.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/blobs/uploads/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("{}?digest={}", location, IMAGE_DIGEST))
+                    .body(Body::from("not the image"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response_error_code(response).await, "DIGEST_INVALID");
+    }
+
+    #[tokio::test]
+    async fn tag_listing_paginates() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        let location = ImageLocation::new("tests".to_owned(), "sample".to_owned());
+        for tag in ["v2", "latest", "v1"] {
+            ctx.registry
+                .storage
+                .put_manifest(
+                    &ManifestReference::new(location.clone(), Reference::new_tag(tag)),
+                    RAW_MANIFEST,
+                )
+                .await
+                .expect("failed to store manifest");
+        }
+
+        // Without pagination, all tags are returned in lexical order.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/tags/list")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(LINK).is_none());
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(
+            response_body,
+            br#"{"name":"tests/sample","tags":["latest","v1","v2"]}"#
+        );
+
+        // First page, should link to the next.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/tags/list?n=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(LINK).unwrap().to_str().unwrap(),
+            r#"</v2/tests/sample/tags/list?n=2&last=v1>; rel="next""#
+        );
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(
+            response_body,
+            br#"{"name":"tests/sample","tags":["latest","v1"]}"#
+        );
+
+        // Last page, no further link.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/tags/list?n=2&last=v1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(LINK).is_none());
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(response_body, br#"{"name":"tests/sample","tags":["v2"]}"#);
+
+        // Unknown repositories are reported as such.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/doesnot/exist/tags/list")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn catalog_lists_tagged_locations() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+        ctx.store_image_blobs("example.com/app").await;
+        ctx.store_image_blobs("tests/b").await;
+
+        for (repository, image) in [("tests", "sample"), ("example.com", "app"), ("tests", "b")] {
+            ctx.registry
+                .storage
+                .put_manifest(
+                    &ManifestReference::new(
+                        ImageLocation::new(repository.to_owned(), image.to_owned()),
+                        Reference::new_tag("latest"),
+                    ),
+                    RAW_MANIFEST,
+                )
+                .await
+                .expect("failed to store manifest");
+        }
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/_catalog?n=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(LINK).unwrap().to_str().unwrap(),
+            r#"</v2/_catalog?n=2&last=tests/b>; rel="next""#
+        );
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(
+            response_body,
+            br#"{"repositories":["example.com/app","tests/b"]}"#
+        );
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/_catalog?n=2&last=tests/b")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(LINK).is_none());
+        let response_body = collect_body(response.into_body()).await;
+        assert_eq!(response_body, br#"{"repositories":["tests/sample"]}"#);
+    }
+
+    #[tokio::test]
+    async fn manifest_deletion() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        let location = ImageLocation::new("tests".to_owned(), "sample".to_owned());
+        for tag in ["latest", "prod"] {
+            ctx.registry
+                .storage
+                .put_manifest(
+                    &ManifestReference::new(location.clone(), Reference::new_tag(tag)),
+                    RAW_MANIFEST,
+                )
+                .await
+                .expect("failed to store manifest");
+        }
+
+        // Deleting a tag leaves the other tags and the manifest intact.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("DELETE")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/prod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        assert!(ctx
+            .registry
+            .storage
+            .get_manifest(&ManifestReference::new(
+                location.clone(),
+                Reference::new_tag("prod")
+            ))
+            .await
+            .expect("failed to get manifest")
+            .is_none());
+        assert!(ctx
+            .registry
+            .storage
+            .get_manifest(&ManifestReference::new(
+                location.clone(),
+                Reference::new_digest(MANIFEST_DIGEST.digest)
+            ))
+            .await
+            .expect("failed to get manifest")
+            .is_some());
+
+        // Deleting a tag twice fails.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("DELETE")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/v2/tests/sample/manifests/prod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Deleting by digest removes remaining tags and the manifest.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("DELETE")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        assert_eq!(
+            ctx.registry
+                .storage
+                .list_tags(&location)
+                .await
+                .expect("failed to list tags"),
+            None
+        );
+        assert!(ctx
+            .registry
+            .storage
+            .get_manifest(&ManifestReference::new(
+                location,
+                Reference::new_digest(MANIFEST_DIGEST.digest)
+            ))
+            .await
+            .expect("failed to get manifest")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_removes_unreferenced_objects() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+
+        // The blobs referenced by the manifest and an orphan blob.
+        ctx.store_image_blobs("tests/sample").await;
+        let orphan_digest = ctx
+            .store_blob("tests/sample", b"nobody references this")
+            .await;
+
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(
+                    ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                    Reference::new_tag("latest"),
+                ),
+                RAW_MANIFEST,
+            )
+            .await
+            .expect("failed to store manifest");
+
+        // Everything is within the default grace period.
+        let report = gc::collect_garbage(ctx.registry.storage.as_ref(), GcOptions::default())
+            .await
+            .expect("garbage collection failed");
+        assert_eq!(report.objects_removed(), 0);
+
+        // A dry run reports, but does not remove the orphan.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/_rockslide/registry/gc?dry_run=true&grace_period=0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(ctx
+            .registry
+            .storage
+            .get_blob_metadata(orphan_digest)
+            .await
+            .expect("failed to get blob metadata")
+            .is_some());
+
+        let report = gc::collect_garbage(
+            ctx.registry.storage.as_ref(),
+            GcOptions {
+                dry_run: false,
+                grace_period: Duration::ZERO,
+            },
+        )
+        .await
+        .expect("garbage collection failed");
+
+        assert_eq!(report.blobs_removed().len(), 1);
+        assert_eq!(report.blobs_removed()[0].digest, orphan_digest);
+        assert!(report.manifests_removed().is_empty());
+        assert!(ctx
+            .registry
+            .storage
+            .get_blob_metadata(orphan_digest)
+            .await
+            .expect("failed to get blob metadata")
+            .is_none());
+        assert!(ctx
+            .registry
+            .storage
+            .get_blob_metadata(IMAGE_DIGEST.digest)
+            .await
+            .expect("failed to get blob metadata")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_waits_for_manifest_pushes() {
+        let (ctx, _service) = mk_test_app();
+
+        // A push in progress, which may reference blobs that are not tagged yet.
+        let push = ctx.registry.gc_lock.read().await;
+        assert!(tokio::time::timeout(
+            Duration::from_millis(50),
+            ctx.registry.collect_garbage(GcOptions::default())
+        )
+        .await
+        .is_err());
+
+        drop(push);
+        ctx.registry
+            .collect_garbage(GcOptions::default())
+            .await
+            .expect("garbage collection failed");
+    }
+
+    #[tokio::test]
+    async fn quotas_limit_repository_usage() {
+        let image_size = (RAW_MANIFEST.len() + RAW_CONFIG.len() + RAW_IMAGE.len()) as u64;
+        let quotas = Quotas::new(
+            None,
+            [
+                ("tests/sample".to_owned(), image_size),
+                ("tests/small".to_owned(), image_size - 1),
+                ("tests/untagged".to_owned(), image_size),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let (ctx, mut service) = mk_app(
+            Box::new(MemoryStorage::new()),
+            None,
+            quotas,
+            TagRules::default(),
+        );
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+        ctx.store_image_blobs("tests/small").await;
+        ctx.store_image_blobs("tests/unlimited").await;
+        ctx.store_image_blobs("tests/untagged").await;
+
+        // Tagging the same image again uses no additional space.
+        for (uri, status) in [
+            ("/v2/tests/sample/manifests/latest", StatusCode::CREATED),
+            ("/v2/tests/sample/manifests/stable", StatusCode::CREATED),
+            ("/v2/tests/small/manifests/latest", StatusCode::FORBIDDEN),
+            ("/v2/tests/unlimited/manifests/latest", StatusCode::CREATED),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("PUT")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(uri)
+                        .body(Body::from(RAW_MANIFEST))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+            if status == StatusCode::FORBIDDEN {
+                assert_eq!(response_error_code(response).await, "DENIED");
+            }
+        }
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/_rockslide/registry/usage")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "repositories": [
+                    {
+                        "name": "tests/sample",
+                        "bytes": image_size,
+                        "manifests": 1,
+                        "blobs": 2,
+                        "quota": image_size,
+                    },
+                    {
+                        "name": "tests/unlimited",
+                        "bytes": image_size,
+                        "manifests": 1,
+                        "blobs": 2,
+                        "quota": null,
+                    },
+                ]
+            })
+        );
+
+        // Untagged manifests count as well, leaving no room for another blob.
+        let digest = Digest::from_contents(RAW_MANIFEST);
+        let extra = b"extra";
+        for (method, uri, body, status) in [
+            (
+                "PUT",
+                format!("/v2/tests/untagged/manifests/sha256:{}", digest),
+                RAW_MANIFEST.to_vec(),
+                StatusCode::CREATED,
+            ),
+            (
+                "POST",
+                format!(
+                    "/v2/tests/untagged/blobs/uploads/?digest=sha256:{}",
+                    Digest::from_contents(extra)
+                ),
+                extra.to_vec(),
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method(method)
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(&uri)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+        assert!(ctx
+            .registry
+            .storage
+            .get_blob_metadata(Digest::from_contents(extra))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn quotas_limit_uploads() {
+        let limit = RAW_IMAGE.len() as u64 + 10;
+        let quotas = Quotas::new(
+            None,
+            [("tests/sample".to_owned(), limit)].into_iter().collect(),
+        );
+        let (ctx, mut service) = mk_app(
+            Box::new(MemoryStorage::new()),
+            None,
+            quotas,
+            TagRules::default(),
+        );
+        let app = service.ready().await.expect("could not launch service");
+
+        let mut uploads = Vec::new();
+        for _ in 0..4 {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("POST")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri("/v2/tests/sample/blobs/uploads/")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            uploads.push(
+                response
+                    .headers()
+                    .get(LOCATION)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_owned(),
+            );
+        }
+
+        // Uploads in progress count against each other, before they are finalized.
+        let padding: &[u8] = &[0; 20];
+        for (upload, body, status) in [
+            (&uploads[0], RAW_IMAGE, StatusCode::ACCEPTED),
+            (&uploads[1], padding, StatusCode::FORBIDDEN),
+            (&uploads[1], &padding[..5], StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("PATCH")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(upload)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", upload);
+            if status == StatusCode::FORBIDDEN {
+                assert_eq!(response_error_code(response).await, "DENIED");
+            }
+        }
+
+        // Uploads exceeding the quota are cancelled, finalized ones count as stored.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri(format!("{}?digest={}", uploads[0], IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        for (upload, body, status) in [
+            (&uploads[2], padding, StatusCode::FORBIDDEN),
+            (&uploads[3], &padding[..5], StatusCode::ACCEPTED),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .method("PATCH")
+                        .header(AUTHORIZATION, ctx.basic_auth())
+                        .uri(upload)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", upload);
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_grant_scoped_access() {
+        let (ctx, mut service) = mk_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+        ctx.store_image_blobs("tests/other").await;
+
+        // Clients are pointed to the token endpoint.
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .header("host", "registry.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(WWW_AUTHENTICATE).unwrap(),
+            "Bearer realm=\"http://registry.example.com/_rockslide/registry/token\",\
+             service=\"rockslide\",scope=\"repository:tests/sample:pull\""
+        );
+
+        // Tokens are only handed out in exchange for valid credentials.
+        let token_uri = "/_rockslide/registry/token?service=rockslide\
+                         &scope=repository:tests/sample:pull";
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(token_uri)
+                    .header(AUTHORIZATION, ctx.invalid_basic_auth())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(token_uri)
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+
+        // The token grants pulling from its repository, but nothing else.
+        check_responses(
+            app,
+            [
+                ("GET", "/v2/", StatusCode::OK),
+                ("GET", "/v2/tests/sample/tags/list", StatusCode::NOT_FOUND),
+                (
+                    "PUT",
+                    "/v2/tests/sample/manifests/latest",
+                    StatusCode::UNAUTHORIZED,
+                ),
+                ("GET", "/v2/tests/other/tags/list", StatusCode::UNAUTHORIZED),
+                ("POST", "/_rockslide/registry/gc", StatusCode::UNAUTHORIZED),
+            ]
+            .map(|(method, uri, status)| (authorized_request(&bearer, method, uri), status)),
+        )
+        .await;
+
+        let response = app
+            .call(authorized_request(
+                &bearer,
+                "PUT",
+                "/v2/tests/sample/manifests/latest",
+            ))
+            .await
+            .unwrap();
+        let challenge = response.headers().get(WWW_AUTHENTICATE).unwrap();
+        assert!(challenge
+            .to_str()
+            .unwrap()
+            .ends_with("scope=\"repository:tests/sample:pull,push\",error=\"insufficient_scope\""));
+
+        // A token asked for pushing allows that.
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(
+                        "/_rockslide/registry/token\
+                         ?scope=repository:tests/sample:pull,push&scope=repository:tests/other:pull",
+                    )
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let bearer = format!("Bearer {}", body["access_token"].as_str().unwrap());
+
+        check_responses(
+            app,
+            [
+                (
+                    authorized_request(&bearer, "PUT", "/v2/tests/sample/manifests/latest"),
+                    StatusCode::CREATED,
+                ),
+                (
+                    authorized_request(&bearer, "GET", "/v2/tests/other/tags/list"),
+                    StatusCode::NOT_FOUND,
+                ),
+                // Forged tokens are rejected.
+                (
+                    authorized_request("Bearer not.a.token", "GET", "/v2/"),
+                    StatusCode::UNAUTHORIZED,
+                ),
+            ],
+        )
+        .await;
+    }
+
+    /// Accepts the master key, with a role that may change while tokens are in use.
+    struct ChangingRole {
+        master_key: MasterKey,
+        role: std::sync::Mutex<Role>,
+    }
+
+    #[async_trait]
+    impl AuthProvider for ChangingRole {
+        async fn check_credentials(&self, creds: &UnverifiedCredentials) -> bool {
+            self.master_key.check_credentials(creds).await
+        }
+
+        async fn role(&self, _username: &str) -> Role {
+            *self.role.lock().unwrap()
+        }
+
+        async fn has_access_to(
+            &self,
+            _username: &str,
+            _namespace: &str,
+            _image: &str,
+            _action: Action,
+        ) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_follow_role_changes() {
+        let password = "random-test-password".to_owned();
+        let provider = Arc::new(ChangingRole {
+            master_key: MasterKey::new_key(password.clone()),
+            role: std::sync::Mutex::new(Role::Push),
+        });
+        let registry = ContainerRegistry::new(
+            Box::new(MemoryStorage::new()),
+            (),
+            provider.clone(),
+            Quotas::default(),
+            TokenIssuer::default(),
+            TagRules::default(),
+        );
+        let mut service = registry.clone().make_router().into_service::<Body>();
+        let ctx = Context {
+            registry,
+            tmp: None,
+            password,
+        };
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_rockslide/registry/token?scope=repository:tests/sample:pull,push")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+
+        let push = || authorized_request(&bearer, "PUT", "/v2/tests/sample/manifests/latest");
+        check_responses(app, [(push(), StatusCode::CREATED)]).await;
+
+        // Demoting the user revokes pushing, even though the token still grants it.
+        *provider.role.lock().unwrap() = Role::Pull;
+        check_responses(
+            app,
+            [
+                (push(), StatusCode::FORBIDDEN),
+                (
+                    authorized_request(&bearer, "GET", "/v2/tests/sample/manifests/latest"),
+                    StatusCode::OK,
+                ),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn user_roles_limit_access() {
+        let (ctx, accounts, mut service) = mk_accounts_test_app();
+        let users = accounts.users;
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+
+        // The master key user administrates the accounts.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri("/_rockslide/registry/users")
+                    .body(Body::from(r#"{"username": "reader", "role": "pull"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let reader_auth = ctx.basic_auth_as(
+            "reader",
+            created["password"].as_str().expect("password missing"),
+        );
+
+        let writer_password = users
+            .add("writer", Role::Push, Vec::new())
+            .await
+            .expect("add failed");
+        let writer_auth = ctx.basic_auth_as("writer", writer_password.reveal_str());
+
+        check_responses(
+            app,
+            [
+                (
+                    &writer_auth,
+                    "PUT",
+                    "/v2/tests/sample/manifests/latest",
+                    StatusCode::CREATED,
+                ),
+                (
+                    &reader_auth,
+                    "GET",
+                    "/v2/tests/sample/manifests/latest",
+                    StatusCode::OK,
+                ),
+                (
+                    &reader_auth,
+                    "PUT",
+                    "/v2/tests/sample/manifests/latest",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &reader_auth,
+                    "POST",
+                    "/v2/tests/sample/blobs/uploads/",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &writer_auth,
+                    "POST",
+                    "/_rockslide/registry/gc",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &writer_auth,
+                    "GET",
+                    "/_rockslide/registry/users",
+                    StatusCode::FORBIDDEN,
+                ),
+            ]
+            .map(|(auth, method, uri, status)| (authorized_request(auth, method, uri), status)),
+        )
+        .await;
+
+        // Disabled users are locked out entirely.
+        users
+            .set_disabled("reader", true)
+            .await
+            .expect("disable failed");
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, &reader_auth)
+                    .uri("/v2/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn repository_grants_limit_access() {
+        let (ctx, accounts, mut service) = mk_accounts_test_app();
+        let users = accounts.users;
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("shared/base").await;
+        ctx.store_image_blobs("team-a.example.com/index").await;
+        ctx.store_image_blobs("team-b.example.com/index").await;
+
+        for (repository, image) in [("shared", "base"), ("team-a.example.com", "index")] {
+            ctx.registry
+                .storage
+                .put_manifest(
+                    &ManifestReference::new(
+                        ImageLocation::new(repository.to_owned(), image.to_owned()),
+                        Reference::new_tag("latest"),
+                    ),
+                    RAW_MANIFEST,
+                )
+                .await
+                .expect("failed to store manifest");
+        }
+
+        let password = users
+            .add(
+                "team-a",
+                Role::Push,
+                vec![
+                    Grant::new("shared/*", Action::Pull),
+                    Grant::new("team-a.example.com/*", Action::Push),
+                ],
+            )
+            .await
+            .expect("add failed");
+        let auth = ctx.basic_auth_as("team-a", password.reveal_str());
+
+        check_responses(
+            app,
+            [
+                ("GET", "/v2/shared/base/manifests/latest", StatusCode::OK),
+                (
+                    "PUT",
+                    "/v2/shared/base/manifests/latest",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    "POST",
+                    "/v2/shared/base/blobs/uploads/",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    "GET",
+                    "/v2/team-a.example.com/index/manifests/latest",
+                    StatusCode::OK,
+                ),
+                (
+                    "PUT",
+                    "/v2/team-a.example.com/index/manifests/v1",
+                    StatusCode::CREATED,
+                ),
+                (
+                    "GET",
+                    "/v2/team-b.example.com/index/manifests/latest",
+                    StatusCode::FORBIDDEN,
+                ),
+            ]
+            .map(|(method, uri, status)| (authorized_request(&auth, method, uri), status)),
+        )
+        .await;
+
+        // Only repositories the user may pull from are listed.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, &auth)
+                    .uri("/v2/_catalog")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let catalog: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        assert_eq!(
+            catalog["repositories"],
+            serde_json::json!(["shared/base", "team-a.example.com/index"])
+        );
+
+        // Tokens only carry the actions granted.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, &auth)
+                    .uri("/_rockslide/registry/token?scope=repository:shared/base:pull,push")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let token: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let claims = ctx
+            .registry
+            .token_issuer
+            .verify(token["token"].as_str().expect("token missing"))
+            .expect("token invalid");
+        assert!(claims.allows("shared/base", Action::Pull));
+        assert!(!claims.allows("shared/base", Action::Push));
+    }
+
+    #[tokio::test]
+    async fn deploy_tokens_only_push_their_application() {
+        let (ctx, accounts, mut service) = mk_accounts_test_app();
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("myapp.example.com/index").await;
+        ctx.store_image_blobs("other.example.com/index").await;
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri("/_rockslide/registry/deploy-tokens")
+                    .body(Body::from(
+                        r#"{"name": "myapp-ci", "repository": "myapp.example.com/index", "tags": "prod"}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let minted: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+        let auth = ctx.basic_auth_as(
+            minted["username"].as_str().expect("username missing"),
+            minted["password"].as_str().expect("password missing"),
+        );
+
+        let other = accounts
+            .deploy_tokens
+            .mint(NewToken::new(
+                "other-ci",
+                "other.example.com/index",
+                None,
+                vec![Action::Pull, Action::Push],
+                None,
+            ))
+            .await
+            .expect("mint failed");
+        let other_auth = ctx.basic_auth_as(other.username(), other.password());
+
+        check_responses(
+            app,
+            [
+                (
+                    &auth,
+                    "PUT",
+                    "/v2/myapp.example.com/index/manifests/prod",
+                    StatusCode::CREATED,
+                ),
+                (
+                    &auth,
+                    "GET",
+                    "/v2/myapp.example.com/index/manifests/prod",
+                    StatusCode::OK,
+                ),
+                (
+                    &auth,
+                    "PUT",
+                    "/v2/myapp.example.com/index/manifests/latest",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &auth,
+                    "PUT",
+                    "/v2/other.example.com/index/manifests/prod",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &other_auth,
+                    "PUT",
+                    "/v2/other.example.com/index/manifests/prod",
+                    StatusCode::CREATED,
+                ),
+                (
+                    &other_auth,
+                    "GET",
+                    "/v2/myapp.example.com/index/manifests/prod",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &auth,
+                    "GET",
+                    "/_rockslide/registry/deploy-tokens",
+                    StatusCode::FORBIDDEN,
+                ),
+            ]
+            .map(|(auth, method, uri, status)| (authorized_request(auth, method, uri), status)),
+        )
+        .await;
+
+        // Deleting a manifest by digest changes every tag pointing to it, including `latest`.
+        let digest = ImageDigest::new(Digest::from_contents(RAW_MANIFEST));
+        check_responses(
+            app,
+            [
+                (
+                    &ctx.basic_auth(),
+                    "PUT",
+                    "/v2/myapp.example.com/index/manifests/latest".to_owned(),
+                    StatusCode::CREATED,
+                ),
+                (
+                    &auth,
+                    "DELETE",
+                    format!("/v2/myapp.example.com/index/manifests/{}", digest),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    &auth,
+                    "DELETE",
+                    "/v2/myapp.example.com/index/manifests/prod".to_owned(),
+                    StatusCode::ACCEPTED,
+                ),
+            ]
+            .map(|(auth, method, uri, status)| (authorized_request(auth, method, &uri), status)),
+        )
+        .await;
+
+        // Revoked tokens stop working right away.
+        let response = app
+            .call(
+                Request::builder()
+                    .method("DELETE")
+                    .header(AUTHORIZATION, ctx.basic_auth())
+                    .uri("/_rockslide/registry/deploy-tokens/myapp-ci")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, &auth)
+                    .uri("/v2/myapp.example.com/index/manifests/prod")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn tag_rules_protect_tags() {
+        let tag_rules = TagRules::new(vec![
+            TagRule::new("*", &["v*.*.*"], &[], &[]),
+            TagRule::new("tests/*", &[], &["prod"], &["release-bot"]),
+        ]);
+        let (ctx, mut service) = mk_app(
+            Box::new(MemoryStorage::new()),
+            None,
+            Quotas::default(),
+            tag_rules,
+        );
+        let app = service.ready().await.expect("could not launch service");
+        ctx.store_image_blobs("tests/sample").await;
+        ctx.store_image_blobs("other/sample").await;
+
+        // A second manifest of the same image, to overwrite tags with.
+        let mut other_manifest: serde_json::Value = serde_json::from_slice(RAW_MANIFEST).unwrap();
+        other_manifest["annotations"] = serde_json::json!({ "variant": "other" });
+        let other_manifest = serde_json::to_vec(&other_manifest).unwrap();
+
+        let bot_auth = ctx.basic_auth_as("release-bot", &ctx.password);
+        let digest = ImageDigest::new(Digest::from_contents(RAW_MANIFEST));
+
+        let auth = ctx.basic_auth();
+        let put_other = |uri: &str| {
+            Request::builder()
+                .method("PUT")
+                .header(AUTHORIZATION, &auth)
+                .uri(uri)
+                .body(Body::from(other_manifest.clone()))
+                .unwrap()
+        };
+        let by_digest = format!("/v2/tests/sample/manifests/{}", digest);
+
+        check_responses(
+            app,
+            [
+                (
+                    authorized_request(&auth, "PUT", "/v2/tests/sample/manifests/v1.0.0"),
+                    StatusCode::CREATED,
+                ),
+                // Pushing the same manifest again does not change anything.
+                (
+                    authorized_request(&auth, "PUT", "/v2/tests/sample/manifests/v1.0.0"),
+                    StatusCode::CREATED,
+                ),
+                (
+                    put_other("/v2/tests/sample/manifests/v1.0.0"),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    authorized_request(&auth, "DELETE", "/v2/tests/sample/manifests/v1.0.0"),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    authorized_request(&auth, "DELETE", &by_digest),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    put_other("/v2/tests/sample/manifests/latest"),
+                    StatusCode::CREATED,
+                ),
+                (
+                    authorized_request(&auth, "PUT", "/v2/tests/sample/manifests/latest"),
+                    StatusCode::CREATED,
+                ),
+                (
+                    authorized_request(&auth, "PUT", "/v2/tests/sample/manifests/prod"),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    authorized_request(&bot_auth, "PUT", "/v2/tests/sample/manifests/prod"),
+                    StatusCode::CREATED,
+                ),
+                (
+                    authorized_request(&auth, "DELETE", "/v2/tests/sample/manifests/prod"),
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    authorized_request(&auth, "PUT", "/v2/other/sample/manifests/prod"),
+                    StatusCode::CREATED,
+                ),
+            ],
+        )
+        .await;
+    }
+
+    /// Builds a request authorized by `auth`. Only `PUT`s carry a body, the sample manifest.
+    fn authorized_request(auth: &str, method: &str, uri: &str) -> Request<Body> {
+        let body = if method == "PUT" {
+            Body::from(RAW_MANIFEST)
+        } else {
+            Body::empty()
+        };
+
+        Request::builder()
+            .method(method)
+            .header(AUTHORIZATION, auth)
+            .uri(uri)
+            .body(body)
+            .unwrap()
+    }
+
+    /// Sends each request and checks the status of its response, refusals must be `DENIED`.
+    async fn check_responses<I>(app: &mut RouterIntoService<Body>, cases: I)
+    where
+        I: IntoIterator<Item = (Request<Body>, StatusCode)>,
+    {
+        for (request, status) in cases {
+            let description = format!("{} {}", request.method(), request.uri());
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), status, "{}", description);
+            if status == StatusCode::FORBIDDEN {
+                assert_eq!(
+                    response_error_code(response).await,
+                    "DENIED",
+                    "{}",
+                    description
+                );
+            }
+        }
+    }
+
+    /// Returns the code of the first error in an error response.
+    async fn response_error_code(response: Response) -> String {
+        let errors: serde_json::Value =
+            serde_json::from_slice(&collect_body(response.into_body()).await)
+                .expect("error response is not valid JSON");
+        errors["errors"][0]["code"]
+            .as_str()
+            .expect("error response is missing code")
+            .to_owned()
     }
 
     async fn collect_body(mut body: Body) -> Vec<u8> {
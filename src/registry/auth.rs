@@ -6,14 +6,19 @@ use axum::{
     http::{
         header::{self},
         request::Parts,
-        StatusCode,
+        Method, StatusCode,
     },
+    response::{IntoResponse, Response},
 };
+use constant_time_eq::constant_time_eq;
 use sec::Secret;
+use serde::{Deserialize, Serialize};
 
 use super::{
+    token::{Access, Action, Claims},
+    types::{ErrorCode, OciError},
     www_authenticate::{self},
-    ContainerRegistry,
+    ContainerRegistry, REALM,
 };
 
 #[derive(Debug)]
@@ -47,31 +52,258 @@ impl<S> FromRequestParts<S> for UnverifiedCredentials {
     }
 }
 
+/// A user authenticated either by credentials or by a bearer token.
 #[derive(Debug)]
-pub(crate) struct ValidUser(UnverifiedCredentials);
+pub(crate) struct ValidUser {
+    username: String,
+    /// Claims of the bearer token presented, `None` if credentials were sent instead.
+    token: Option<Claims>,
+}
 
 impl ValidUser {
-    #[allow(dead_code)] // TODO
     pub(crate) fn username(&self) -> &str {
-        &self.0.username
+        &self.username
+    }
+
+    /// Checks whether the user's token, if any, grants `action` on the repository `name`.
+    ///
+    /// Users who sent credentials are not restricted by a token.
+    pub(crate) fn token_allows(&self, name: &str, action: Action) -> bool {
+        self.token
+            .as_ref()
+            .is_none_or(|claims| claims.allows(name, action))
+    }
+}
+
+/// The access a request requires, determined by its path and method.
+enum RequiredAccess {
+    /// Any authenticated user may proceed, as for the API version check.
+    Authenticated,
+    /// Access to the repository the request refers to.
+    Repository { name: String, action: Action },
+    /// Administrative access. Tokens are not accepted, credentials must be sent instead.
+    Admin,
+}
+
+impl RequiredAccess {
+    fn of(parts: &Parts) -> Self {
+        let Some(rest) = parts.uri.path().strip_prefix("/v2/") else {
+            return RequiredAccess::Admin;
+        };
+
+        // The catalog only lists repositories the token grants access to.
+        if rest.is_empty() || rest == "_catalog" {
+            return RequiredAccess::Authenticated;
+        }
+
+        let mut segments = rest.split('/');
+        match (segments.next(), segments.next(), segments.next()) {
+            (Some(repository), Some(image), Some(_)) if !repository.starts_with('_') => {
+                let action = if parts.method == Method::GET || parts.method == Method::HEAD {
+                    Action::Pull
+                } else {
+                    Action::Push
+                };
+
+                RequiredAccess::Repository {
+                    name: format!("{}/{}", repository, image),
+                    action,
+                }
+            }
+            _ => RequiredAccess::Admin,
+        }
+    }
+
+    fn required_role(&self) -> Role {
+        match self {
+            RequiredAccess::Authenticated => Role::Pull,
+            RequiredAccess::Repository { action, .. } => Role::for_action(*action),
+            RequiredAccess::Admin => Role::Admin,
+        }
+    }
+
+    fn is_granted_by(&self, claims: &Claims) -> bool {
+        match self {
+            RequiredAccess::Authenticated => true,
+            RequiredAccess::Repository { name, action } => claims.allows(name, *action),
+            RequiredAccess::Admin => false,
+        }
+    }
+
+    /// Builds the response for a request lacking authentication.
+    fn unauthorized(
+        &self,
+        registry: &ContainerRegistry,
+        parts: &Parts,
+        error: Option<&str>,
+    ) -> Response {
+        match self {
+            RequiredAccess::Authenticated => {
+                unauthorized(registry.token_issuer.challenge(&parts.headers, None, error))
+            }
+            RequiredAccess::Repository { name, action } => {
+                // Pushing involves checking for existing blobs, so clients ask for both.
+                let actions = match action {
+                    Action::Pull => vec![Action::Pull],
+                    Action::Push => vec![Action::Pull, Action::Push],
+                };
+                let scope = Access::repository(name.clone(), actions);
+
+                unauthorized(
+                    registry
+                        .token_issuer
+                        .challenge(&parts.headers, Some(&scope), error),
+                )
+            }
+            RequiredAccess::Admin => unauthorized(basic_challenge(&registry.realm)),
+        }
     }
 }
 
 #[async_trait]
 impl FromRequestParts<Arc<ContainerRegistry>> for ValidUser {
-    type Rejection = StatusCode;
+    type Rejection = Response;
 
     async fn from_request_parts(
         parts: &mut Parts,
         state: &Arc<ContainerRegistry>,
     ) -> Result<Self, Self::Rejection> {
-        let unverified = UnverifiedCredentials::from_request_parts(parts, state).await?;
+        let required = RequiredAccess::of(parts);
 
-        // We got a set of credentials, now verify.
-        if !state.auth_provider.check_credentials(&unverified).await {
-            Err(StatusCode::UNAUTHORIZED)
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| www_authenticate::bearer_token(value.as_bytes()).ok())
+            .map(|(_unparsed, token)| token);
+        let user = if let Some(token) = bearer {
+            let claims = str::from_utf8(token)
+                .ok()
+                .and_then(|token| state.token_issuer.verify(token))
+                .ok_or_else(|| required.unauthorized(state, parts, Some("invalid_token")))?;
+
+            if !required.is_granted_by(&claims) {
+                return Err(required.unauthorized(state, parts, Some("insufficient_scope")));
+            }
+
+            Self {
+                username: claims.subject().to_owned(),
+                token: Some(claims),
+            }
         } else {
-            Ok(Self(unverified))
+            // Malformed credentials are treated like missing ones, the client should try again.
+            let unverified = UnverifiedCredentials::from_request_parts(parts, state)
+                .await
+                .map_err(|_| required.unauthorized(state, parts, None))?;
+
+            // We got a set of credentials, now verify.
+            if !state.auth_provider.check_credentials(&unverified).await {
+                return Err(required.unauthorized(state, parts, None));
+            }
+
+            Self {
+                username: unverified.username,
+                token: None,
+            }
+        };
+
+        // Roles and permissions may have changed since a token was issued, so they are checked
+        // for tokens as well.
+        if state.auth_provider.role(&user.username).await < required.required_role() {
+            return Err(OciError::new(ErrorCode::Denied).into_response());
+        }
+
+        if let RequiredAccess::Repository { name, action } = &required {
+            let permitted = match name.split_once('/') {
+                Some((repository, image)) => {
+                    state
+                        .auth_provider
+                        .has_access_to(&user.username, repository, image, *action)
+                        .await
+                }
+                None => false,
+            };
+
+            if !permitted {
+                return Err(OciError::new(ErrorCode::Denied)
+                    .with_message(format!("{} access denied", action))
+                    .with_detail(name.clone())
+                    .into_response());
+            }
+        }
+
+        Ok(user)
+    }
+}
+
+/// An administrator, authenticated through credentials by the provider in the state.
+///
+/// Guards admin APIs living outside of the registry's router.
+pub(crate) struct Admin;
+
+#[async_trait]
+impl<P> FromRequestParts<Arc<P>> for Admin
+where
+    P: AuthProvider + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        provider: &Arc<P>,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = || unauthorized(basic_challenge(REALM));
+
+        let creds = UnverifiedCredentials::from_request_parts(parts, provider)
+            .await
+            .map_err(|_| unauthorized())?;
+
+        if !provider.check_credentials(&creds).await {
+            return Err(unauthorized());
+        }
+
+        if provider.role(&creds.username).await < Role::Admin {
+            return Err(OciError::new(ErrorCode::Denied).into_response());
+        }
+
+        Ok(Admin)
+    }
+}
+
+/// Builds a challenge asking for credentials through basic authentication.
+pub(super) fn basic_challenge(realm: &str) -> String {
+    format!("Basic realm=\"{realm}\"")
+}
+
+/// Builds an `UNAUTHORIZED` response, asking the client to authenticate.
+pub(super) fn unauthorized(challenge: String) -> Response {
+    let mut response = OciError::new(ErrorCode::Unauthorized).into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        challenge
+            .parse()
+            .expect("challenge should be a valid header value"),
+    );
+    response
+}
+
+/// Global permission level of a user, each role including the ones before it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// May pull images.
+    Pull,
+    /// May pull and push images.
+    Push,
+    /// May do anything, including administration.
+    Admin,
+}
+
+impl Role {
+    /// Returns the role required to perform `action`.
+    pub(crate) fn for_action(action: Action) -> Self {
+        match action {
+            Action::Pull => Role::Pull,
+            Action::Push => Role::Push,
         }
     }
 }
@@ -81,8 +313,36 @@ pub(crate) trait AuthProvider: Send + Sync {
     /// Determine whether the supplied credentials are valid.
     async fn check_credentials(&self, creds: &UnverifiedCredentials) -> bool;
 
-    /// Check if the given user has access to the given repo.
-    async fn has_access_to(&self, username: &str, namespace: &str, image: &str) -> bool;
+    /// Returns the role of a user whose credentials have been checked.
+    async fn role(&self, username: &str) -> Role;
+
+    /// Check if the given user may perform `action` on the given repo.
+    async fn has_access_to(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        action: Action,
+    ) -> bool;
+
+    /// Check if the given user may point `tag` of the given repo somewhere else, or remove it.
+    ///
+    /// Only called for users with push access to the repo.
+    async fn may_change_tag(
+        &self,
+        _username: &str,
+        _namespace: &str,
+        _image: &str,
+        _tag: &str,
+    ) -> bool {
+        true
+    }
+
+    /// Check if the given user is a deploy token, which is scoped to the applications it was
+    /// minted for instead of holding a role across the registry.
+    async fn is_deploy_token(&self, _username: &str) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -91,7 +351,17 @@ impl AuthProvider for bool {
         *self
     }
 
-    async fn has_access_to(&self, _username: &str, _namespace: &str, _image: &str) -> bool {
+    async fn role(&self, _username: &str) -> Role {
+        Role::Admin
+    }
+
+    async fn has_access_to(
+        &self,
+        _username: &str,
+        _namespace: &str,
+        _image: &str,
+        _action: Action,
+    ) -> bool {
         *self
     }
 }
@@ -105,17 +375,26 @@ impl AuthProvider for HashMap<String, Secret<String>> {
             password: unverified_password,
         }: &UnverifiedCredentials,
     ) -> bool {
-        if let Some(correct_password) = self.get(unverified_username) {
-            // TODO: Use constant-time compare. Maybe add to `sec`?
-            if correct_password == unverified_password {
-                return true;
-            }
-        }
+        self.get(unverified_username)
+            .is_some_and(|correct_password| {
+                constant_time_eq(
+                    correct_password.reveal_str().as_bytes(),
+                    unverified_password.reveal_str().as_bytes(),
+                )
+            })
+    }
 
-        false
+    async fn role(&self, _username: &str) -> Role {
+        Role::Admin
     }
 
-    async fn has_access_to(&self, _username: &str, _namespace: &str, _image: &str) -> bool {
+    async fn has_access_to(
+        &self,
+        _username: &str,
+        _namespace: &str,
+        _image: &str,
+        _action: Action,
+    ) -> bool {
         true
     }
 }
@@ -131,7 +410,34 @@ where
     }
 
     #[inline(always)]
-    async fn has_access_to(&self, username: &str, namespace: &str, image: &str) -> bool {
-        <T as AuthProvider>::has_access_to(self, username, namespace, image).await
+    async fn role(&self, username: &str) -> Role {
+        <T as AuthProvider>::role(self, username).await
+    }
+
+    #[inline(always)]
+    async fn has_access_to(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        action: Action,
+    ) -> bool {
+        <T as AuthProvider>::has_access_to(self, username, namespace, image, action).await
+    }
+
+    #[inline(always)]
+    async fn may_change_tag(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        tag: &str,
+    ) -> bool {
+        <T as AuthProvider>::may_change_tag(self, username, namespace, image, tag).await
+    }
+
+    #[inline(always)]
+    async fn is_deploy_token(&self, username: &str) -> bool {
+        <T as AuthProvider>::is_deploy_token(self, username).await
     }
 }
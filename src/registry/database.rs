@@ -0,0 +1,125 @@
+//! Small TOML databases next to the registry storage, e.g. user accounts and deploy tokens.
+//!
+//! Databases are loaded once on startup and kept in memory. Every change rewrites the whole file,
+//! which is synced and renamed into place so that a crash leaves either the old or the new
+//! version behind.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use super::{
+    storage,
+    types::{ErrorCode, OciError},
+};
+
+/// A database persisted to a single TOML file.
+#[derive(Debug)]
+pub(crate) struct DatabaseFile {
+    path: PathBuf,
+    /// Human readable name, used in error messages.
+    description: &'static str,
+}
+
+impl DatabaseFile {
+    pub(crate) fn new(storage_path: &Path, file_name: &str, description: &'static str) -> Self {
+        Self {
+            path: storage_path.join(file_name),
+            description,
+        }
+    }
+
+    /// Loads the database, starting out empty if it has not been written yet.
+    pub(crate) fn load<T: DeserializeOwned + Default>(&self) -> anyhow::Result<T> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("failed to parse {}", self.description))
+                .with_context(|| self.path.display().to_string()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(err) => Err(err)
+                .with_context(|| format!("could not read {}", self.description))
+                .with_context(|| self.path.display().to_string()),
+        }
+    }
+
+    /// Writes the database to disk, durably replacing the previous file.
+    pub(crate) async fn save<T: Serialize>(&self, contents: &T) -> anyhow::Result<()> {
+        let raw = toml::to_string(contents)
+            .with_context(|| format!("could not serialize {}", self.description))?;
+
+        let parent = self.path.parent().expect("database should have parent");
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("could not create storage directory")?;
+
+        storage::replace_durably(
+            &self.path.with_extension("toml.tmp"),
+            &self.path,
+            raw.as_bytes(),
+        )
+        .await
+        .with_context(|| format!("could not write {}", self.description))
+    }
+}
+
+/// Failure to change a database record through the admin API.
+///
+/// Variants carry the kind of record, e.g. `user`, followed by its name.
+#[derive(Debug, Error)]
+pub(crate) enum RecordError {
+    #[error("invalid {0} name {1}")]
+    InvalidName(&'static str, String),
+    #[error("invalid {0} {1}: {2}")]
+    Invalid(&'static str, String, &'static str),
+    #[error("{0} {1} already exists")]
+    Exists(&'static str, String),
+    #[error("unknown {0} {1}")]
+    Unknown(&'static str, String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for RecordError {
+    fn into_response(self) -> Response {
+        match self {
+            RecordError::InvalidName(kind, name) => OciError::new(ErrorCode::NameInvalid)
+                .with_message(format!("invalid {} name", kind))
+                .with_detail(name)
+                .into_response(),
+            RecordError::Invalid(_, name, reason) => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::Unsupported)
+                    .with_message(reason)
+                    .with_detail(name),
+            )
+                .into_response(),
+            RecordError::Exists(kind, name) => (
+                StatusCode::CONFLICT,
+                OciError::new(ErrorCode::Denied)
+                    .with_message(format!("{} already exists", kind))
+                    .with_detail(name),
+            )
+                .into_response(),
+            RecordError::Unknown(kind, name) => OciError::new(ErrorCode::NameUnknown)
+                .with_message(format!("unknown {}", kind))
+                .with_detail(name)
+                .into_response(),
+            RecordError::Internal(err) => {
+                error!(err = format!("{:#}", err), "database error");
+                OciError::new(ErrorCode::Unknown)
+                    .with_message(err.to_string())
+                    .into_response()
+            }
+        }
+    }
+}
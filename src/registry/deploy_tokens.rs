@@ -0,0 +1,677 @@
+//! Deploy tokens, credentials limited to a single application.
+//!
+//! A deploy token may only perform the actions it was minted with, on repositories matching a
+//! pattern and optionally only on tags matching another one, e.g. pushing `prod` of
+//! `myapp.example.com/index`. Tokens log in using the username `deploy+<name>` and their secret
+//! as password. They can expire, be revoked at any time and record when they were last used.
+//! Everyone else is authenticated by the wrapped provider.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use constant_time_eq::constant_time_eq;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use super::{
+    auth::{Admin, AuthProvider, Role, UnverifiedCredentials},
+    database::{DatabaseFile, RecordError},
+    pattern::Pattern,
+    token::Action,
+    types,
+    users::{generate_password, is_valid_username},
+};
+
+/// Name of the file holding the tokens, inside the storage path.
+const TOKENS_FILE: &str = "deploy_tokens.toml";
+
+/// Prefix of the usernames deploy tokens log in with, followed by the token name.
+const USERNAME_PREFIX: &str = "deploy+";
+
+/// Seconds a last-used timestamp must advance before it is updated again, which is also how
+/// often updated timestamps are written to disk.
+const LAST_USED_PRECISION: u64 = 60;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct TokenRecord {
+    /// Hex encoded SHA-256 of the secret. Secrets are random, a slow hash would gain nothing.
+    secret_hash: String,
+    repository: Pattern,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<Pattern>,
+    actions: Vec<Action>,
+    /// Unix timestamps, in seconds.
+    created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used: Option<u64>,
+}
+
+impl TokenRecord {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TokensFile {
+    #[serde(default)]
+    tokens: BTreeMap<String, TokenRecord>,
+}
+
+/// A token, as listed through the admin API.
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenSummary {
+    name: String,
+    username: String,
+    repository: Pattern,
+    tags: Option<Pattern>,
+    actions: Vec<Action>,
+    created_at: u64,
+    expires_at: Option<u64>,
+    last_used: Option<u64>,
+    expired: bool,
+}
+
+/// Scopes of a token to mint.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NewToken {
+    name: String,
+    repository: Pattern,
+    #[serde(default)]
+    tags: Option<Pattern>,
+    #[serde(default = "default_actions")]
+    actions: Vec<Action>,
+    /// Lifetime in seconds, tokens without one never expire.
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+impl NewToken {
+    #[cfg(test)]
+    pub(crate) fn new(
+        name: &str,
+        repository: &str,
+        tags: Option<&str>,
+        actions: Vec<Action>,
+        expires_in: Option<u64>,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            repository: Pattern::new(repository),
+            tags: tags.map(Pattern::new),
+            actions,
+            expires_in,
+        }
+    }
+}
+
+fn default_actions() -> Vec<Action> {
+    vec![Action::Pull, Action::Push]
+}
+
+/// Credentials of a newly minted token, only ever shown once.
+#[derive(Debug, Serialize)]
+pub(crate) struct MintedToken {
+    name: String,
+    username: String,
+    password: String,
+    expires_at: Option<u64>,
+}
+
+impl MintedToken {
+    #[cfg(test)]
+    pub(crate) fn username(&self) -> &str {
+        &self.username
+    }
+
+    #[cfg(test)]
+    pub(crate) fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+/// Deploy token database, persisted to [`TOKENS_FILE`].
+pub(crate) struct DeployTokens {
+    file: DatabaseFile,
+    /// Authenticates everyone who is not using a deploy token.
+    inner: Arc<dyn AuthProvider>,
+    tokens: RwLock<BTreeMap<String, TokenRecord>>,
+    /// Set when a last-used timestamp changed since the tokens were last written to disk.
+    usage_changed: AtomicBool,
+}
+
+impl DeployTokens {
+    /// Loads the tokens stored below `storage_path`, starting out empty if there are none.
+    pub(crate) fn open(storage_path: &Path, inner: Arc<dyn AuthProvider>) -> anyhow::Result<Self> {
+        let file = DatabaseFile::new(storage_path, TOKENS_FILE, "deploy token database");
+        let tokens = file.load::<TokensFile>()?.tokens;
+
+        Ok(Self {
+            file,
+            inner,
+            tokens: RwLock::new(tokens),
+            usage_changed: AtomicBool::new(false),
+        })
+    }
+
+    /// Periodically writes last-used timestamps to disk, keeping disk writes out of logins.
+    pub(crate) fn spawn_usage_writer(self: &Arc<Self>) {
+        let tokens = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(LAST_USED_PRECISION));
+
+            loop {
+                ticker.tick().await;
+                tokens.save_usage().await;
+            }
+        });
+    }
+
+    /// Writes the tokens to disk if any last-used timestamp changed since they were last written.
+    pub(crate) async fn save_usage(&self) {
+        if !self.usage_changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        // Mints and revocations save while holding the write lock, so they cannot interleave.
+        let tokens = self.tokens.read().await;
+        if let Err(err) = self.save(&tokens).await {
+            self.usage_changed.store(true, Ordering::Relaxed);
+            warn!(
+                err = format!("{:#}", err),
+                "could not record deploy token usage"
+            );
+        }
+    }
+
+    pub(crate) fn make_router(self: Arc<Self>) -> Router {
+        Router::new()
+            .route("/_rockslide/registry/deploy-tokens", get(tokens_list))
+            .route("/_rockslide/registry/deploy-tokens", post(tokens_mint))
+            .route(
+                "/_rockslide/registry/deploy-tokens/:name",
+                delete(tokens_revoke),
+            )
+            .with_state(self)
+    }
+
+    pub(crate) async fn list(&self) -> Vec<TokenSummary> {
+        let now = unix_now();
+
+        self.tokens
+            .read()
+            .await
+            .iter()
+            .map(|(name, record)| TokenSummary {
+                name: name.clone(),
+                username: format!("{}{}", USERNAME_PREFIX, name),
+                repository: record.repository.clone(),
+                tags: record.tags.clone(),
+                actions: record.actions.clone(),
+                created_at: record.created_at,
+                expires_at: record.expires_at,
+                last_used: record.last_used,
+                expired: record.is_expired(now),
+            })
+            .collect()
+    }
+
+    /// Creates a new token with a random secret, returning its credentials.
+    pub(crate) async fn mint(&self, new_token: NewToken) -> Result<MintedToken, RecordError> {
+        let NewToken {
+            name,
+            repository,
+            tags,
+            actions,
+            expires_in,
+        } = new_token;
+
+        if !is_valid_username(&name) {
+            return Err(RecordError::InvalidName("token", name));
+        }
+
+        if actions.is_empty() {
+            return Err(RecordError::Invalid(
+                "token",
+                name,
+                "token must allow at least one action",
+            ));
+        }
+
+        let mut tokens = self.tokens.write().await;
+        if tokens.contains_key(&name) {
+            return Err(RecordError::Exists("token", name));
+        }
+
+        let now = unix_now();
+        let password = generate_password();
+        let expires_at = expires_in.map(|expires_in| now.saturating_add(expires_in));
+
+        let mut updated = tokens.clone();
+        updated.insert(
+            name.clone(),
+            TokenRecord {
+                secret_hash: hash_secret(password.reveal_str()),
+                repository,
+                tags,
+                actions,
+                created_at: now,
+                expires_at,
+                last_used: None,
+            },
+        );
+        self.save(&updated).await?;
+        *tokens = updated;
+
+        info!(%name, ?expires_at, "minted deploy token");
+        Ok(MintedToken {
+            username: format!("{}{}", USERNAME_PREFIX, name),
+            name,
+            password: password.reveal_str().to_owned(),
+            expires_at,
+        })
+    }
+
+    /// Revokes a token, which immediately stops working.
+    pub(crate) async fn revoke(&self, name: &str) -> Result<(), RecordError> {
+        let mut tokens = self.tokens.write().await;
+
+        let mut updated = tokens.clone();
+        if updated.remove(name).is_none() {
+            return Err(RecordError::Unknown("token", name.to_owned()));
+        }
+        self.save(&updated).await?;
+        *tokens = updated;
+
+        info!(%name, "revoked deploy token");
+        Ok(())
+    }
+
+    /// Applies `f` to the token called `name`, if it exists and has not expired.
+    async fn with_token<F, R>(&self, name: &str, f: F) -> Option<R>
+    where
+        F: FnOnce(&TokenRecord) -> R,
+    {
+        self.tokens
+            .read()
+            .await
+            .get(name)
+            .filter(|record| !record.is_expired(unix_now()))
+            .map(f)
+    }
+
+    /// Writes the tokens to disk, replacing the previous file atomically.
+    async fn save(&self, tokens: &BTreeMap<String, TokenRecord>) -> anyhow::Result<()> {
+        self.file
+            .save(&TokensFile {
+                tokens: tokens.clone(),
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl AuthProvider for DeployTokens {
+    async fn check_credentials(&self, creds: &UnverifiedCredentials) -> bool {
+        let Some(name) = creds.username.strip_prefix(USERNAME_PREFIX) else {
+            return self.inner.check_credentials(creds).await;
+        };
+
+        let now = unix_now();
+        let secret_hash = hash_secret(creds.password.reveal_str());
+
+        let outdated = {
+            let tokens = self.tokens.read().await;
+            let Some(record) = tokens.get(name) else {
+                return false;
+            };
+
+            if record.is_expired(now)
+                || !constant_time_eq(record.secret_hash.as_bytes(), secret_hash.as_bytes())
+            {
+                return false;
+            }
+
+            record
+                .last_used
+                .is_none_or(|last_used| now >= last_used + LAST_USED_PRECISION)
+        };
+
+        // Usage is only recorded in memory here, see `spawn_usage_writer`.
+        if outdated {
+            if let Some(record) = self.tokens.write().await.get_mut(name) {
+                record.last_used = Some(now);
+                self.usage_changed.store(true, Ordering::Relaxed);
+            }
+        }
+
+        true
+    }
+
+    /// Deploy tokens never administrate, they only push if they were minted to do so.
+    async fn role(&self, username: &str) -> Role {
+        let Some(name) = username.strip_prefix(USERNAME_PREFIX) else {
+            return self.inner.role(username).await;
+        };
+
+        match self
+            .with_token(name, |record| record.actions.contains(&Action::Push))
+            .await
+        {
+            Some(true) => Role::Push,
+            _ => Role::Pull,
+        }
+    }
+
+    async fn has_access_to(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        action: Action,
+    ) -> bool {
+        let Some(name) = username.strip_prefix(USERNAME_PREFIX) else {
+            return self
+                .inner
+                .has_access_to(username, namespace, image, action)
+                .await;
+        };
+
+        let repository = format!("{}/{}", namespace, image);
+        self.with_token(name, |record| {
+            record.actions.contains(&action) && record.repository.matches(&repository)
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    async fn may_change_tag(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        tag: &str,
+    ) -> bool {
+        let Some(name) = username.strip_prefix(USERNAME_PREFIX) else {
+            return self
+                .inner
+                .may_change_tag(username, namespace, image, tag)
+                .await;
+        };
+
+        self.with_token(name, |record| {
+            record.tags.as_ref().is_none_or(|tags| tags.matches(tag))
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    async fn is_deploy_token(&self, username: &str) -> bool {
+        username.starts_with(USERNAME_PREFIX) || self.inner.is_deploy_token(username).await
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+async fn tokens_list(State(tokens): State<Arc<DeployTokens>>, _admin: Admin) -> Response {
+    types::json_response(&tokens.list().await)
+}
+
+async fn tokens_mint(
+    State(tokens): State<Arc<DeployTokens>>,
+    _admin: Admin,
+    Json(new_token): Json<NewToken>,
+) -> Result<Response, RecordError> {
+    let minted = tokens.mint(new_token).await?;
+
+    Ok((StatusCode::CREATED, types::json_response(&minted)).into_response())
+}
+
+async fn tokens_revoke(
+    State(tokens): State<Arc<DeployTokens>>,
+    UrlPath(name): UrlPath<String>,
+    _admin: Admin,
+) -> Result<StatusCode, RecordError> {
+    tokens.revoke(&name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sec::Secret;
+    use tempdir::TempDir;
+
+    use super::{DeployTokens, NewToken, RecordError, TOKENS_FILE};
+    use crate::{
+        config::MasterKey,
+        registry::{
+            auth::{AuthProvider, Role, UnverifiedCredentials},
+            token::Action,
+        },
+    };
+
+    fn creds(username: &str, password: &str) -> UnverifiedCredentials {
+        UnverifiedCredentials {
+            username: username.to_owned(),
+            password: Secret::new(password.to_owned()),
+        }
+    }
+
+    fn open(tmp: &TempDir) -> DeployTokens {
+        DeployTokens::open(
+            tmp.path(),
+            Arc::new(MasterKey::new_key("master-key".to_owned())),
+        )
+        .expect("could not open deploy tokens")
+    }
+
+    #[tokio::test]
+    async fn deploy_tokens_are_limited_to_their_scopes() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let tokens = open(&tmp);
+
+        let minted = tokens
+            .mint(NewToken::new(
+                "myapp-ci",
+                "myapp.example.com/index",
+                Some("prod"),
+                vec![Action::Pull, Action::Push],
+                None,
+            ))
+            .await
+            .expect("mint failed");
+        assert_eq!(minted.username(), "deploy+myapp-ci");
+
+        assert!(
+            tokens
+                .check_credentials(&creds(minted.username(), minted.password()))
+                .await
+        );
+        assert!(
+            !tokens
+                .check_credentials(&creds(minted.username(), "master-key"))
+                .await
+        );
+        assert_eq!(tokens.role(minted.username()).await, Role::Push);
+
+        for (namespace, image, action, expected) in [
+            ("myapp.example.com", "index", Action::Pull, true),
+            ("myapp.example.com", "index", Action::Push, true),
+            ("otherapp.example.com", "index", Action::Pull, false),
+        ] {
+            assert_eq!(
+                tokens
+                    .has_access_to(minted.username(), namespace, image, action)
+                    .await,
+                expected
+            );
+        }
+
+        let (namespace, image) = ("myapp.example.com", "index");
+        assert!(
+            tokens
+                .may_change_tag(minted.username(), namespace, image, "prod")
+                .await
+        );
+        assert!(
+            !tokens
+                .may_change_tag(minted.username(), namespace, image, "latest")
+                .await
+        );
+
+        // Everyone else is authenticated by the wrapped provider.
+        assert!(
+            tokens
+                .check_credentials(&creds("admin", "master-key"))
+                .await
+        );
+        assert_eq!(tokens.role("admin").await, Role::Admin);
+        assert!(tokens.is_deploy_token(minted.username()).await);
+        assert!(!tokens.is_deploy_token("admin").await);
+
+        assert!(matches!(
+            tokens
+                .mint(NewToken::new(
+                    "myapp-ci",
+                    "*",
+                    None,
+                    vec![Action::Pull],
+                    None
+                ))
+                .await,
+            Err(RecordError::Exists(..))
+        ));
+        assert!(matches!(
+            tokens
+                .mint(NewToken::new("empty", "*", None, Vec::new(), None))
+                .await,
+            Err(RecordError::Invalid(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens_stop_working() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let tokens = open(&tmp);
+
+        let expired = tokens
+            .mint(NewToken::new(
+                "expired",
+                "*",
+                None,
+                vec![Action::Pull],
+                Some(0),
+            ))
+            .await
+            .expect("mint failed");
+        assert!(
+            !tokens
+                .check_credentials(&creds(expired.username(), expired.password()))
+                .await
+        );
+
+        let revoked = tokens
+            .mint(NewToken::new(
+                "revoked",
+                "*",
+                None,
+                vec![Action::Pull],
+                None,
+            ))
+            .await
+            .expect("mint failed");
+        assert!(
+            tokens
+                .check_credentials(&creds(revoked.username(), revoked.password()))
+                .await
+        );
+
+        tokens.revoke("revoked").await.expect("revoke failed");
+        assert!(
+            !tokens
+                .check_credentials(&creds(revoked.username(), revoked.password()))
+                .await
+        );
+        assert!(
+            !tokens
+                .has_access_to(revoked.username(), "tests", "sample", Action::Pull)
+                .await
+        );
+        assert!(matches!(
+            tokens.revoke("revoked").await,
+            Err(RecordError::Unknown(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn usage_is_recorded_and_persisted() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let tokens = open(&tmp);
+
+        let minted = tokens
+            .mint(NewToken::new(
+                "ci",
+                "tests/*",
+                None,
+                vec![Action::Pull],
+                None,
+            ))
+            .await
+            .expect("mint failed");
+        assert!(tokens.list().await[0].last_used.is_none());
+
+        assert!(
+            tokens
+                .check_credentials(&creds(minted.username(), minted.password()))
+                .await
+        );
+
+        assert!(tokens.list().await[0].last_used.is_some());
+        tokens.save_usage().await;
+
+        let contents =
+            std::fs::read_to_string(tmp.path().join(TOKENS_FILE)).expect("could not read file");
+        assert!(!contents.contains(minted.password()));
+
+        let reopened = open(&tmp);
+        assert!(reopened.list().await[0].last_used.is_some());
+        assert!(
+            reopened
+                .check_credentials(&creds(minted.username(), minted.password()))
+                .await
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Integrity check for registry storage.
+//!
+//! Blobs and manifests are re-hashed and compared against the digest they are stored under,
+//! manifests must parse and everything they reference must be present with the expected size.
+//! Finally, every tag must point to an intact manifest recorded as pushed to its location, which
+//! tags written by earlier versions are not. In repair mode, corrupt objects are quarantined,
+//! dangling tags removed and pushes of tagged manifests recorded. Missing references cannot be
+//! repaired, they are only reported.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use sha2::Digest as Sha2Digest;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, warn};
+
+use super::{
+    storage::{Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage},
+    types::{ContentDescriptor, Manifest},
+    ImageDigest,
+};
+
+/// Size of the buffer used when re-hashing blobs.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FsckOptions {
+    /// Quarantine corrupt objects and remove dangling tags.
+    pub(crate) repair: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct MissingReferences {
+    manifest: ImageDigest,
+    missing: Vec<ImageDigest>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct FsckReport {
+    repair: bool,
+    blobs_checked: usize,
+    manifests_checked: usize,
+    /// Blobs whose contents do not match their digest.
+    corrupt_blobs: Vec<ImageDigest>,
+    /// Manifests whose contents do not match their digest or that cannot be parsed.
+    corrupt_manifests: Vec<ImageDigest>,
+    missing_references: Vec<MissingReferences>,
+    /// Tags pointing to manifests that are missing or corrupt, as `<location>:<tag>`.
+    dangling_tags: Vec<String>,
+    /// Tags pointing to manifests not recorded as pushed to their location, which deleting the
+    /// manifest from another location would remove.
+    unrecorded_tags: Vec<String>,
+}
+
+impl FsckReport {
+    #[cfg(test)]
+    pub(super) fn corrupt_blobs(&self) -> &[ImageDigest] {
+        &self.corrupt_blobs
+    }
+
+    #[cfg(test)]
+    pub(super) fn dangling_tags(&self) -> &[String] {
+        &self.dangling_tags
+    }
+
+    #[cfg(test)]
+    pub(super) fn unrecorded_tags(&self) -> &[String] {
+        &self.unrecorded_tags
+    }
+
+    /// Returns the number of problems found.
+    pub(crate) fn problems(&self) -> usize {
+        self.corrupt_blobs.len()
+            + self.corrupt_manifests.len()
+            + self.missing_references.len()
+            + self.dangling_tags.len()
+            + self.unrecorded_tags.len()
+    }
+
+    pub(crate) fn is_clean(&self) -> bool {
+        self.problems() == 0
+    }
+}
+
+/// Checks the integrity of `storage`, repairing it if requested.
+pub(crate) async fn check_storage(
+    storage: &dyn RegistryStorage,
+    options: FsckOptions,
+) -> Result<FsckReport, Error> {
+    let mut report = FsckReport {
+        repair: options.repair,
+        ..Default::default()
+    };
+
+    // Blobs go first, so manifests referencing corrupt blobs are reported as well.
+    let mut blobs = HashMap::new();
+    for blob in storage.list_blobs().await? {
+        report.blobs_checked += 1;
+
+        let Some(reader) = storage.get_blob_reader(blob.digest(), 0).await? else {
+            // Removed in the meantime, e.g. by garbage collection.
+            continue;
+        };
+
+        if hash_reader(reader).await? == blob.digest() {
+            blobs.insert(blob.digest(), blob.size());
+            continue;
+        }
+
+        warn!(digest = %blob.digest(), "blob is corrupt");
+        if options.repair {
+            storage.quarantine_blob(blob.digest()).await?;
+        }
+        report.corrupt_blobs.push(ImageDigest::new(blob.digest()));
+    }
+
+    let mut manifests = HashMap::new();
+    let mut parsed = Vec::new();
+    for metadata in storage.list_manifests().await? {
+        report.manifests_checked += 1;
+        let digest = metadata.digest();
+
+        // The location is irrelevant when retrieving by digest.
+        let reference = ManifestReference::new(
+            ImageLocation::new(String::new(), String::new()),
+            Reference::new_digest(digest),
+        );
+        let Some(raw) = storage.get_manifest(&reference).await? else {
+            continue;
+        };
+
+        match Manifest::from_slice(&raw) {
+            Ok(manifest) if Digest::from_contents(&raw) == digest => {
+                manifests.insert(digest, raw.len() as u64);
+                parsed.push((digest, manifest));
+            }
+            _ => {
+                warn!(%digest, "manifest is corrupt");
+                if options.repair {
+                    storage.quarantine_manifest(digest).await?;
+                }
+                report.corrupt_manifests.push(ImageDigest::new(digest));
+            }
+        }
+    }
+
+    let mut children = HashMap::new();
+    for (digest, manifest) in parsed {
+        let missing = match manifest {
+            Manifest::Image(ref image) => unresolved(image.blob_descriptors(), &blobs),
+            Manifest::Index(ref index) => {
+                children.insert(
+                    digest,
+                    index
+                        .manifests()
+                        .iter()
+                        .filter_map(ContentDescriptor::digest)
+                        .collect::<Vec<_>>(),
+                );
+                unresolved(index.manifests(), &manifests)
+            }
+        };
+
+        if !missing.is_empty() {
+            warn!(%digest, missing = missing.len(), "manifest has missing references");
+            report.missing_references.push(MissingReferences {
+                manifest: ImageDigest::new(digest),
+                missing,
+            });
+        }
+    }
+
+    let intact: HashSet<_> = manifests.into_keys().collect();
+    for location in storage.list_locations().await? {
+        for tag in storage.list_tags(&location).await?.unwrap_or_default() {
+            let reference = ManifestReference::new(location.clone(), Reference::new_tag(&tag));
+
+            let target = storage.get_manifest_digest(&reference).await?;
+            let Some(digest) = target.filter(|digest| intact.contains(digest)) else {
+                warn!(%reference, "tag is dangling");
+                if options.repair {
+                    storage.delete_manifest(&reference).await?;
+                }
+                report.dangling_tags.push(reference.to_string());
+                continue;
+            };
+
+            if storage.has_revision(&location, digest).await? {
+                continue;
+            }
+
+            warn!(%reference, "tag is not recorded as pushed");
+            if options.repair {
+                // Manifests contained in an index are pulled through the same location.
+                let mut pending = vec![digest];
+                while let Some(manifest) = pending.pop() {
+                    storage.record_revision(&location, manifest).await?;
+                    pending.extend(children.get(&manifest).into_iter().flatten().copied());
+                }
+            }
+            report.unrecorded_tags.push(reference.to_string());
+        }
+    }
+
+    debug!(problems = report.problems(), "storage check finished");
+
+    Ok(report)
+}
+
+/// Returns digests of all descriptors not present in `known` with matching size.
+fn unresolved<'a, I>(descriptors: I, known: &HashMap<Digest, u64>) -> Vec<ImageDigest>
+where
+    I: IntoIterator<Item = &'a ContentDescriptor>,
+{
+    descriptors
+        .into_iter()
+        .filter_map(|descriptor| {
+            let digest = descriptor.digest()?;
+            (known.get(&digest) != Some(&descriptor.size())).then_some(ImageDigest::new(digest))
+        })
+        .collect()
+}
+
+async fn hash_reader<R>(mut reader: R) -> Result<Digest, Error>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = reader.read(&mut buf).await.map_err(Error::Io)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(Digest::new(hasher.finalize().into()))
+}
@@ -0,0 +1,179 @@
+//! Mark-and-sweep garbage collection for registry storage.
+//!
+//! Every manifest reachable through a tag is considered alive, along with the config and layer
+//! blobs it references. Manifests contained in a live image index are alive as well. Everything
+//! else is removed, unless it has been modified within a grace period, which protects blobs of
+//! pushes that are still in progress.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tracing::debug;
+
+use super::{
+    storage::{BlobMetadata, Digest, Error, ManifestReference, Reference, RegistryStorage},
+    types::{ContentDescriptor, Manifest},
+    ImageDigest,
+};
+
+/// Default age a blob or manifest must reach before it is eligible for collection.
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GcOptions {
+    /// Only report what would be removed, without removing anything.
+    pub(crate) dry_run: bool,
+    /// Minimum age of unreferenced objects before they are removed.
+    pub(crate) grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct GcReport {
+    dry_run: bool,
+    manifests_removed: Vec<ImageDigest>,
+    blobs_removed: Vec<ImageDigest>,
+    bytes_freed: u64,
+}
+
+impl GcReport {
+    #[cfg(test)]
+    pub(super) fn blobs_removed(&self) -> &[ImageDigest] {
+        &self.blobs_removed
+    }
+
+    #[cfg(test)]
+    pub(super) fn manifests_removed(&self) -> &[ImageDigest] {
+        &self.manifests_removed
+    }
+
+    pub(crate) fn bytes_freed(&self) -> u64 {
+        self.bytes_freed
+    }
+
+    pub(crate) fn objects_removed(&self) -> usize {
+        self.manifests_removed.len() + self.blobs_removed.len()
+    }
+}
+
+/// Runs a full garbage collection cycle on `storage`.
+pub(crate) async fn collect_garbage(
+    storage: &dyn RegistryStorage,
+    options: GcOptions,
+) -> Result<GcReport, Error> {
+    let (live_manifests, live_blobs) = mark(storage).await?;
+
+    let now = SystemTime::now();
+    let is_expired = |object: &BlobMetadata| {
+        now.duration_since(object.modified())
+            .map(|age| age >= options.grace_period)
+            .unwrap_or(false)
+    };
+
+    let mut report = GcReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+
+    for manifest in storage.list_manifests().await? {
+        if live_manifests.contains(&manifest.digest()) || !is_expired(&manifest) {
+            continue;
+        }
+
+        debug!(digest = %manifest.digest(), dry_run = options.dry_run, "collecting manifest");
+        if !options.dry_run {
+            storage.purge_manifest(manifest.digest()).await?;
+        }
+
+        report.bytes_freed += manifest.size();
+        report
+            .manifests_removed
+            .push(ImageDigest::new(manifest.digest()));
+    }
+
+    for blob in storage.list_blobs().await? {
+        if live_blobs.contains(&blob.digest()) || !is_expired(&blob) {
+            continue;
+        }
+
+        debug!(digest = %blob.digest(), dry_run = options.dry_run, "collecting blob");
+        if !options.dry_run {
+            storage.purge_blob(blob.digest()).await?;
+        }
+
+        report.bytes_freed += blob.size();
+        report.blobs_removed.push(ImageDigest::new(blob.digest()));
+    }
+
+    Ok(report)
+}
+
+/// Determines all manifests and blobs reachable from tags.
+async fn mark(storage: &dyn RegistryStorage) -> Result<(HashSet<Digest>, HashSet<Digest>), Error> {
+    let mut live_manifests = HashSet::new();
+    let mut live_blobs = HashSet::new();
+
+    let mut pending = Vec::new();
+    for location in storage.list_locations().await? {
+        for tag in storage.list_tags(&location).await?.unwrap_or_default() {
+            let manifest_reference =
+                ManifestReference::new(location.clone(), Reference::new_tag(tag));
+
+            // The tag may have been removed since listing it.
+            if let Some(digest) = storage.get_manifest_digest(&manifest_reference).await? {
+                pending.push((location.clone(), digest));
+            }
+        }
+    }
+
+    while let Some((location, digest)) = pending.pop() {
+        if !live_manifests.insert(digest) {
+            continue;
+        }
+
+        // Referrers such as signatures are usually untagged and live as long as their subject.
+        pending.extend(
+            storage
+                .list_referrers(&location, digest)
+                .await?
+                .iter()
+                .filter_map(ContentDescriptor::digest)
+                .map(|referrer| (location.clone(), referrer)),
+        );
+
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let Some(raw) = storage.get_manifest(&manifest_reference).await? else {
+            continue;
+        };
+
+        // A manifest we cannot parse might reference anything, so we must not sweep at all.
+        match Manifest::from_slice(&raw).map_err(Error::InvalidManifest)? {
+            Manifest::Image(image) => live_blobs.extend(
+                image
+                    .blob_descriptors()
+                    .filter_map(ContentDescriptor::digest),
+            ),
+            Manifest::Index(index) => pending.extend(
+                index
+                    .manifests()
+                    .iter()
+                    .filter_map(ContentDescriptor::digest)
+                    .map(|child| (location.clone(), child)),
+            ),
+        }
+    }
+
+    Ok((live_manifests, live_blobs))
+}
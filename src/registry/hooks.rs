@@ -7,6 +7,10 @@ pub(crate) trait RegistryHooks: Send + Sync {
     async fn on_manifest_uploaded(&self, manifest_reference: &ManifestReference) {
         let _ = manifest_reference;
     }
+
+    async fn on_manifest_deleted(&self, manifest_reference: &ManifestReference) {
+        let _ = manifest_reference;
+    }
 }
 
 impl RegistryHooks for () {}
@@ -0,0 +1,92 @@
+//! Glob patterns, matching repository names like `shared/*` or tags like `v*`.
+
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+/// A glob pattern, in which `*` matches any number of characters and `?` exactly one.
+///
+/// Slashes are not treated specially, `team-a.example.com/*` matches every image in the
+/// `team-a.example.com` repository.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Pattern(String);
+
+impl Pattern {
+    #[cfg(test)]
+    pub(crate) fn new<S: Into<String>>(raw: S) -> Self {
+        Self(raw.into())
+    }
+
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+
+        let (mut p, mut t) = (0, 0);
+        // Position of the last `*` seen and the text position it currently extends to.
+        let mut backtrack = None;
+
+        while t < text.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == text[t] => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => {
+                    // Let the last `*` swallow one more character and try again.
+                    let Some((star, extent)) = backtrack else {
+                        return false;
+                    };
+                    backtrack = Some((star, extent + 1));
+                    p = star + 1;
+                    t = extent + 1;
+                }
+            }
+        }
+
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    #[test]
+    fn patterns_match_globs() {
+        for (pattern, text, expected) in [
+            ("prod", "prod", true),
+            ("prod", "production", false),
+            ("*", "", true),
+            ("*", "anything/at/all", true),
+            ("shared/*", "shared/base", true),
+            ("shared/*", "shared-not/base", false),
+            ("team-a.example.com/*", "team-a.example.com/index", true),
+            ("v*.*.*", "v1.2.3", true),
+            ("v*.*.*", "v1.2", false),
+            ("v?", "v1", true),
+            ("v?", "v12", false),
+            ("*-rc*", "1.0-rc1", true),
+            ("*a*b", "xaxxab", true),
+            ("*a*b", "xaxxabc", false),
+        ] {
+            assert_eq!(
+                Pattern::new(pattern).matches(text),
+                expected,
+                "{} ~ {}",
+                pattern,
+                text
+            );
+        }
+    }
+}
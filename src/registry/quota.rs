@@ -0,0 +1,256 @@
+//! Storage usage accounting and quotas per repository.
+//!
+//! A repository uses the bytes of everything reachable from the manifests stored in it, whether
+//! they are tagged or not: the manifests themselves, including the manifests of image indexes and
+//! referrers, plus the config and layer blobs they reference. Blobs uploaded to the repository
+//! count as well, even before a manifest references them. Blobs shared between manifests of the
+//! same repository are counted once.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    storage::{Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage},
+    types::{ContentDescriptor, Manifest},
+};
+
+/// Size limits of repositories, in bytes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Quotas {
+    /// Limit for repositories without a limit of their own.
+    default: Option<u64>,
+    /// Limits by repository name, e.g. `tests/sample`.
+    repositories: HashMap<String, u64>,
+}
+
+impl Quotas {
+    pub(crate) fn new(default: Option<u64>, repositories: HashMap<String, u64>) -> Self {
+        Self {
+            default,
+            repositories,
+        }
+    }
+
+    /// Returns the limit for the given repository, if any.
+    pub(crate) fn limit(&self, location: &ImageLocation) -> Option<u64> {
+        self.repositories
+            .get(&location.to_string())
+            .copied()
+            .or(self.default)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Usage {
+    /// Total size of all referenced manifests and blobs.
+    bytes: u64,
+    manifests: usize,
+    blobs: usize,
+    #[serde(skip)]
+    seen_manifests: HashSet<Digest>,
+    #[serde(skip)]
+    seen_blobs: HashSet<Digest>,
+}
+
+impl Usage {
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Accounts for a manifest, returning the digests of the manifests it contains.
+    fn count_manifest(&mut self, digest: Digest, raw: &[u8]) -> Result<Vec<Digest>, Error> {
+        if !self.seen_manifests.insert(digest) {
+            return Ok(Vec::new());
+        }
+
+        self.manifests += 1;
+        self.bytes += raw.len() as u64;
+
+        match Manifest::from_slice(raw).map_err(Error::InvalidManifest)? {
+            Manifest::Image(image) => {
+                for descriptor in image.blob_descriptors() {
+                    if let Some(blob) = descriptor.digest() {
+                        self.count_blob(blob, descriptor.size());
+                    }
+                }
+
+                Ok(Vec::new())
+            }
+            Manifest::Index(index) => Ok(index
+                .manifests()
+                .iter()
+                .filter_map(ContentDescriptor::digest)
+                .collect()),
+        }
+    }
+
+    /// Accounts for all stored manifests reachable from `pending`, along with their referrers.
+    async fn count_reachable(
+        &mut self,
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+        mut pending: Vec<Digest>,
+    ) -> Result<(), Error> {
+        while let Some(digest) = pending.pop() {
+            if self.seen_manifests.contains(&digest) {
+                continue;
+            }
+
+            let manifest_reference =
+                ManifestReference::new(location.clone(), Reference::new_digest(digest));
+            let Some(raw) = storage.get_manifest(&manifest_reference).await? else {
+                continue;
+            };
+
+            pending.extend(self.count_manifest(digest, &raw)?);
+            pending.extend(
+                storage
+                    .list_referrers(location, digest)
+                    .await?
+                    .iter()
+                    .filter_map(ContentDescriptor::digest),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Accounts for a blob, unless it has been counted already.
+    fn count_blob(&mut self, digest: Digest, size: u64) {
+        if self.seen_blobs.insert(digest) {
+            self.blobs += 1;
+            self.bytes += size;
+        }
+    }
+
+    /// Accounts for a manifest that has not been stored yet, along with everything it contains.
+    pub(crate) async fn count_new_manifest(
+        &mut self,
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+        raw: &[u8],
+    ) -> Result<(), Error> {
+        let children = self.count_manifest(Digest::from_contents(raw), raw)?;
+        self.count_reachable(storage, location, children).await
+    }
+}
+
+/// Measures the storage used by a repository.
+///
+/// Moving a tag does not free anything, the manifest it pointed to stays in the repository until
+/// it is deleted by digest or garbage collected.
+pub(crate) async fn measure_usage(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+) -> Result<Usage, Error> {
+    let mut pending = storage.list_revisions(location).await?;
+    for tag in storage.list_tags(location).await?.unwrap_or_default() {
+        // The tag may have been removed since listing it.
+        let manifest_reference = ManifestReference::new(location.clone(), Reference::new_tag(tag));
+        if let Some(digest) = storage.get_manifest_digest(&manifest_reference).await? {
+            pending.push(digest);
+        }
+    }
+
+    let mut usage = Usage::default();
+    usage.count_reachable(storage, location, pending).await?;
+
+    // Links of blobs that have been collected in the meantime are left behind.
+    for digest in storage.list_linked_blobs(location).await? {
+        if let Some(metadata) = storage.get_blob_metadata(digest).await? {
+            usage.count_blob(digest, metadata.size());
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Bytes written to blob uploads in progress.
+///
+/// Uploads only count towards the usage of their repository once finalized, until then
+/// concurrent uploads into the same repository are counted against each other here. Kept in
+/// memory only, uploads continued after a restart start out with the bytes uploaded so far.
+#[derive(Debug, Default)]
+pub(crate) struct PendingUploads {
+    uploads: Mutex<HashMap<Uuid, (ImageLocation, u64)>>,
+}
+
+impl PendingUploads {
+    /// Accounts for `len` more bytes written to `upload`, which contained `offset` bytes before.
+    ///
+    /// If `location` would use more than `limit` bytes with `stored` bytes used by what is stored
+    /// already, nothing is accounted for and the bytes it would use are returned instead.
+    pub(crate) fn reserve(
+        &self,
+        location: &ImageLocation,
+        upload: Uuid,
+        offset: u64,
+        len: u64,
+        stored: u64,
+        limit: u64,
+    ) -> Result<(), u64> {
+        let mut uploads = self.uploads.lock().expect("lock poisoned");
+        uploads
+            .entry(upload)
+            .or_insert_with(|| (location.clone(), offset));
+
+        let pending: u64 = uploads
+            .values()
+            .filter(|(other, _)| other == location)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        let bytes = stored + pending + len;
+        if bytes > limit {
+            return Err(bytes);
+        }
+
+        uploads
+            .get_mut(&upload)
+            .expect("upload was just inserted")
+            .1 += len;
+        Ok(())
+    }
+
+    /// Stops accounting for an upload, once it has been finalized or discarded.
+    pub(crate) fn remove(&self, upload: Uuid) {
+        self.uploads.lock().expect("lock poisoned").remove(&upload);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RepositoryUsage {
+    name: String,
+    #[serde(flatten)]
+    usage: Usage,
+    quota: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct UsageReport {
+    repositories: Vec<RepositoryUsage>,
+}
+
+/// Measures the usage of every repository with at least one tag.
+pub(crate) async fn usage_report(
+    storage: &dyn RegistryStorage,
+    quotas: &Quotas,
+) -> Result<UsageReport, Error> {
+    let mut locations = storage.list_locations().await?;
+    locations.sort_by_key(ToString::to_string);
+
+    let mut report = UsageReport::default();
+    for location in locations {
+        report.repositories.push(RepositoryUsage {
+            name: location.to_string(),
+            usage: measure_usage(storage, &location).await?,
+            quota: quotas.limit(&location),
+        });
+    }
+
+    Ok(report)
+}
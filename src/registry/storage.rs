@@ -1,23 +1,38 @@
+#[cfg(test)]
+mod conformance;
+pub(crate) mod memory;
+pub(crate) mod s3;
+
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
     fs,
     io::{self, Read},
     path::{Path, PathBuf},
+    pin::Pin,
     str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
 
 use axum::{async_trait, http::StatusCode, response::IntoResponse};
+use hex::FromHex;
 use serde::{Deserialize, Serialize};
 use sha2::Digest as Sha2Digest;
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, warn};
 use uuid::Uuid;
 
-use super::{types::ImageManifest, ImageDigest};
+use super::{
+    types::{ContentDescriptor, ErrorCode, Manifest, OciError, OciErrors},
+    ImageDigest,
+};
 
 const SHA256_LEN: usize = 32;
 
-const BUFFER_SIZE: usize = 1024 * 1024 * 1024; // 1 MiB
+const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
 
 // TODO: Maybe use `ImageDigest` directly?
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize)]
@@ -34,6 +49,13 @@ impl Digest {
 
         Self::new(hasher.finalize().into())
     }
+
+    /// Parses a digest from its bare hex representation, i.e. without `sha256:` prefix.
+    pub(crate) fn from_hex(hex_encoded: &str) -> Option<Self> {
+        <[u8; SHA256_LEN]>::from_hex(hex_encoded)
+            .ok()
+            .map(Self::new)
+    }
 }
 
 impl Display for Digest {
@@ -42,19 +64,60 @@ impl Display for Digest {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct LayerManifest {
-    #[serde(rename = "camelCase")]
-    #[allow(dead_code)] // TODO
-    blob_sum: String,
-}
-
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub(crate) struct ImageLocation {
+    #[serde(deserialize_with = "deserialize_name_component")]
     repository: String,
+    #[serde(deserialize_with = "deserialize_name_component")]
     image: String,
 }
 
+/// Checks a single path component of a repository name.
+///
+/// The spec demands `[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*`. Besides enforcing the spec, this
+/// ensures names are safe to use as path components.
+fn is_valid_name_component(component: &str) -> bool {
+    let is_alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+    component
+        .split(is_alphanumeric)
+        .enumerate()
+        .all(|(idx, separator)| match separator {
+            // Only the very first and last parts may be empty, as there cannot be a separator at
+            // either end. The checks below catch these cases.
+            "" => true,
+            "." | "_" | "__" => idx != 0,
+            dashes => idx != 0 && dashes.chars().all(|c| c == '-'),
+        })
+        && component.starts_with(is_alphanumeric)
+        && component.ends_with(is_alphanumeric)
+}
+
+/// Checks a tag name, which must match `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}`.
+fn is_valid_tag(tag: &str) -> bool {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    tag.len() <= 128
+        && tag.starts_with(is_word)
+        && tag.chars().all(|c| is_word(c) || c == '.' || c == '-')
+}
+
+fn deserialize_name_component<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+
+    if !is_valid_name_component(&raw) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid repository name component: {}",
+            raw
+        )));
+    }
+
+    Ok(raw)
+}
+
 impl Display for ImageLocation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}/{}", self.repository, self.image)
@@ -75,7 +138,6 @@ impl Display for ManifestReference {
 }
 
 impl ManifestReference {
-    #[allow(dead_code)] // TODO
     pub(crate) fn new(location: ImageLocation, reference: Reference) -> Self {
         Self {
             location,
@@ -100,11 +162,18 @@ impl ManifestReference {
 }
 
 impl ImageLocation {
-    #[allow(dead_code)] // TODO
     pub(crate) fn new(repository: String, image: String) -> Self {
         Self { repository, image }
     }
 
+    /// Parses a location given as `<repository>/<image>`, e.g. the source of a blob mount.
+    pub(crate) fn parse(raw: &str) -> Option<Self> {
+        let (repository, image) = raw.split_once('/')?;
+
+        (is_valid_name_component(repository) && is_valid_name_component(image))
+            .then(|| Self::new(repository.to_owned(), image.to_owned()))
+    }
+
     #[inline(always)]
     pub(crate) fn repository(&self) -> &str {
         self.repository.as_ref()
@@ -131,7 +200,11 @@ impl<'de> Deserialize<'de> for Reference {
 
         match ImageDigest::from_str(raw) {
             Ok(digest) => Ok(Self::Digest(digest.digest)),
-            Err(_) => Ok(Self::Tag(raw.to_owned())),
+            Err(_) if is_valid_tag(raw) => Ok(Self::Tag(raw.to_owned())),
+            Err(err) => Err(serde::de::Error::custom(format!(
+                "neither a valid tag nor digest ({}): {}",
+                err, raw
+            ))),
         }
     }
 }
@@ -150,18 +223,16 @@ impl Serialize for Reference {
 
 impl Reference {
     #[inline(always)]
-    #[allow(dead_code)] // TODO
     pub(crate) fn new_tag<S: ToString>(s: S) -> Self {
         Reference::Tag(s.to_string())
     }
 
     #[inline(always)]
-    #[allow(dead_code)] // TODO
     pub(crate) fn new_digest(d: Digest) -> Self {
         Reference::Digest(d)
     }
 
-    fn as_tag(&self) -> Option<&str> {
+    pub(crate) fn as_tag(&self) -> Option<&str> {
         match self {
             Reference::Tag(tag) => Some(tag),
             Reference::Digest(_) => None,
@@ -192,18 +263,65 @@ pub(crate) enum Error {
     BackgroundTaskPanicked(#[source] tokio::task::JoinError),
     #[error("invalid image manifest")]
     InvalidManifest(#[source] serde_json::Error),
-    #[error("cannot store manifest under hash")]
-    NotATag,
+    #[error("manifest references unknown content")]
+    UnknownReferences(Vec<Digest>),
+    #[error("manifest references blobs that have not been uploaded")]
+    UnknownBlobs(Vec<Digest>),
+    #[error("upload is in use by another request")]
+    UploadLocked,
+    #[error("chunk does not continue upload")]
+    UploadOffsetMismatch,
+    #[error("object storage request failed")]
+    ObjectStorage(#[source] s3::S3Error),
 }
 
 impl IntoResponse for Error {
     #[inline]
     fn into_response(self) -> axum::response::Response {
         match self {
-            Error::UploadDoesNotExit => StatusCode::NOT_FOUND.into_response(),
-            Error::InvalidManifest(_) | Error::NotATag => StatusCode::BAD_REQUEST.into_response(),
-            Error::DigestMismatch | Error::Io(_) | Error::BackgroundTaskPanicked(_) => {
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            Error::UploadDoesNotExit => OciError::new(ErrorCode::BlobUploadUnknown).into_response(),
+            Error::UploadLocked => (
+                StatusCode::CONFLICT,
+                OciError::new(ErrorCode::BlobUploadInvalid).with_message(self.to_string()),
+            )
+                .into_response(),
+            Error::UploadOffsetMismatch => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                OciError::new(ErrorCode::BlobUploadInvalid).with_message(self.to_string()),
+            )
+                .into_response(),
+            Error::DigestMismatch => OciError::new(ErrorCode::DigestInvalid).into_response(),
+            Error::InvalidManifest(ref err) => OciError::new(ErrorCode::ManifestInvalid)
+                .with_detail(err.to_string())
+                .into_response(),
+            // Every missing object gets its own error, so clients can list all of them.
+            Error::UnknownBlobs(digests) | Error::UnknownReferences(digests) => OciErrors::new(
+                digests
+                    .into_iter()
+                    .map(|digest| {
+                        OciError::new(ErrorCode::ManifestBlobUnknown)
+                            .with_detail(ImageDigest::new(digest).to_string())
+                    })
+                    .collect(),
+            )
+            .into_response(),
+            Error::Io(ref err) => {
+                error!(%err, "storage io error");
+                OciError::new(ErrorCode::Unknown)
+                    .with_message(self.to_string())
+                    .into_response()
+            }
+            Error::BackgroundTaskPanicked(ref err) => {
+                error!(%err, "storage background task panicked");
+                OciError::new(ErrorCode::Unknown)
+                    .with_message(self.to_string())
+                    .into_response()
+            }
+            Error::ObjectStorage(ref err) => {
+                error!(%err, "object storage request failed");
+                OciError::new(ErrorCode::Unknown)
+                    .with_message(self.to_string())
+                    .into_response()
             }
         }
     }
@@ -211,13 +329,12 @@ impl IntoResponse for Error {
 
 #[derive(Debug)]
 pub(crate) struct BlobMetadata {
-    #[allow(dead_code)] // TODO
     digest: Digest,
     size: u64,
+    modified: SystemTime,
 }
 
 impl BlobMetadata {
-    #[allow(dead_code)] // TODO
     pub(crate) fn digest(&self) -> Digest {
         self.digest
     }
@@ -225,19 +342,329 @@ impl BlobMetadata {
     pub(crate) fn size(&self) -> u64 {
         self.size
     }
+
+    pub(crate) fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+/// A manifest about to be stored, checked the same way regardless of the storage backend.
+pub(crate) struct NewManifest {
+    parsed: Manifest,
+    digest: Digest,
+    size: u64,
+}
+
+impl NewManifest {
+    /// Parses a manifest pushed to `manifest_reference`.
+    ///
+    /// Fails if the manifest is pushed by a digest that does not match its contents.
+    pub(crate) fn parse(
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Self, Error> {
+        let parsed = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
+        let digest = Digest::from_contents(manifest);
+
+        if let Reference::Digest(expected) = manifest_reference.reference() {
+            if *expected != digest {
+                return Err(Error::DigestMismatch);
+            }
+        }
+
+        Ok(NewManifest {
+            parsed,
+            digest,
+            size: manifest.len() as u64,
+        })
+    }
+
+    pub(crate) fn digest(&self) -> Digest {
+        self.digest
+    }
+
+    /// Checks that everything referenced by the manifest is stored in `location`.
+    ///
+    /// Manifests may only be stored once everything they reference is completely uploaded,
+    /// otherwise deployments would fail much later when pulling the image. Objects stored for
+    /// other locations do not count, as that would allow reading them through this one.
+    pub(crate) async fn check_references(
+        &self,
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+    ) -> Result<(), Error> {
+        let mut unknown = Vec::new();
+
+        match self.parsed {
+            Manifest::Image(ref image) => {
+                for descriptor in image.blob_descriptors() {
+                    // Digests using other algorithms cannot be verified and are left to the
+                    // client.
+                    let Some(digest) = descriptor.digest() else {
+                        continue;
+                    };
+
+                    let size = storage
+                        .get_blob_metadata(digest)
+                        .await?
+                        .map(|metadata| metadata.size());
+                    if size != Some(descriptor.size())
+                        || !is_blob_in_location(storage, location, digest).await?
+                    {
+                        unknown.push(digest);
+                    }
+                }
+
+                if !unknown.is_empty() {
+                    return Err(Error::UnknownBlobs(unknown));
+                }
+            }
+            Manifest::Index(ref index) => {
+                for descriptor in index.manifests() {
+                    let Some(digest) = descriptor.digest() else {
+                        continue;
+                    };
+
+                    let manifest_reference =
+                        ManifestReference::new(location.clone(), Reference::new_digest(digest));
+                    let size = storage
+                        .get_manifest(&manifest_reference)
+                        .await?
+                        .map(|raw| raw.len() as u64);
+                    if size != Some(descriptor.size())
+                        || !is_manifest_in_location(storage, location, digest).await?
+                    {
+                        unknown.push(digest);
+                    }
+                }
+
+                if !unknown.is_empty() {
+                    return Err(Error::UnknownReferences(unknown));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the subject of the manifest, along with the descriptor to return for the manifest
+    /// from the referrers API.
+    ///
+    /// Returns `None` if the manifest has no subject and thus does not need to be recorded as a
+    /// referrer.
+    pub(crate) fn referrer(&self) -> Result<Option<(Digest, Vec<u8>)>, Error> {
+        let Some(subject) = self.parsed.subject().and_then(ContentDescriptor::digest) else {
+            return Ok(None);
+        };
+
+        let descriptor = self.parsed.referrer_descriptor(self.digest, self.size);
+        let raw = serde_json::to_vec(&descriptor).map_err(Error::InvalidManifest)?;
+
+        Ok(Some((subject, raw)))
+    }
+}
+
+/// What deleting a manifest by digest removes from a location, regardless of the backend.
+pub(crate) struct ManifestDeletion {
+    /// Tags of the location pointing to the manifest.
+    pub(crate) tags: Vec<String>,
+    /// Subject the manifest is recorded as a referrer of.
+    pub(crate) subject: Option<Digest>,
+}
+
+impl ManifestDeletion {
+    /// Determines what deleting `digest` from `location` removes.
+    ///
+    /// Returns `None` if the manifest does not belong to the location, i.e. it has neither been
+    /// pushed to it nor does any of its tags point to it. Manifests are shared between locations,
+    /// so existing elsewhere does not count.
+    pub(crate) async fn plan(
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Option<Self>, Error> {
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let Some(raw) = storage.get_manifest(&manifest_reference).await? else {
+            return Ok(None);
+        };
+
+        let tags = tags_pointing_to(storage, location, digest).await?;
+        if tags.is_empty() && !storage.has_revision(location, digest).await? {
+            return Ok(None);
+        }
+
+        let subject = Manifest::from_slice(&raw)
+            .ok()
+            .and_then(|manifest| manifest.subject().and_then(ContentDescriptor::digest));
+
+        Ok(Some(ManifestDeletion { tags, subject }))
+    }
+}
+
+/// Lists the tags of a location pointing to the given manifest.
+pub(crate) async fn tags_pointing_to(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+    digest: Digest,
+) -> Result<Vec<String>, Error> {
+    let mut tags = Vec::new();
+    for tag in storage.list_tags(location).await?.unwrap_or_default() {
+        let tag_reference = ManifestReference::new(location.clone(), Reference::new_tag(&tag));
+        if storage.get_manifest_digest(&tag_reference).await? == Some(digest) {
+            tags.push(tag);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Checks whether a manifest may be read by digest through `location`.
+///
+/// Manifests are stored once for all locations, but only belong to those they have been pushed
+/// to or that have a tag pointing to them.
+pub(crate) async fn is_manifest_in_location(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+    digest: Digest,
+) -> Result<bool, Error> {
+    if storage.has_revision(location, digest).await? {
+        return Ok(true);
+    }
+
+    // Tags written before revisions were recorded.
+    Ok(!tags_pointing_to(storage, location, digest)
+        .await?
+        .is_empty())
+}
+
+/// Checks whether a blob may be read or referenced through `location`.
+///
+/// Blobs belong to the locations they have been uploaded or mounted to, as well as to those
+/// with a manifest referencing them. The latter is remembered as a link, which saves reading the
+/// manifests of the location next time.
+pub(crate) async fn is_blob_in_location(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+    digest: Digest,
+) -> Result<bool, Error> {
+    if storage.is_blob_linked(location, digest).await? {
+        return Ok(true);
+    }
+
+    if !is_blob_referenced(storage, location, digest).await? {
+        return Ok(false);
+    }
+
+    storage.link_blob(location, digest).await?;
+    Ok(true)
+}
+
+/// Checks whether a manifest of `location` references the given blob, including the manifests
+/// contained in its image indexes.
+pub(crate) async fn is_blob_referenced(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+    digest: Digest,
+) -> Result<bool, Error> {
+    let mut pending = storage.list_revisions(location).await?;
+    for tag in storage.list_tags(location).await?.unwrap_or_default() {
+        let tag_reference = ManifestReference::new(location.clone(), Reference::new_tag(tag));
+        pending.extend(storage.get_manifest_digest(&tag_reference).await?);
+    }
+
+    let mut seen = HashSet::new();
+    while let Some(manifest) = pending.pop() {
+        if !seen.insert(manifest) {
+            continue;
+        }
+
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(manifest));
+        let Some(raw) = storage.get_manifest(&manifest_reference).await? else {
+            continue;
+        };
+
+        // Corrupt manifests are the business of fsck, they reference nothing we could serve.
+        match Manifest::from_slice(&raw) {
+            Ok(Manifest::Image(image)) => {
+                if image
+                    .blob_descriptors()
+                    .any(|descriptor| descriptor.digest() == Some(digest))
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(Manifest::Index(index)) => pending.extend(
+                index
+                    .manifests()
+                    .iter()
+                    .filter_map(ContentDescriptor::digest),
+            ),
+            Err(_) => {}
+        }
+    }
+
+    Ok(false)
+}
+
+/// Checks whether an image index of `location` contains the given manifest.
+///
+/// Indexes are only accepted if their manifests have been pushed to the same location, so there
+/// is no need to look at the indexes of other locations.
+pub(crate) async fn is_in_location_index(
+    storage: &dyn RegistryStorage,
+    location: &ImageLocation,
+    digest: Digest,
+) -> Result<bool, Error> {
+    let mut manifests = storage.list_revisions(location).await?;
+    for tag in storage.list_tags(location).await?.unwrap_or_default() {
+        let tag_reference = ManifestReference::new(location.clone(), Reference::new_tag(tag));
+        manifests.extend(storage.get_manifest_digest(&tag_reference).await?);
+    }
+
+    for manifest in manifests {
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(manifest));
+        let Some(raw) = storage.get_manifest(&manifest_reference).await? else {
+            continue;
+        };
+
+        // Corrupt manifests are the business of fsck, they cannot have been accepted as index.
+        if let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) {
+            if index
+                .manifests()
+                .iter()
+                .any(|child| child.digest() == Some(digest))
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
 }
 
 #[async_trait]
 pub(crate) trait RegistryStorage: Send + Sync {
     async fn begin_new_upload(&self) -> Result<Uuid, Error>;
 
+    /// Returns a reader for a blob, positioned at `start_at`.
     async fn get_blob_reader(
         &self,
         digest: Digest,
+        start_at: u64,
     ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error>;
 
     async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error>;
 
+    /// Returns the number of bytes uploaded so far.
+    async fn get_upload_progress(&self, upload: Uuid) -> Result<u64, Error>;
+
+    /// Returns a writer for an upload, positioned at `start_at`.
+    ///
+    /// `start_at` must match the current upload progress. Only a single writer may exist per
+    /// upload, the upload is locked until the writer is dropped.
     async fn get_upload_writer(
         &self,
         start_at: u64,
@@ -246,16 +673,106 @@ pub(crate) trait RegistryStorage: Send + Sync {
 
     async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error>;
 
+    /// Cancels an upload, discarding all data uploaded so far.
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error>;
+
+    /// Removes all uploads that have not been written to for longer than `max_idle`.
+    ///
+    /// Returns the expired uploads. Uploads currently being written to are never expired.
+    async fn expire_uploads(&self, max_idle: Duration) -> Result<Vec<Uuid>, Error>;
+
     async fn get_manifest(
         &self,
         manifest_reference: &ManifestReference,
     ) -> Result<Option<Vec<u8>>, Error>;
 
+    /// Resolves a manifest reference to the digest of the manifest.
+    ///
+    /// Returns `None` if the manifest or tag does not exist.
+    async fn get_manifest_digest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Digest>, Error>;
+
     async fn put_manifest(
         &self,
         manifest_reference: &ManifestReference,
         manifest: &[u8],
     ) -> Result<Digest, Error>;
+
+    /// Deletes a manifest.
+    ///
+    /// Deleting by tag removes only the tag. Deleting by digest removes the manifest from the
+    /// location, along with all tags of the location pointing to it. The manifest itself is
+    /// removed once it has not been pushed to any location and no image index of the location
+    /// contains it anymore.
+    ///
+    /// Returns the removed tags, or `None` if the tag did not exist or the manifest does not
+    /// belong to the location.
+    async fn delete_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<String>>, Error>;
+
+    /// Lists descriptors of all manifests in a location whose subject is the given manifest.
+    ///
+    /// Referrers are recorded when they are uploaded, the subject itself need not exist.
+    async fn list_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error>;
+
+    /// Lists the digests of all manifests pushed to a location, whether they are tagged or not.
+    async fn list_revisions(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error>;
+
+    /// Checks whether a manifest has been pushed to a location and is still stored.
+    async fn has_revision(&self, location: &ImageLocation, digest: Digest) -> Result<bool, Error>;
+
+    /// Records that a manifest has been pushed to a location, which `put_manifest` does already.
+    ///
+    /// Only needed for manifests tagged before pushes were recorded.
+    async fn record_revision(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error>;
+
+    /// Records that a blob belongs to a location, e.g. because it has been uploaded there.
+    ///
+    /// Links are kept when the blob is purged, so they only ever grant access to content the
+    /// location had before.
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error>;
+
+    /// Checks whether a blob has been linked to a location, regardless of whether it is stored.
+    async fn is_blob_linked(&self, location: &ImageLocation, digest: Digest)
+        -> Result<bool, Error>;
+
+    /// Lists all blobs linked to a location, regardless of whether they are stored.
+    async fn list_linked_blobs(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error>;
+
+    /// Lists all tags of an image location, sorted lexically.
+    ///
+    /// Returns `None` if the location has no tags, either because it is not known to the storage
+    /// or because all of its tags have been deleted.
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error>;
+
+    /// Lists all image locations that have at least one tag.
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error>;
+
+    /// Lists all stored blobs.
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error>;
+
+    /// Lists all stored manifests, regardless of whether they are tagged.
+    async fn list_manifests(&self) -> Result<Vec<BlobMetadata>, Error>;
+
+    /// Removes a blob, regardless of whether it is still referenced.
+    async fn purge_blob(&self, digest: Digest) -> Result<(), Error>;
+
+    /// Removes a manifest, regardless of whether it is still referenced.
+    async fn purge_manifest(&self, digest: Digest) -> Result<(), Error>;
+
+    /// Moves a blob out of the way, keeping it around for later inspection.
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error>;
+
+    /// Moves a manifest out of the way, keeping it around for later inspection.
+    async fn quarantine_manifest(&self, digest: Digest) -> Result<(), Error>;
 }
 
 #[derive(Debug, Error)]
@@ -272,6 +789,12 @@ pub(crate) enum FilesystemStorageError {
         #[source]
         err: io::Error,
     },
+    #[error("could not remove leftover {}", path.display())]
+    FailedToRecover {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
 }
 
 #[derive(Debug)]
@@ -280,7 +803,137 @@ pub(crate) struct FilesystemStorage {
     blobs: PathBuf,
     manifests: PathBuf,
     tags: PathBuf,
+    revisions: PathBuf,
+    /// Locations each manifest has been pushed to, the reverse of `revisions`.
+    pushes: PathBuf,
+    links: PathBuf,
+    referrers: PathBuf,
+    tmp: PathBuf,
+    quarantine: PathBuf,
     rel_manifest_to_blobs: PathBuf,
+    hashers: Arc<Mutex<HashMap<Uuid, UploadHasher>>>,
+    locked_uploads: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+/// Exclusive access to an upload, released when dropped.
+#[derive(Debug)]
+struct UploadLock {
+    upload: Uuid,
+    locked_uploads: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl UploadLock {
+    /// Locks an upload, failing if it is already locked.
+    fn acquire(locked_uploads: &Arc<Mutex<HashSet<Uuid>>>, upload: Uuid) -> Result<Self, Error> {
+        if !locked_uploads.lock().expect("lock poisoned").insert(upload) {
+            return Err(Error::UploadLocked);
+        }
+
+        Ok(UploadLock {
+            upload,
+            locked_uploads: locked_uploads.clone(),
+        })
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.locked_uploads
+            .lock()
+            .expect("lock poisoned")
+            .remove(&self.upload);
+    }
+}
+
+/// Hashing state of an upload, covering its first `len` bytes.
+///
+/// Kept in memory only, as `sha2` does not allow exporting its state. Whenever it is missing,
+/// e.g. after a restart, it is rebuilt from the partial upload on disk. This reads everything
+/// uploaded so far once, which is what hashing while writing avoids otherwise.
+#[derive(Clone, Debug)]
+struct UploadHasher {
+    len: u64,
+    hasher: sha2::Sha256,
+}
+
+impl UploadHasher {
+    /// Hashes the first `len` bytes of the file at `path`, on a blocking thread.
+    async fn from_file(path: PathBuf, len: u64) -> Result<Self, Error> {
+        tokio::task::spawn_blocking(move || {
+            let mut src = fs::File::open(path).map_err(Error::Io)?.take(len);
+
+            // Uses `vec!` instead of `Box`, as initializing the latter blows the stack:
+            let mut buf = vec![0; BUFFER_SIZE];
+            let mut hasher = sha2::Sha256::new();
+            let mut hashed = 0;
+
+            loop {
+                let read = src.read(buf.as_mut()).map_err(Error::Io)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+                hashed += read as u64;
+            }
+
+            Ok(UploadHasher {
+                len: hashed,
+                hasher,
+            })
+        })
+        .await
+        .map_err(Error::BackgroundTaskPanicked)?
+    }
+}
+
+/// Upload writer that hashes all data as it is being written.
+///
+/// The hashing state is handed back to the storage once the writer is dropped.
+struct HashingWriter {
+    file: tokio::fs::File,
+    upload: Uuid,
+    state: Option<UploadHasher>,
+    hashers: Arc<Mutex<HashMap<Uuid, UploadHasher>>>,
+    // Dropped after the hashing state has been handed back.
+    _lock: UploadLock,
+}
+
+impl AsyncWrite for HashingWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.file).poll_write(cx, buf);
+
+        // Only data that has actually been accepted by the file is hashed.
+        if let (Poll::Ready(Ok(written)), Some(state)) = (&poll, this.state.as_mut()) {
+            state.hasher.update(&buf[..*written]);
+            state.len += *written as u64;
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+impl Drop for HashingWriter {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.hashers
+                .lock()
+                .expect("lock poisoned")
+                .insert(self.upload, state);
+        }
+    }
 }
 
 impl FilesystemStorage {
@@ -297,9 +950,26 @@ impl FilesystemStorage {
         let blobs = root.join("blobs");
         let manifests = root.join("manifests");
         let tags = root.join("tags");
+        let revisions = root.join("revisions");
+        let pushes = root.join("pushes");
+        let links = root.join("links");
+        let referrers = root.join("referrers");
+        let tmp = root.join("tmp");
+        let quarantine = root.join("quarantine");
         let rel_manifest_to_blobs = PathBuf::from("../../../manifests");
 
-        for dir in [&uploads, &blobs, &manifests, &tags] {
+        for dir in [
+            &uploads,
+            &blobs,
+            &manifests,
+            &tags,
+            &revisions,
+            &pushes,
+            &links,
+            &referrers,
+            &tmp,
+            &quarantine,
+        ] {
             if !dir.exists() {
                 fs::create_dir(dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
                     path: dir.to_owned(),
@@ -313,9 +983,81 @@ impl FilesystemStorage {
             blobs,
             manifests,
             tags,
+            revisions,
+            pushes,
+            links,
+            referrers,
+            tmp,
+            quarantine,
             rel_manifest_to_blobs,
+            hashers: Default::default(),
+            locked_uploads: Default::default(),
         })
     }
+
+    /// Removes leftovers of writes interrupted by a crash.
+    ///
+    /// Since all writes are atomic renames, these are temporary files that never made it into
+    /// place and temporary tag symlinks, which are the only non-directories inside `tags/`.
+    ///
+    /// Must only be called on startup, as it would interfere with writes in progress.
+    pub(crate) fn recover(&self) -> Result<(), FilesystemStorageError> {
+        let mut removed = 0;
+
+        for dir in [&self.tmp, &self.tags] {
+            let entries =
+                fs::read_dir(dir).map_err(|err| FilesystemStorageError::FailedToRecover {
+                    path: dir.to_owned(),
+                    err,
+                })?;
+
+            for entry in entries {
+                let path = entry
+                    .map_err(|err| FilesystemStorageError::FailedToRecover {
+                        path: dir.to_owned(),
+                        err,
+                    })?
+                    .path();
+
+                // `symlink_metadata` does not follow (possibly dangling) symlinks.
+                if path
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.is_dir())
+                {
+                    continue;
+                }
+
+                fs::remove_file(&path)
+                    .map_err(|err| FilesystemStorageError::FailedToRecover { path, err })?;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            warn!(removed, "removed leftovers of interrupted writes");
+        }
+
+        Ok(())
+    }
+    /// Locks an upload, failing if it is already locked.
+    fn lock_upload(&self, upload: Uuid) -> Result<UploadLock, Error> {
+        UploadLock::acquire(&self.locked_uploads, upload)
+    }
+
+    /// Removes an upload along with its hashing state. The upload must be locked.
+    async fn remove_upload(&self, lock: &UploadLock) -> Result<(), Error> {
+        self.hashers
+            .lock()
+            .expect("lock poisoned")
+            .remove(&lock.upload);
+
+        match tokio::fs::remove_file(self.upload_path(lock.upload)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::UploadDoesNotExit),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
     fn blob_path(&self, digest: Digest) -> PathBuf {
         self.blobs.join(format!("{}", digest))
     }
@@ -332,15 +1074,98 @@ impl FilesystemStorage {
     }
 
     fn tag_path(&self, location: &ImageLocation, tag: &str) -> PathBuf {
-        self.tags
+        self.tags_dir(location).join(tag)
+    }
+
+    fn tags_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.tags.join(location.repository()).join(location.image())
+    }
+
+    fn revisions_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.revisions
+            .join(location.repository())
+            .join(location.image())
+    }
+
+    fn revision_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        self.revisions_dir(location).join(format!("{}", digest))
+    }
+
+    fn pushes_dir(&self, digest: Digest) -> PathBuf {
+        self.pushes.join(format!("{}", digest))
+    }
+
+    fn push_path(&self, digest: Digest, location: &ImageLocation) -> PathBuf {
+        self.pushes_dir(digest)
             .join(location.repository())
             .join(location.image())
-            .join(tag)
+    }
+
+    fn links_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.links
+            .join(location.repository())
+            .join(location.image())
+    }
+
+    fn link_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        self.links_dir(location).join(format!("{}", digest))
+    }
+
+    fn referrers_dir(&self, location: &ImageLocation, subject: Digest) -> PathBuf {
+        self.referrers
+            .join(location.repository())
+            .join(location.image())
+            .join(format!("{}", subject))
     }
 
     fn temp_tag_path(&self) -> PathBuf {
         self.tags.join(Uuid::new_v4().to_string())
     }
+
+    fn temp_path(&self) -> PathBuf {
+        self.tmp.join(Uuid::new_v4().to_string())
+    }
+
+    /// Writes a file such that it is either completely present or absent after a crash.
+    async fn write_atomically(&self, dest: &Path, contents: &[u8]) -> Result<(), Error> {
+        replace_durably(&self.temp_path(), dest, contents).await
+    }
+
+    /// Resolves a tag to the digest of the manifest it points to.
+    async fn read_tag(&self, location: &ImageLocation, tag: &str) -> Result<Option<Digest>, Error> {
+        match tokio::fs::read_link(self.tag_path(location, tag)).await {
+            Ok(target) => Ok(target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(Digest::from_hex)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    /// Lists all locations a manifest has been pushed to.
+    async fn pushed_to(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        let dir = self.pushes_dir(digest);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut locations = Vec::new();
+
+        // Layout is `pushes/<digest>/<repository>/<image>`.
+        for repository in read_subdirs(&dir).await? {
+            let mut entries = tokio::fs::read_dir(dir.join(&repository))
+                .await
+                .map_err(Error::Io)?;
+            while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+                if let Ok(image) = entry.file_name().into_string() {
+                    locations.push(ImageLocation::new(repository.clone(), image));
+                }
+            }
+        }
+
+        Ok(locations)
+    }
 }
 
 #[async_trait]
@@ -367,12 +1192,14 @@ impl RegistryStorage for FilesystemStorage {
         Ok(Some(BlobMetadata {
             digest,
             size: metadata.len(),
+            modified: metadata.modified().map_err(Error::Io)?,
         }))
     }
 
     async fn get_blob_reader(
         &self,
         digest: Digest,
+        start_at: u64,
     ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
         let blob_path = self.blob_path(digest);
 
@@ -380,24 +1207,52 @@ impl RegistryStorage for FilesystemStorage {
             return Ok(None);
         }
 
-        let reader = tokio::fs::File::open(blob_path).await.map_err(Error::Io)?;
+        let mut reader = tokio::fs::File::open(blob_path).await.map_err(Error::Io)?;
+        reader
+            .seek(io::SeekFrom::Start(start_at))
+            .await
+            .map_err(Error::Io)?;
 
         Ok(Some(Box::new(reader)))
     }
 
+    async fn get_upload_progress(&self, upload: Uuid) -> Result<u64, Error> {
+        match tokio::fs::metadata(self.upload_path(upload)).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::UploadDoesNotExit),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
     async fn get_upload_writer(
         &self,
         start_at: u64,
         upload: Uuid,
     ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
         let location = self.upload_path(upload);
+        let lock = self.lock_upload(upload)?;
 
-        if !location.exists() {
-            return Err(Error::UploadDoesNotExit);
+        // Checked while holding the lock, another writer might just have finished.
+        if self.get_upload_progress(upload).await? != start_at {
+            return Err(Error::UploadOffsetMismatch);
         }
 
+        // Resuming right where the last writer stopped is the common case, otherwise the hash of
+        // everything before `start_at` needs to be recalculated.
+        let existing = self
+            .hashers
+            .lock()
+            .expect("lock poisoned")
+            .remove(&upload)
+            .filter(|state| state.len == start_at);
+        let state = match existing {
+            Some(state) => state,
+            None => UploadHasher::from_file(location.clone(), start_at).await?,
+        };
+
+        // Not opened in append mode, as that would ignore the position we seek to.
         let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
+            .write(true)
             .truncate(false)
             .open(location)
             .await
@@ -407,58 +1262,111 @@ impl RegistryStorage for FilesystemStorage {
             .await
             .map_err(Error::Io)?;
 
-        Ok(Box::new(file))
+        Ok(Box::new(HashingWriter {
+            file,
+            upload,
+            state: Some(state),
+            hashers: self.hashers.clone(),
+            _lock: lock,
+        }))
     }
 
     async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
-        // We are to validate the uploaded partial, then move it into the proper store.
-        // TODO: Lock in place so that the hash cannot be corrupted/attacked.
-
+        // We are to validate the uploaded partial, then move it into the proper store. Locking
+        // ensures nothing is written while we do so.
         let upload_path = self.upload_path(upload);
+        let _lock = self.lock_upload(upload)?;
+        let size = self.get_upload_progress(upload).await?;
+
+        // The hash has been calculated while writing. It is only missing if the upload has not
+        // been written to since a restart, in which case we need to read it back once.
+        let existing = self
+            .hashers
+            .lock()
+            .expect("lock poisoned")
+            .remove(&upload)
+            .filter(|state| state.len == size);
+        let state = match existing {
+            Some(state) => state,
+            None => UploadHasher::from_file(upload_path.clone(), size).await?,
+        };
 
-        if !upload_path.exists() {
-            return Err(Error::UploadDoesNotExit);
-        }
-
-        // We offload hashing to a blocking thread.
-        let actual = {
-            let upload_path = upload_path.clone();
-            tokio::task::spawn_blocking::<_, Result<Digest, Error>>(move || {
-                let mut src = fs::File::open(upload_path).map_err(Error::Io)?;
-
-                // Uses `vec!` instead of `Box`, as initializing the latter blows the stack:
-                let mut buf = vec![0; BUFFER_SIZE];
-                let mut hasher = sha2::Sha256::new();
-
-                loop {
-                    let read = src.read(buf.as_mut()).map_err(Error::Io)?;
-                    if read == 0 {
-                        break;
-                    }
-                    hasher.update(&buf[..read]);
-                }
-
-                let actual = hasher.finalize();
-                Ok(Digest::new(actual.into()))
-            })
-        }
-        .await
-        .map_err(Error::BackgroundTaskPanicked)??;
-
+        let actual = Digest::new(state.hasher.clone().finalize().into());
         if actual != digest {
+            // Keep the state around, the client might still continue the upload.
+            self.hashers
+                .lock()
+                .expect("lock poisoned")
+                .insert(upload, state);
             return Err(Error::DigestMismatch);
         }
 
-        // The uploaded file matches, we can rename it now.
-        let dest = self.blob_path(digest);
-        tokio::fs::rename(upload_path, dest)
+        // The uploaded file matches, we can rename it now. Writers do not sync, so we make sure
+        // the data has hit the disk before the blob becomes visible.
+        tokio::fs::File::open(&upload_path)
+            .await
+            .map_err(Error::Io)?
+            .sync_all()
             .await
             .map_err(Error::Io)?;
 
+        let dest = self.blob_path(digest);
+        rename_durably(&upload_path, &dest).await?;
+
         // All good.
         Ok(())
     }
 
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        let lock = self.lock_upload(upload)?;
+        self.remove_upload(&lock).await
+    }
+
+    async fn expire_uploads(&self, max_idle: Duration) -> Result<Vec<Uuid>, Error> {
+        let mut entries = tokio::fs::read_dir(&self.uploads)
+            .await
+            .map_err(Error::Io)?;
+        let now = SystemTime::now();
+
+        let mut expired = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let Some(upload) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".partial"))
+                .and_then(|uuid| Uuid::parse_str(uuid).ok())
+            else {
+                continue;
+            };
+
+            // Every write touches the partial file, so its modification time tells us when the
+            // upload was last active.
+            let modified = entry
+                .metadata()
+                .await
+                .map_err(Error::Io)?
+                .modified()
+                .map_err(Error::Io)?;
+            if now.duration_since(modified).unwrap_or_default() <= max_idle {
+                continue;
+            }
+
+            // Uploads that are in use are anything but idle.
+            let Ok(lock) = self.lock_upload(upload) else {
+                continue;
+            };
+
+            match self.remove_upload(&lock).await {
+                Ok(()) => expired.push(upload),
+                // Finalized or cancelled in the meantime.
+                Err(Error::UploadDoesNotExit) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(expired)
+    }
+
     async fn get_manifest(
         &self,
         manifest_reference: &ManifestReference,
@@ -475,26 +1383,48 @@ impl RegistryStorage for FilesystemStorage {
         }
     }
 
+    async fn get_manifest_digest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Digest>, Error> {
+        match manifest_reference.reference() {
+            // Tags are symlinks named after the manifest, no need to hash anything.
+            Reference::Tag(ref tag) => self.read_tag(manifest_reference.location(), tag).await,
+            Reference::Digest(digest) => {
+                Ok(Some(*digest).filter(|digest| self.manifest_path(*digest).exists()))
+            }
+        }
+    }
+
     async fn put_manifest(
         &self,
         manifest_reference: &ManifestReference,
         manifest: &[u8],
     ) -> Result<Digest, Error> {
-        // TODO: Validate all blobs are completely uploaded.
-        let _manifest: ImageManifest =
-            serde_json::from_slice(manifest).map_err(Error::InvalidManifest)?;
+        let new = NewManifest::parse(manifest_reference, manifest)?;
+        new.check_references(self, manifest_reference.location())
+            .await?;
 
-        let digest = Digest::from_contents(manifest);
+        let digest = new.digest();
         let dest = self.manifest_path(digest);
-        tokio::fs::write(dest, &manifest).await.map_err(Error::Io)?;
+        self.write_atomically(&dest, manifest).await?;
 
-        let tag = self.tag_path(
-            manifest_reference.location(),
-            manifest_reference
-                .reference()
-                .as_tag()
-                .ok_or(Error::NotATag)?,
-        );
+        self.record_revision(manifest_reference.location(), digest)
+            .await?;
+
+        if let Some((subject, raw)) = new.referrer()? {
+            let dir = self.referrers_dir(manifest_reference.location(), subject);
+            tokio::fs::create_dir_all(&dir).await.map_err(Error::Io)?;
+            self.write_atomically(&dir.join(format!("{}", digest)), &raw)
+                .await?;
+        }
+
+        // Manifests pushed by digest, e.g. those contained in an index, are not tagged.
+        let Some(tag) = manifest_reference.reference().as_tag() else {
+            return Ok(digest);
+        };
+
+        let tag = self.tag_path(manifest_reference.location(), tag);
 
         let tag_parent = tag.parent().expect("should have parent");
 
@@ -509,8 +1439,314 @@ impl RegistryStorage for FilesystemStorage {
         tokio::fs::symlink(self.blob_rel_path(digest), &tmp_tag)
             .await
             .map_err(Error::Io)?;
-        tokio::fs::rename(tmp_tag, tag).await.map_err(Error::Io)?;
+        rename_durably(&tmp_tag, &tag).await?;
 
         Ok(digest)
     }
+
+    async fn delete_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<String>>, Error> {
+        let location = manifest_reference.location();
+
+        match manifest_reference.reference() {
+            Reference::Tag(ref tag) => Ok(remove_durably(&self.tag_path(location, tag))
+                .await?
+                .then(|| vec![tag.clone()])),
+            Reference::Digest(digest) => {
+                let Some(deletion) = ManifestDeletion::plan(self, location, *digest).await? else {
+                    return Ok(None);
+                };
+
+                for tag in &deletion.tags {
+                    remove_durably(&self.tag_path(location, tag)).await?;
+                }
+                remove_durably(&self.revision_path(location, *digest)).await?;
+                remove_durably(&self.push_path(*digest, location)).await?;
+                if let Some(subject) = deletion.subject {
+                    let record = self
+                        .referrers_dir(location, subject)
+                        .join(format!("{}", digest));
+                    remove_durably(&record).await?;
+                }
+
+                // Manifests are shared between locations, only remove if no longer in use.
+                if self.pushed_to(*digest).await?.is_empty()
+                    && !is_in_location_index(self, location, *digest).await?
+                {
+                    remove_durably(&self.manifest_path(*digest)).await?;
+                }
+
+                Ok(Some(deletion.tags))
+            }
+        }
+    }
+
+    async fn list_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.referrers_dir(location, subject)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut referrers = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex) else {
+                continue;
+            };
+
+            // Referrers whose manifest has been deleted or collected are skipped.
+            if !self.manifest_path(digest).exists() {
+                continue;
+            }
+
+            let raw = tokio::fs::read(entry.path()).await.map_err(Error::Io)?;
+            referrers.push(serde_json::from_slice(&raw).map_err(Error::InvalidManifest)?);
+        }
+
+        Ok(referrers)
+    }
+
+    async fn list_revisions(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.revisions_dir(location)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut revisions = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex) else {
+                continue;
+            };
+
+            // Quarantined manifests leave their records behind.
+            if self.manifest_path(digest).exists() {
+                revisions.push(digest);
+            }
+        }
+
+        Ok(revisions)
+    }
+
+    async fn has_revision(&self, location: &ImageLocation, digest: Digest) -> Result<bool, Error> {
+        Ok(self.revision_path(location, digest).exists() && self.manifest_path(digest).exists())
+    }
+
+    async fn record_revision(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        // The reverse record goes first, a leftover one only keeps the manifest around.
+        let push = self.push_path(digest, location);
+        tokio::fs::create_dir_all(push.parent().expect("push record should have parent"))
+            .await
+            .map_err(Error::Io)?;
+        self.write_atomically(&push, &[]).await?;
+
+        tokio::fs::create_dir_all(self.revisions_dir(location))
+            .await
+            .map_err(Error::Io)?;
+        self.write_atomically(&self.revision_path(location, digest), &[])
+            .await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        tokio::fs::create_dir_all(self.links_dir(location))
+            .await
+            .map_err(Error::Io)?;
+        self.write_atomically(&self.link_path(location, digest), &[])
+            .await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        Ok(self.link_path(location, digest).exists())
+    }
+
+    async fn list_linked_blobs(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.links_dir(location)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut links = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            if let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex) {
+                links.push(digest);
+            }
+        }
+
+        Ok(links)
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.tags_dir(location)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut tags = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            // Tags are always valid UTF-8, anything else was not created by us.
+            if let Ok(tag) = entry.file_name().into_string() {
+                tags.push(tag);
+            }
+        }
+
+        // Deleting the last tag leaves an empty directory behind, which is not a location.
+        if tags.is_empty() {
+            return Ok(None);
+        }
+
+        tags.sort();
+
+        Ok(Some(tags))
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        let mut locations = Vec::new();
+
+        // Layout is `tags/<repository>/<image>/<tag>`. Temporary tags live directly inside
+        // `tags/` and are skipped, as they are not directories.
+        for repository in read_subdirs(&self.tags).await? {
+            for image in read_subdirs(&self.tags.join(&repository)).await? {
+                let location = ImageLocation::new(repository.clone(), image);
+
+                if self.list_tags(&location).await?.is_some() {
+                    locations.push(location);
+                }
+            }
+        }
+
+        Ok(locations)
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        read_objects(&self.blobs).await
+    }
+
+    async fn list_manifests(&self) -> Result<Vec<BlobMetadata>, Error> {
+        read_objects(&self.manifests).await
+    }
+
+    async fn purge_blob(&self, digest: Digest) -> Result<(), Error> {
+        remove_durably(&self.blob_path(digest)).await?;
+        Ok(())
+    }
+
+    async fn purge_manifest(&self, digest: Digest) -> Result<(), Error> {
+        for location in self.pushed_to(digest).await? {
+            remove_durably(&self.revision_path(&location, digest)).await?;
+        }
+
+        let pushes = self.pushes_dir(digest);
+        match tokio::fs::remove_dir_all(&pushes).await {
+            Ok(()) => sync_parent(&pushes).await?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        remove_durably(&self.manifest_path(digest)).await?;
+        Ok(())
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        let dest = self.quarantine.join(format!("blob-{}", digest));
+        rename_durably(&self.blob_path(digest), &dest).await
+    }
+
+    async fn quarantine_manifest(&self, digest: Digest) -> Result<(), Error> {
+        let dest = self.quarantine.join(format!("manifest-{}", digest));
+        rename_durably(&self.manifest_path(digest), &dest).await
+    }
+}
+
+/// Writes `contents` to `tmp` and renames it to `dest`, which is either completely present or
+/// absent after a crash.
+pub(crate) async fn replace_durably(tmp: &Path, dest: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut file = tokio::fs::File::create(tmp).await.map_err(Error::Io)?;
+    file.write_all(contents).await.map_err(Error::Io)?;
+    file.sync_all().await.map_err(Error::Io)?;
+    drop(file);
+
+    rename_durably(tmp, dest).await
+}
+
+/// Renames a file and syncs the destination directory, making the rename itself durable.
+async fn rename_durably(src: &Path, dest: &Path) -> Result<(), Error> {
+    tokio::fs::rename(src, dest).await.map_err(Error::Io)?;
+    sync_parent(dest).await
+}
+
+/// Removes a file and syncs its directory, making the removal itself durable.
+///
+/// Returns whether the file existed.
+async fn remove_durably(path: &Path) -> Result<bool, Error> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::Io(e)),
+    }
+
+    sync_parent(path).await?;
+    Ok(true)
+}
+
+/// Syncs the directory containing `path`, which persists entries added to or removed from it.
+async fn sync_parent(path: &Path) -> Result<(), Error> {
+    let parent = path.parent().expect("path should have parent");
+    tokio::fs::File::open(parent)
+        .await
+        .map_err(Error::Io)?
+        .sync_all()
+        .await
+        .map_err(Error::Io)
+}
+
+/// Reads metadata of all content addressed objects stored inside `dir`.
+async fn read_objects(dir: &Path) -> Result<Vec<BlobMetadata>, Error> {
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(Error::Io)?;
+
+    let mut objects = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+        // Skip anything that is not named after a digest.
+        let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex) else {
+            continue;
+        };
+
+        let metadata = entry.metadata().await.map_err(Error::Io)?;
+        objects.push(BlobMetadata {
+            digest,
+            size: metadata.len(),
+            modified: metadata.modified().map_err(Error::Io)?,
+        });
+    }
+
+    Ok(objects)
+}
+
+/// Returns the names of all subdirectories of `dir`.
+async fn read_subdirs(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(Error::Io)?;
+
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+        if !entry.file_type().await.map_err(Error::Io)?.is_dir() {
+            continue;
+        }
+
+        if let Ok(name) = entry.file_name().into_string() {
+            names.push(name);
+        }
+    }
+
+    Ok(names)
 }
@@ -0,0 +1,738 @@
+//! Conformance tests run against every storage backend.
+//!
+//! Each case is an ordinary async function taking a freshly opened storage. The
+//! `conformance_tests!` macro turns all of them into tests for a backend.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::{Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage};
+
+const RAW_IMAGE: &[u8] = include_bytes!(
+    "../../../fixtures/596a7d877b33569d199046aaf293ecf45026445be36de1818d50b4f1850762ad"
+);
+const RAW_CONFIG: &[u8] = include_bytes!(
+    "../../../fixtures/eda5434ef81cd8062f87188f61cc45f75e89230a7d231c39b8ca6272ccaa20f0"
+);
+const RAW_MANIFEST: &[u8] = include_bytes!(
+    "../../../fixtures/7afbb9dda380e16481f74de3029d9f94fcb7fc9fd5eb379086ee063d5406f08e"
+);
+
+macro_rules! conformance_tests {
+    ($open:ident) => {
+        conformance_tests!(
+            $open:
+            uploads_are_verified_and_stored,
+            uploads_are_locked_while_written,
+            uploads_can_be_cancelled_and_expire,
+            manifests_require_known_references,
+            tags_point_to_manifests,
+            deleting_by_digest_removes_tags,
+            deleting_by_digest_is_scoped_to_location,
+            referrers_are_recorded,
+            objects_can_be_purged_and_quarantined,
+        );
+    };
+    ($open:ident: $($case:ident),* $(,)?) => {
+        $(
+            #[tokio::test]
+            async fn $case() {
+                let (_guard, storage) = $open().await;
+                super::$case(storage.as_ref()).await;
+            }
+        )*
+    };
+}
+
+mod filesystem {
+    use tempdir::TempDir;
+
+    use super::super::{FilesystemStorage, RegistryStorage};
+
+    async fn open() -> (TempDir, Box<dyn RegistryStorage>) {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let storage = FilesystemStorage::new(tmp.as_ref()).expect("could not open storage");
+        (tmp, Box::new(storage))
+    }
+
+    conformance_tests!(open);
+}
+
+mod memory {
+    use super::super::{memory::MemoryStorage, RegistryStorage};
+
+    async fn open() -> ((), Box<dyn RegistryStorage>) {
+        ((), Box::new(MemoryStorage::new()))
+    }
+
+    conformance_tests!(open);
+}
+
+mod s3 {
+    use super::super::{
+        s3::{stand_in, S3Storage},
+        RegistryStorage,
+    };
+
+    async fn open() -> ((), Box<dyn RegistryStorage>) {
+        ((), Box::new(S3Storage::new(stand_in::spawn().await)))
+    }
+
+    conformance_tests!(open);
+}
+
+fn sample_location() -> ImageLocation {
+    ImageLocation::new("tests".to_owned(), "sample".to_owned())
+}
+
+fn tag_reference(location: &ImageLocation, tag: &str) -> ManifestReference {
+    ManifestReference::new(location.clone(), Reference::new_tag(tag))
+}
+
+fn digest_reference(location: &ImageLocation, digest: Digest) -> ManifestReference {
+    ManifestReference::new(location.clone(), Reference::new_digest(digest))
+}
+
+async fn write_upload(storage: &dyn RegistryStorage, upload: Uuid, start_at: u64, data: &[u8]) {
+    let mut writer = storage
+        .get_upload_writer(start_at, upload)
+        .await
+        .expect("could not create upload writer");
+    writer.write_all(data).await.expect("failed to write");
+    writer.shutdown().await.expect("failed to close writer");
+}
+
+async fn store_blob(storage: &dyn RegistryStorage, contents: &[u8]) -> Digest {
+    let digest = Digest::from_contents(contents);
+    let upload = storage
+        .begin_new_upload()
+        .await
+        .expect("could not start upload");
+    write_upload(storage, upload, 0, contents).await;
+    storage
+        .finalize_upload(upload, digest)
+        .await
+        .expect("failed to finalize upload");
+    digest
+}
+
+/// Links the blobs of the sample image to a location, as if they had been uploaded there.
+async fn link_image_blobs(storage: &dyn RegistryStorage, location: &ImageLocation) {
+    for contents in [RAW_CONFIG, RAW_IMAGE] {
+        storage
+            .link_blob(location, Digest::from_contents(contents))
+            .await
+            .expect("failed to link blob");
+    }
+}
+
+/// Stores the sample image, tagged `latest` in the given location.
+async fn store_image(storage: &dyn RegistryStorage, location: &ImageLocation) -> Digest {
+    store_blob(storage, RAW_CONFIG).await;
+    store_blob(storage, RAW_IMAGE).await;
+    link_image_blobs(storage, location).await;
+    storage
+        .put_manifest(&tag_reference(location, "latest"), RAW_MANIFEST)
+        .await
+        .expect("failed to store manifest")
+}
+
+/// Checks that a location has no tags left, which makes it unknown to the storage.
+async fn assert_untagged(storage: &dyn RegistryStorage, location: &ImageLocation) {
+    assert_eq!(storage.list_tags(location).await.unwrap(), None);
+}
+
+async fn uploads_are_verified_and_stored(storage: &dyn RegistryStorage) {
+    let digest = Digest::from_contents(b"hello world");
+
+    assert!(storage.get_blob_metadata(digest).await.unwrap().is_none());
+    assert!(storage.get_blob_reader(digest, 0).await.unwrap().is_none());
+
+    let upload = storage.begin_new_upload().await.unwrap();
+    assert_eq!(storage.get_upload_progress(upload).await.unwrap(), 0);
+
+    write_upload(storage, upload, 0, b"hello ").await;
+    write_upload(storage, upload, 6, b"world").await;
+    assert_eq!(storage.get_upload_progress(upload).await.unwrap(), 11);
+
+    // A mismatch keeps the upload around.
+    assert!(matches!(
+        storage
+            .finalize_upload(upload, Digest::from_contents(b"hello"))
+            .await,
+        Err(Error::DigestMismatch)
+    ));
+    assert_eq!(storage.get_upload_progress(upload).await.unwrap(), 11);
+
+    storage.finalize_upload(upload, digest).await.unwrap();
+    assert!(matches!(
+        storage.get_upload_progress(upload).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+
+    let metadata = storage
+        .get_blob_metadata(digest)
+        .await
+        .unwrap()
+        .expect("blob should exist");
+    assert_eq!(metadata.digest(), digest);
+    assert_eq!(metadata.size(), 11);
+
+    let mut contents = Vec::new();
+    storage
+        .get_blob_reader(digest, 6)
+        .await
+        .unwrap()
+        .expect("blob should exist")
+        .read_to_end(&mut contents)
+        .await
+        .unwrap();
+    assert_eq!(contents, b"world");
+
+    let blobs = storage.list_blobs().await.unwrap();
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(blobs[0].digest(), digest);
+}
+
+async fn uploads_are_locked_while_written(storage: &dyn RegistryStorage) {
+    assert!(matches!(
+        storage.get_upload_progress(Uuid::new_v4()).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+    assert!(matches!(
+        storage.get_upload_writer(0, Uuid::new_v4()).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+
+    let upload = storage.begin_new_upload().await.unwrap();
+    let writer = storage.get_upload_writer(0, upload).await.unwrap();
+
+    assert!(matches!(
+        storage.get_upload_writer(0, upload).await,
+        Err(Error::UploadLocked)
+    ));
+    assert!(matches!(
+        storage
+            .finalize_upload(upload, Digest::from_contents(b""))
+            .await,
+        Err(Error::UploadLocked)
+    ));
+    assert!(matches!(
+        storage.cancel_upload(upload).await,
+        Err(Error::UploadLocked)
+    ));
+
+    drop(writer);
+
+    assert!(matches!(
+        storage.get_upload_writer(5, upload).await,
+        Err(Error::UploadOffsetMismatch)
+    ));
+    write_upload(storage, upload, 0, b"data").await;
+    assert!(matches!(
+        storage.get_upload_writer(0, upload).await,
+        Err(Error::UploadOffsetMismatch)
+    ));
+}
+
+async fn uploads_can_be_cancelled_and_expire(storage: &dyn RegistryStorage) {
+    let cancelled = storage.begin_new_upload().await.unwrap();
+    storage.cancel_upload(cancelled).await.unwrap();
+    assert!(matches!(
+        storage.get_upload_progress(cancelled).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+    assert!(matches!(
+        storage.cancel_upload(cancelled).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+
+    let idle = storage.begin_new_upload().await.unwrap();
+    let busy = storage.begin_new_upload().await.unwrap();
+    let writer = storage.get_upload_writer(0, busy).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    assert!(storage
+        .expire_uploads(Duration::from_secs(3600))
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        storage.expire_uploads(Duration::ZERO).await.unwrap(),
+        vec![idle]
+    );
+    assert!(matches!(
+        storage.get_upload_progress(idle).await,
+        Err(Error::UploadDoesNotExit)
+    ));
+
+    drop(writer);
+    assert_eq!(storage.get_upload_progress(busy).await.unwrap(), 0);
+}
+
+async fn manifests_require_known_references(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+    let digest = Digest::from_contents(RAW_MANIFEST);
+
+    assert!(matches!(
+        storage
+            .put_manifest(&tag_reference(&location, "latest"), RAW_MANIFEST)
+            .await,
+        Err(Error::UnknownBlobs(ref unknown)) if unknown.len() == 2
+    ));
+    assert!(storage.list_manifests().await.unwrap().is_empty());
+
+    store_blob(storage, RAW_CONFIG).await;
+    store_blob(storage, RAW_IMAGE).await;
+
+    // Stored, but not uploaded to this location.
+    assert!(matches!(
+        storage
+            .put_manifest(&tag_reference(&location, "latest"), RAW_MANIFEST)
+            .await,
+        Err(Error::UnknownBlobs(ref unknown)) if unknown.len() == 2
+    ));
+    link_image_blobs(storage, &location).await;
+
+    assert!(matches!(
+        storage
+            .put_manifest(
+                &digest_reference(&location, Digest::from_contents(b"other")),
+                RAW_MANIFEST
+            )
+            .await,
+        Err(Error::DigestMismatch)
+    ));
+    assert!(matches!(
+        storage
+            .put_manifest(&tag_reference(&location, "latest"), b"not json")
+            .await,
+        Err(Error::InvalidManifest(_))
+    ));
+
+    assert_eq!(
+        storage
+            .put_manifest(&digest_reference(&location, digest), RAW_MANIFEST)
+            .await
+            .unwrap(),
+        digest
+    );
+    assert_eq!(
+        storage
+            .get_manifest(&digest_reference(&location, digest))
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(RAW_MANIFEST)
+    );
+    assert!(storage
+        .get_manifest(&tag_reference(&location, "latest"))
+        .await
+        .unwrap()
+        .is_none());
+
+    let index = |digest: Digest, size: usize| {
+        format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [{{
+                    "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                    "digest": "sha256:{}",
+                    "size": {},
+                    "platform": {{ "os": "linux", "architecture": "amd64" }}
+                }}]
+            }}"#,
+            digest, size
+        )
+    };
+
+    let missing = Digest::from_contents(b"missing");
+    assert!(matches!(
+        storage
+            .put_manifest(
+                &tag_reference(&location, "multi"),
+                index(missing, 7).as_bytes()
+            )
+            .await,
+        Err(Error::UnknownReferences(ref unknown)) if *unknown == [missing]
+    ));
+    storage
+        .put_manifest(
+            &tag_reference(&location, "multi"),
+            index(digest, RAW_MANIFEST.len()).as_bytes(),
+        )
+        .await
+        .expect("index of known manifests should be accepted");
+    assert_eq!(storage.list_manifests().await.unwrap().len(), 2);
+
+    // Manifests pushed to other locations cannot be referenced.
+    let other = ImageLocation::new("tests".to_owned(), "other".to_owned());
+    assert!(matches!(
+        storage
+            .put_manifest(
+                &tag_reference(&other, "multi"),
+                index(digest, RAW_MANIFEST.len()).as_bytes()
+            )
+            .await,
+        Err(Error::UnknownReferences(ref unknown)) if *unknown == [digest]
+    ));
+}
+
+async fn tags_point_to_manifests(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+
+    assert!(storage.list_tags(&location).await.unwrap().is_none());
+    assert!(storage.list_locations().await.unwrap().is_empty());
+
+    let digest = store_image(storage, &location).await;
+    for tag in ["v2", "v1"] {
+        storage
+            .put_manifest(&tag_reference(&location, tag), RAW_MANIFEST)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        storage.list_tags(&location).await.unwrap(),
+        Some(vec!["latest".to_owned(), "v1".to_owned(), "v2".to_owned()])
+    );
+    assert_eq!(
+        storage.list_locations().await.unwrap(),
+        vec![location.clone()]
+    );
+    assert_eq!(
+        storage
+            .get_manifest_digest(&tag_reference(&location, "v1"))
+            .await
+            .unwrap(),
+        Some(digest)
+    );
+    assert_eq!(
+        storage
+            .get_manifest(&tag_reference(&location, "v1"))
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(RAW_MANIFEST)
+    );
+    assert!(storage
+        .get_manifest_digest(&tag_reference(&location, "v3"))
+        .await
+        .unwrap()
+        .is_none());
+
+    assert_eq!(
+        storage
+            .delete_manifest(&tag_reference(&location, "v1"))
+            .await
+            .unwrap(),
+        Some(vec!["v1".to_owned()])
+    );
+    assert!(storage
+        .delete_manifest(&tag_reference(&location, "v1"))
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        storage.list_tags(&location).await.unwrap(),
+        Some(vec!["latest".to_owned(), "v2".to_owned()])
+    );
+
+    // Removing a tag leaves the manifest in place.
+    assert_eq!(
+        storage
+            .get_manifest_digest(&digest_reference(&location, digest))
+            .await
+            .unwrap(),
+        Some(digest)
+    );
+}
+
+async fn deleting_by_digest_removes_tags(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+    let other = ImageLocation::new("tests".to_owned(), "other".to_owned());
+
+    let digest = store_image(storage, &location).await;
+    storage
+        .put_manifest(&tag_reference(&location, "stable"), RAW_MANIFEST)
+        .await
+        .unwrap();
+    link_image_blobs(storage, &other).await;
+    storage
+        .put_manifest(&tag_reference(&other, "latest"), RAW_MANIFEST)
+        .await
+        .unwrap();
+
+    assert!(storage
+        .delete_manifest(&digest_reference(
+            &location,
+            Digest::from_contents(b"missing")
+        ))
+        .await
+        .unwrap()
+        .is_none());
+
+    let mut removed = storage
+        .delete_manifest(&digest_reference(&location, digest))
+        .await
+        .unwrap()
+        .expect("manifest should exist");
+    removed.sort();
+    assert_eq!(removed, ["latest", "stable"]);
+    assert_untagged(storage, &location).await;
+    assert_eq!(storage.list_locations().await.unwrap(), vec![other.clone()]);
+
+    // Still tagged elsewhere, so the manifest itself is kept.
+    assert_eq!(
+        storage
+            .get_manifest(&tag_reference(&other, "latest"))
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(RAW_MANIFEST)
+    );
+
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&other, digest))
+            .await
+            .unwrap(),
+        Some(vec!["latest".to_owned()])
+    );
+    assert_untagged(storage, &other).await;
+    assert!(storage.list_manifests().await.unwrap().is_empty());
+    assert!(storage
+        .get_manifest_digest(&digest_reference(&other, digest))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+async fn deleting_by_digest_is_scoped_to_location(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+    let other = ImageLocation::new("tests".to_owned(), "other".to_owned());
+    let digest = store_image(storage, &location).await;
+
+    // Never pushed to the other location, so it is unknown there.
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&other, digest))
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(storage.list_revisions(&location).await.unwrap(), [digest]);
+
+    // Untagged pushes belong to the location as well.
+    link_image_blobs(storage, &other).await;
+    storage
+        .put_manifest(&digest_reference(&other, digest), RAW_MANIFEST)
+        .await
+        .unwrap();
+    assert_eq!(storage.list_revisions(&other).await.unwrap(), [digest]);
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&other, digest))
+            .await
+            .unwrap(),
+        Some(Vec::new())
+    );
+    assert!(storage.list_revisions(&other).await.unwrap().is_empty());
+
+    let index = format!(
+        r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{{
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "digest": "sha256:{}",
+                "size": {}
+            }}]
+        }}"#,
+        digest,
+        RAW_MANIFEST.len()
+    );
+    storage
+        .put_manifest(&digest_reference(&other, digest), RAW_MANIFEST)
+        .await
+        .unwrap();
+    let index_digest = storage
+        .put_manifest(&tag_reference(&other, "multi"), index.as_bytes())
+        .await
+        .unwrap();
+
+    // Gone from the location, but still pushed to the other one.
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&location, digest))
+            .await
+            .unwrap(),
+        Some(vec!["latest".to_owned()])
+    );
+    assert!(storage.list_revisions(&location).await.unwrap().is_empty());
+    assert_eq!(
+        storage
+            .get_manifest(&digest_reference(&other, digest))
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(RAW_MANIFEST)
+    );
+
+    // Gone from the other location as well, but its index still needs it.
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&other, digest))
+            .await
+            .unwrap(),
+        Some(Vec::new())
+    );
+    assert_eq!(
+        storage.list_revisions(&other).await.unwrap(),
+        [index_digest]
+    );
+    assert_eq!(storage.list_manifests().await.unwrap().len(), 2);
+
+    // Manifests left behind by their index are up to garbage collection.
+    storage
+        .delete_manifest(&digest_reference(&other, index_digest))
+        .await
+        .unwrap()
+        .expect("index should exist");
+    assert_eq!(storage.list_manifests().await.unwrap().len(), 1);
+}
+
+async fn referrers_are_recorded(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+    let subject = store_image(storage, &location).await;
+    let empty = store_blob(storage, b"{}").await;
+    storage.link_blob(&location, empty).await.unwrap();
+
+    let artifact = format!(
+        r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example.sbom",
+            "config": {{
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+            }},
+            "layers": [],
+            "subject": {{
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "digest": "sha256:{}",
+                "size": {}
+            }}
+        }}"#,
+        subject,
+        RAW_MANIFEST.len()
+    );
+    let artifact_digest = Digest::from_contents(artifact.as_bytes());
+
+    // Subjects need not exist, so referrers of unknown manifests are simply empty.
+    assert!(storage
+        .list_referrers(&location, artifact_digest)
+        .await
+        .unwrap()
+        .is_empty());
+
+    storage
+        .put_manifest(
+            &digest_reference(&location, artifact_digest),
+            artifact.as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let referrers = storage.list_referrers(&location, subject).await.unwrap();
+    assert_eq!(referrers.len(), 1);
+    assert_eq!(referrers[0].digest(), Some(artifact_digest));
+    assert_eq!(referrers[0].size(), artifact.len() as u64);
+    assert_eq!(
+        referrers[0].artifact_type(),
+        Some("application/vnd.example.sbom")
+    );
+
+    let other = ImageLocation::new("tests".to_owned(), "other".to_owned());
+    assert!(storage
+        .list_referrers(&other, subject)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Deleting the referrer from another location leaves it in place.
+    assert!(storage
+        .delete_manifest(&digest_reference(&other, artifact_digest))
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        storage
+            .list_referrers(&location, subject)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    assert_eq!(
+        storage
+            .delete_manifest(&digest_reference(&location, artifact_digest))
+            .await
+            .unwrap(),
+        Some(Vec::new())
+    );
+    assert!(storage
+        .list_referrers(&location, subject)
+        .await
+        .unwrap()
+        .is_empty());
+
+    storage
+        .put_manifest(
+            &digest_reference(&location, artifact_digest),
+            artifact.as_bytes(),
+        )
+        .await
+        .unwrap();
+    storage.purge_manifest(artifact_digest).await.unwrap();
+    assert!(storage
+        .list_referrers(&location, subject)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+async fn objects_can_be_purged_and_quarantined(storage: &dyn RegistryStorage) {
+    let location = sample_location();
+    let manifest = store_image(storage, &location).await;
+    let config = Digest::from_contents(RAW_CONFIG);
+    let image = Digest::from_contents(RAW_IMAGE);
+
+    assert_eq!(storage.list_blobs().await.unwrap().len(), 2);
+    assert_eq!(storage.list_manifests().await.unwrap().len(), 1);
+
+    storage.purge_blob(config).await.unwrap();
+    assert!(storage.get_blob_metadata(config).await.unwrap().is_none());
+
+    storage.quarantine_blob(image).await.unwrap();
+    assert!(storage.get_blob_metadata(image).await.unwrap().is_none());
+    assert!(storage.list_blobs().await.unwrap().is_empty());
+
+    storage.quarantine_manifest(manifest).await.unwrap();
+    assert!(storage.list_manifests().await.unwrap().is_empty());
+    assert!(storage
+        .get_manifest(&tag_reference(&location, "latest"))
+        .await
+        .unwrap()
+        .is_none());
+
+    // The blob can be uploaded again afterwards.
+    store_blob(storage, RAW_IMAGE).await;
+    assert_eq!(storage.list_blobs().await.unwrap().len(), 1);
+}
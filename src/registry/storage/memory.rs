@@ -0,0 +1,551 @@
+//! Storage backend keeping everything in memory.
+//!
+//! Nothing survives a restart, which makes it a good fit for tests and throwaway registries, e.g.
+//! in CI. Semantics match the filesystem storage, including upload locking and the hashing of
+//! uploads while they are being written.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{self, Cursor},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use axum::async_trait;
+use sha2::{Digest as Sha2Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use super::{
+    super::types::ContentDescriptor, is_in_location_index, BlobMetadata, Digest, Error,
+    ImageLocation, ManifestDeletion, ManifestReference, NewManifest, Reference, RegistryStorage,
+    UploadLock,
+};
+
+#[derive(Debug)]
+struct Object {
+    data: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+impl Object {
+    fn new(data: Vec<u8>) -> Self {
+        Object {
+            data: data.into(),
+            modified: SystemTime::now(),
+        }
+    }
+
+    fn metadata(&self, digest: Digest) -> BlobMetadata {
+        BlobMetadata {
+            digest,
+            size: self.data.len() as u64,
+            modified: self.modified,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Upload {
+    data: Vec<u8>,
+    hasher: Sha256,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct Contents {
+    uploads: HashMap<Uuid, Upload>,
+    blobs: HashMap<Digest, Object>,
+    manifests: HashMap<Digest, Object>,
+    /// Tags by location. Like directories on disk, locations remain after their last tag is gone.
+    tags: HashMap<ImageLocation, BTreeMap<String, Digest>>,
+    /// Manifests pushed to each location, tagged or not.
+    revisions: HashMap<ImageLocation, BTreeSet<Digest>>,
+    /// Blobs belonging to each location.
+    links: HashMap<ImageLocation, HashSet<Digest>>,
+    /// Serialized referrer descriptors, by location and subject.
+    referrers: HashMap<(ImageLocation, Digest), BTreeMap<Digest, Vec<u8>>>,
+    quarantine: HashMap<String, Object>,
+}
+
+impl Contents {
+    fn read_tag(&self, location: &ImageLocation, tag: &str) -> Option<Digest> {
+        self.tags.get(location)?.get(tag).copied()
+    }
+
+    /// Checks whether the given manifest has been pushed to any location, which tagging implies.
+    fn is_manifest_in_use(&self, digest: Digest) -> bool {
+        self.revisions
+            .values()
+            .any(|revisions| revisions.contains(&digest))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStorage {
+    contents: Arc<Mutex<Contents>>,
+    locked_uploads: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+/// Upload writer appending to an upload, hashing all data as it is being written.
+struct UploadWriter {
+    contents: Arc<Mutex<Contents>>,
+    upload: Uuid,
+    _lock: UploadLock,
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut contents = self.contents.lock().expect("lock poisoned");
+
+        // Uploads cannot vanish while locked, unless the storage is misbehaving.
+        let Some(upload) = contents.uploads.get_mut(&self.upload) else {
+            return Poll::Ready(Err(io::ErrorKind::NotFound.into()));
+        };
+
+        upload.data.extend_from_slice(buf);
+        upload.hasher.update(buf);
+        upload.modified = SystemTime::now();
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl MemoryStorage {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    fn contents(&self) -> std::sync::MutexGuard<'_, Contents> {
+        self.contents.lock().expect("lock poisoned")
+    }
+}
+
+#[async_trait]
+impl RegistryStorage for MemoryStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        let upload = Uuid::new_v4();
+
+        self.contents().uploads.insert(
+            upload,
+            Upload {
+                data: Vec::new(),
+                hasher: Sha256::new(),
+                modified: SystemTime::now(),
+            },
+        );
+
+        Ok(upload)
+    }
+
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        start_at: u64,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        let Some(data) = self
+            .contents()
+            .blobs
+            .get(&digest)
+            .map(|blob| blob.data.clone())
+        else {
+            return Ok(None);
+        };
+
+        let mut reader = Cursor::new(data);
+        reader.set_position(start_at);
+
+        Ok(Some(Box::new(reader)))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self
+            .contents()
+            .blobs
+            .get(&digest)
+            .map(|blob| blob.metadata(digest)))
+    }
+
+    async fn get_upload_progress(&self, upload: Uuid) -> Result<u64, Error> {
+        self.contents()
+            .uploads
+            .get(&upload)
+            .map(|upload| upload.data.len() as u64)
+            .ok_or(Error::UploadDoesNotExit)
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        let lock = UploadLock::acquire(&self.locked_uploads, upload)?;
+
+        if self.get_upload_progress(upload).await? != start_at {
+            return Err(Error::UploadOffsetMismatch);
+        }
+
+        Ok(Box::new(UploadWriter {
+            contents: self.contents.clone(),
+            upload,
+            _lock: lock,
+        }))
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
+        let _lock = UploadLock::acquire(&self.locked_uploads, upload)?;
+        let mut contents = self.contents();
+
+        let state = contents
+            .uploads
+            .get(&upload)
+            .ok_or(Error::UploadDoesNotExit)?;
+        if Digest::new(state.hasher.clone().finalize().into()) != digest {
+            // The upload is kept, the client might still continue it.
+            return Err(Error::DigestMismatch);
+        }
+
+        let state = contents
+            .uploads
+            .remove(&upload)
+            .expect("upload should still exist");
+        contents.blobs.insert(digest, Object::new(state.data));
+
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        let _lock = UploadLock::acquire(&self.locked_uploads, upload)?;
+
+        self.contents()
+            .uploads
+            .remove(&upload)
+            .map(|_| ())
+            .ok_or(Error::UploadDoesNotExit)
+    }
+
+    async fn expire_uploads(&self, max_idle: Duration) -> Result<Vec<Uuid>, Error> {
+        let now = SystemTime::now();
+        let mut contents = self.contents();
+
+        let idle: Vec<_> = contents
+            .uploads
+            .iter()
+            .filter(|(_, upload)| {
+                now.duration_since(upload.modified).unwrap_or_default() > max_idle
+            })
+            .map(|(upload, _)| *upload)
+            .collect();
+
+        let mut expired = Vec::new();
+        for upload in idle {
+            // Uploads that are in use are anything but idle.
+            let Ok(_lock) = UploadLock::acquire(&self.locked_uploads, upload) else {
+                continue;
+            };
+
+            contents.uploads.remove(&upload);
+            expired.push(upload);
+        }
+
+        Ok(expired)
+    }
+
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let contents = self.contents();
+
+        let digest = match manifest_reference.reference() {
+            Reference::Tag(ref tag) => {
+                match contents.read_tag(manifest_reference.location(), tag) {
+                    Some(digest) => digest,
+                    None => return Ok(None),
+                }
+            }
+            Reference::Digest(digest) => *digest,
+        };
+
+        Ok(contents
+            .manifests
+            .get(&digest)
+            .map(|manifest| manifest.data.to_vec()))
+    }
+
+    async fn get_manifest_digest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Digest>, Error> {
+        let contents = self.contents();
+
+        match manifest_reference.reference() {
+            Reference::Tag(ref tag) => Ok(contents.read_tag(manifest_reference.location(), tag)),
+            Reference::Digest(digest) => {
+                Ok(Some(*digest).filter(|digest| contents.manifests.contains_key(digest)))
+            }
+        }
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        let new = NewManifest::parse(manifest_reference, manifest)?;
+        new.check_references(self, manifest_reference.location())
+            .await?;
+
+        let digest = new.digest();
+        let mut contents = self.contents();
+        contents
+            .manifests
+            .insert(digest, Object::new(manifest.to_vec()));
+
+        let location = manifest_reference.location();
+        contents
+            .revisions
+            .entry(location.clone())
+            .or_default()
+            .insert(digest);
+
+        if let Some((subject, raw)) = new.referrer()? {
+            contents
+                .referrers
+                .entry((location.clone(), subject))
+                .or_default()
+                .insert(digest, raw);
+        }
+
+        if let Some(tag) = manifest_reference.reference().as_tag() {
+            contents
+                .tags
+                .entry(location.clone())
+                .or_default()
+                .insert(tag.to_owned(), digest);
+        }
+
+        Ok(digest)
+    }
+
+    async fn delete_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<String>>, Error> {
+        let location = manifest_reference.location();
+
+        match manifest_reference.reference() {
+            Reference::Tag(ref tag) => Ok(self
+                .contents()
+                .tags
+                .get_mut(location)
+                .and_then(|tags| tags.remove(tag))
+                .map(|_| vec![tag.clone()])),
+            Reference::Digest(digest) => {
+                let Some(deletion) = ManifestDeletion::plan(self, location, *digest).await? else {
+                    return Ok(None);
+                };
+                let in_index = is_in_location_index(self, location, *digest).await?;
+
+                let mut contents = self.contents();
+                if let Some(tags) = contents.tags.get_mut(location) {
+                    tags.retain(|tag, _| !deletion.tags.contains(tag));
+                }
+                if let Some(revisions) = contents.revisions.get_mut(location) {
+                    revisions.remove(digest);
+                }
+                if let Some(subject) = deletion.subject {
+                    if let Some(referrers) =
+                        contents.referrers.get_mut(&(location.clone(), subject))
+                    {
+                        referrers.remove(digest);
+                    }
+                }
+
+                // Manifests are shared between locations, only remove if no longer in use.
+                if !contents.is_manifest_in_use(*digest) && !in_index {
+                    contents.manifests.remove(digest);
+                }
+
+                Ok(Some(deletion.tags))
+            }
+        }
+    }
+
+    async fn list_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        let contents = self.contents();
+
+        let Some(referrers) = contents.referrers.get(&(location.clone(), subject)) else {
+            return Ok(Vec::new());
+        };
+
+        referrers
+            .iter()
+            // Referrers whose manifest has been deleted or collected are skipped.
+            .filter(|(digest, _)| contents.manifests.contains_key(digest))
+            .map(|(_, raw)| serde_json::from_slice(raw).map_err(Error::InvalidManifest))
+            .collect()
+    }
+
+    async fn list_revisions(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        let contents = self.contents();
+
+        Ok(contents
+            .revisions
+            .get(location)
+            .into_iter()
+            .flatten()
+            // Quarantined manifests leave their records behind.
+            .filter(|digest| contents.manifests.contains_key(digest))
+            .copied()
+            .collect())
+    }
+
+    async fn has_revision(&self, location: &ImageLocation, digest: Digest) -> Result<bool, Error> {
+        let contents = self.contents();
+
+        Ok(contents
+            .revisions
+            .get(location)
+            .is_some_and(|revisions| revisions.contains(&digest))
+            && contents.manifests.contains_key(&digest))
+    }
+
+    async fn record_revision(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.contents()
+            .revisions
+            .entry(location.clone())
+            .or_default()
+            .insert(digest);
+        Ok(())
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.contents()
+            .links
+            .entry(location.clone())
+            .or_default()
+            .insert(digest);
+        Ok(())
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        Ok(self
+            .contents()
+            .links
+            .get(location)
+            .is_some_and(|links| links.contains(&digest)))
+    }
+
+    async fn list_linked_blobs(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        Ok(self
+            .contents()
+            .links
+            .get(location)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect())
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+        Ok(self
+            .contents()
+            .tags
+            .get(location)
+            .filter(|tags| !tags.is_empty())
+            .map(|tags| tags.keys().cloned().collect()))
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        Ok(self
+            .contents()
+            .tags
+            .iter()
+            .filter(|(_, tags)| !tags.is_empty())
+            .map(|(location, _)| location.clone())
+            .collect())
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        Ok(self
+            .contents()
+            .blobs
+            .iter()
+            .map(|(digest, blob)| blob.metadata(*digest))
+            .collect())
+    }
+
+    async fn list_manifests(&self) -> Result<Vec<BlobMetadata>, Error> {
+        Ok(self
+            .contents()
+            .manifests
+            .iter()
+            .map(|(digest, manifest)| manifest.metadata(*digest))
+            .collect())
+    }
+
+    async fn purge_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.contents().blobs.remove(&digest);
+        Ok(())
+    }
+
+    async fn purge_manifest(&self, digest: Digest) -> Result<(), Error> {
+        let mut contents = self.contents();
+
+        for revisions in contents.revisions.values_mut() {
+            revisions.remove(&digest);
+        }
+        contents.manifests.remove(&digest);
+
+        Ok(())
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        let mut contents = self.contents();
+
+        let blob = contents
+            .blobs
+            .remove(&digest)
+            .ok_or_else(|| Error::Io(io::ErrorKind::NotFound.into()))?;
+        contents.quarantine.insert(format!("blob-{}", digest), blob);
+
+        Ok(())
+    }
+
+    async fn quarantine_manifest(&self, digest: Digest) -> Result<(), Error> {
+        let mut contents = self.contents();
+
+        let manifest = contents
+            .manifests
+            .remove(&digest)
+            .ok_or_else(|| Error::Io(io::ErrorKind::NotFound.into()))?;
+        contents
+            .quarantine
+            .insert(format!("manifest-{}", digest), manifest);
+
+        Ok(())
+    }
+}
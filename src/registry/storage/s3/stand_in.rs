@@ -0,0 +1,259 @@
+//! In-process stand-in for an S3-compatible object store, used in tests.
+//!
+//! Implements just enough of the API for [`S3Storage`](super::S3Storage), similar to what MinIO
+//! offers: objects, copies, paginated listings and multipart uploads assembled from copied parts.
+//! Requests must carry a SigV4 `Authorization` header, which is not verified though.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use reqwest::Url;
+use sec::Secret;
+use sha2::{Digest, Sha256};
+
+use super::{civil_from_days, S3Options};
+
+/// Page size of listings, small to exercise pagination.
+const MAX_KEYS: usize = 3;
+
+/// Minimum size of all parts but the last, as enforced by S3.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+const BUCKET: &str = "rockslide-test";
+
+#[derive(Debug, Default)]
+struct Bucket {
+    objects: BTreeMap<String, (Vec<u8>, SystemTime)>,
+    multipart_uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+}
+
+type Shared = Arc<Mutex<Bucket>>;
+
+/// Starts a stand-in server, returning options to access it with.
+pub(crate) async fn spawn() -> S3Options {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("could not bind stand-in");
+    let addr = listener.local_addr().expect("should have local address");
+
+    let app = Router::new()
+        .fallback(handle)
+        .layer(DefaultBodyLimit::disable())
+        .with_state(Shared::default());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    S3Options {
+        endpoint: Url::parse(&format!("http://{}", addr)).expect("should be valid URL"),
+        bucket: BUCKET.to_owned(),
+        region: "us-east-1".to_owned(),
+        access_key: "minioadmin".to_owned(),
+        secret_key: Secret::new("minioadmin".to_owned()),
+        prefix: "registry/".to_owned(),
+        path_style: true,
+    }
+}
+
+fn error(status: StatusCode, code: &str) -> Response {
+    (
+        status,
+        format!("<Error><Code>{}</Code><Message></Message></Error>", code),
+    )
+        .into_response()
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(Sha256::digest(data))[..32])
+}
+
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+async fn handle(
+    State(bucket): State<Shared>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let signed = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("AWS4-HMAC-SHA256 Credential="));
+    if !signed {
+        return error(StatusCode::FORBIDDEN, "AccessDenied");
+    }
+
+    let path = uri.path().trim_start_matches('/');
+    let (bucket_name, key) = path.split_once('/').unwrap_or((path, ""));
+    if bucket_name != BUCKET {
+        return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+    }
+
+    let copy_source = headers
+        .get("x-amz-copy-source")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(&format!("/{}/", BUCKET)))
+        .map(ToOwned::to_owned);
+
+    let mut bucket = bucket.lock().unwrap();
+    let now = SystemTime::now();
+
+    match method {
+        Method::GET if key.is_empty() => list(&bucket, &query),
+        Method::GET => {
+            let Some((data, _)) = bucket.objects.get(key) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchKey");
+            };
+
+            let start = headers
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("bytes="))
+                .and_then(|value| value.strip_suffix('-'))
+                .and_then(|value| value.parse::<usize>().ok());
+
+            match start {
+                None => data.clone().into_response(),
+                Some(start) if start < data.len() => {
+                    (StatusCode::PARTIAL_CONTENT, data[start..].to_vec()).into_response()
+                }
+                Some(_) => error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange"),
+            }
+        }
+        Method::PUT => {
+            let data = match copy_source {
+                Some(source) => match bucket.objects.get(&source) {
+                    Some((data, _)) => data.clone(),
+                    None => return error(StatusCode::NOT_FOUND, "NoSuchKey"),
+                },
+                None => body.to_vec(),
+            };
+            let tag = etag(&data);
+
+            match (query.get("uploadId"), query.get("partNumber")) {
+                (Some(upload_id), Some(part_number)) => {
+                    let Ok(part_number) = part_number.parse() else {
+                        return error(StatusCode::BAD_REQUEST, "InvalidArgument");
+                    };
+                    let Some(parts) = bucket.multipart_uploads.get_mut(upload_id) else {
+                        return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+                    };
+                    parts.insert(part_number, data);
+
+                    format!("<CopyPartResult><ETag>{}</ETag></CopyPartResult>", tag).into_response()
+                }
+                _ => {
+                    bucket.objects.insert(key.to_owned(), (data, now));
+                    format!("<CopyObjectResult><ETag>{}</ETag></CopyObjectResult>", tag)
+                        .into_response()
+                }
+            }
+        }
+        Method::DELETE => {
+            match query.get("uploadId") {
+                Some(upload_id) => {
+                    bucket.multipart_uploads.remove(upload_id);
+                }
+                None => {
+                    bucket.objects.remove(key);
+                }
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Method::POST if query.contains_key("uploads") => {
+            let upload_id = uuid::Uuid::new_v4().to_string();
+            bucket
+                .multipart_uploads
+                .insert(upload_id.clone(), Default::default());
+
+            format!(
+                "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                upload_id
+            )
+            .into_response()
+        }
+        Method::POST => {
+            let Some(parts) = query
+                .get("uploadId")
+                .and_then(|upload_id| bucket.multipart_uploads.remove(upload_id))
+            else {
+                return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+
+            let count = parts.len();
+            if parts
+                .values()
+                .take(count.saturating_sub(1))
+                .any(|part| part.len() < MIN_PART_SIZE)
+            {
+                return error(StatusCode::BAD_REQUEST, "EntityTooSmall");
+            }
+
+            let data: Vec<u8> = parts.into_values().flatten().collect();
+            bucket.objects.insert(key.to_owned(), (data, now));
+
+            "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response()
+        }
+        _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+    }
+}
+
+fn list(bucket: &Bucket, query: &HashMap<String, String>) -> Response {
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+    let after = query.get("continuation-token");
+
+    let mut matching = bucket
+        .objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .filter(|(key, _)| after.is_none_or(|after| *key > after));
+
+    let mut contents = String::new();
+    let mut last = None;
+    for (key, (data, modified)) in matching.by_ref().take(MAX_KEYS) {
+        contents.push_str(&format!(
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size></Contents>",
+            key,
+            format_timestamp(*modified),
+            etag(data),
+            data.len()
+        ));
+        last = Some(key);
+    }
+
+    let truncated = matching.next().is_some();
+    let token = match last {
+        Some(key) if truncated => format!("<NextContinuationToken>{}</NextContinuationToken>", key),
+        _ => String::new(),
+    };
+
+    format!(
+        "<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+        BUCKET, prefix, truncated, contents, token
+    )
+    .into_response()
+}
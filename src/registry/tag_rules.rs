@@ -0,0 +1,126 @@
+//! Tag protection rules.
+//!
+//! Rules apply to all repositories matching their pattern. Tags matching one of the rule's
+//! immutable patterns, e.g. release tags like `v*.*.*`, may be created but never moved or
+//! deleted afterwards. Tags matching a protected pattern, e.g. `prod`, may only be pushed or
+//! deleted by the users listed as pushers.
+
+use serde::Deserialize;
+
+use super::{
+    pattern::Pattern,
+    storage::ImageLocation,
+    types::{ErrorCode, OciError},
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TagRule {
+    repository: Pattern,
+    #[serde(default)]
+    immutable: Vec<Pattern>,
+    #[serde(default)]
+    protected: Vec<Pattern>,
+    /// Users allowed to change protected tags.
+    #[serde(default)]
+    pushers: Vec<String>,
+}
+
+impl TagRule {
+    #[cfg(test)]
+    pub(crate) fn new(
+        repository: &str,
+        immutable: &[&str],
+        protected: &[&str],
+        pushers: &[&str],
+    ) -> Self {
+        Self {
+            repository: Pattern::new(repository),
+            immutable: immutable.iter().copied().map(Pattern::new).collect(),
+            protected: protected.iter().copied().map(Pattern::new).collect(),
+            pushers: pushers.iter().map(|&pusher| pusher.to_owned()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TagRules {
+    rules: Vec<TagRule>,
+}
+
+impl TagRules {
+    pub(crate) fn new(rules: Vec<TagRule>) -> Self {
+        Self { rules }
+    }
+
+    fn applying_to<'a>(&'a self, location: &ImageLocation) -> impl Iterator<Item = &'a TagRule> {
+        let name = location.to_string();
+        self.rules
+            .iter()
+            .filter(move |rule| rule.repository.matches(&name))
+    }
+
+    /// Returns whether any rule makes the tag immutable.
+    pub(crate) fn is_immutable(&self, location: &ImageLocation, tag: &str) -> bool {
+        self.applying_to(location)
+            .any(|rule| rule.immutable.iter().any(|pattern| pattern.matches(tag)))
+    }
+
+    /// Checks whether `username` may change `tag`.
+    ///
+    /// `overwrite` is set if the tag exists and would be moved to another manifest or removed.
+    pub(crate) fn check(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        username: &str,
+        overwrite: bool,
+    ) -> Result<(), OciError> {
+        for rule in self.applying_to(location) {
+            if overwrite && rule.immutable.iter().any(|pattern| pattern.matches(tag)) {
+                return Err(OciError::new(ErrorCode::Denied)
+                    .with_message("tag is immutable")
+                    .with_detail(format!("{}:{}", location, tag)));
+            }
+
+            if rule.protected.iter().any(|pattern| pattern.matches(tag))
+                && !rule.pushers.iter().any(|pusher| pusher == username)
+            {
+                return Err(OciError::new(ErrorCode::Denied)
+                    .with_message("tag is protected")
+                    .with_detail(format!("{}:{}", location, tag)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TagRule, TagRules};
+    use crate::registry::storage::ImageLocation;
+
+    #[test]
+    fn rules_apply_to_matching_repositories() {
+        let rules = TagRules::new(vec![
+            TagRule::new("*", &["v*.*.*"], &[], &[]),
+            TagRule::new("myapp.example.com/*", &[], &["prod"], &["alice"]),
+        ]);
+        let myapp = ImageLocation::new("myapp.example.com".to_owned(), "index".to_owned());
+        let other = ImageLocation::new("other.example.com".to_owned(), "index".to_owned());
+
+        assert!(rules.is_immutable(&myapp, "v1.2.3"));
+        assert!(!rules.is_immutable(&myapp, "latest"));
+
+        // Immutable tags may be created, but not overwritten.
+        assert!(rules.check(&other, "v1.2.3", "bob", false).is_ok());
+        assert!(rules.check(&other, "v1.2.3", "bob", true).is_err());
+        assert!(rules.check(&other, "latest", "bob", true).is_ok());
+
+        // Protected tags may only be changed by pushers, in matching repositories.
+        assert!(rules.check(&myapp, "prod", "alice", true).is_ok());
+        assert!(rules.check(&myapp, "prod", "bob", false).is_err());
+        assert!(rules.check(&other, "prod", "bob", true).is_ok());
+    }
+}
@@ -0,0 +1,406 @@
+//! Bearer tokens, following Docker's token authentication scheme.
+//!
+//! Clients exchange their credentials for a short-lived token at the token endpoint, asking for
+//! the scopes they need, e.g. `repository:tests/sample:pull,push`. Tokens are JWTs signed with
+//! HMAC-SHA256, using a key generated at startup. All tokens become invalid once rockslide
+//! restarts, which is fine given their short lifetime.
+//!
+//! See https://distribution.github.io/distribution/spec/auth/token/
+
+use std::{
+    fmt::{self, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::HeaderMap;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sec::Secret;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Default lifetime of issued tokens.
+pub(crate) const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Default name of the service tokens are issued for.
+pub(crate) const DEFAULT_SERVICE: &str = "rockslide";
+
+/// Path of the token endpoint.
+pub(crate) const TOKEN_PATH: &str = "/_rockslide/registry/token";
+
+const ISSUER: &str = "rockslide";
+
+const KEY_LEN: usize = 32;
+
+/// Clock skew tolerated when checking the validity period of a token.
+const LEEWAY: u64 = 30;
+
+/// Header of every token we issue, the only one we accept.
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            alg: "HS256".to_owned(),
+            typ: "JWT".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Action {
+    Pull,
+    Push,
+}
+
+impl Action {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "pull" => Some(Action::Pull),
+            "push" => Some(Action::Push),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Pull => "pull",
+            Action::Push => "push",
+        })
+    }
+}
+
+/// Access to a single resource, as requested through a scope and granted by a token.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Access {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    actions: Vec<Action>,
+}
+
+impl Access {
+    pub(crate) fn repository(name: String, actions: Vec<Action>) -> Self {
+        Self {
+            kind: "repository".to_owned(),
+            name,
+            actions,
+        }
+    }
+
+    /// Parses a scope, e.g. `repository:tests/sample:pull,push`.
+    ///
+    /// Actions we do not know are dropped, clients are expected to cope with partial grants.
+    pub(crate) fn parse_scope(raw: &str) -> Option<Self> {
+        let (kind, rest) = raw.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+
+        Some(Self {
+            kind: kind.to_owned(),
+            name: name.to_owned(),
+            actions: actions.split(',').filter_map(Action::parse).collect(),
+        })
+    }
+
+    /// Returns the repository and image the access refers to, if it is a repository scope.
+    pub(crate) fn repository_name(&self) -> Option<(&str, &str)> {
+        if self.kind != "repository" {
+            return None;
+        }
+
+        self.name.split_once('/')
+    }
+
+    pub(crate) fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// Drops all actions not matching `keep`.
+    pub(crate) fn retain_actions(&mut self, keep: impl Fn(Action) -> bool) {
+        self.actions.retain(|action| keep(*action));
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:", self.kind, self.name)?;
+
+        for (idx, action) in self.actions.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            Display::fmt(action, f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Claims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: u64,
+    nbf: u64,
+    iat: u64,
+    access: Vec<Access>,
+}
+
+impl Claims {
+    pub(crate) fn subject(&self) -> &str {
+        &self.sub
+    }
+
+    /// Checks whether the token grants `action` on the repository named `name`.
+    pub(crate) fn allows(&self, name: &str, action: Action) -> bool {
+        self.access.iter().any(|access| {
+            access.kind == "repository" && access.name == name && access.actions.contains(&action)
+        })
+    }
+}
+
+/// Response of the token endpoint.
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenResponse {
+    token: String,
+    /// Same as `token`, for OAuth2 compatible clients.
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Issues and verifies bearer tokens.
+#[derive(Debug)]
+pub(crate) struct TokenIssuer {
+    key: Secret<[u8; KEY_LEN]>,
+    service: String,
+    /// URL of the token endpoint, derived from the request if not set.
+    realm: Option<String>,
+    ttl: Duration,
+}
+
+impl Default for TokenIssuer {
+    fn default() -> Self {
+        Self::new(DEFAULT_SERVICE.to_owned(), None, DEFAULT_TOKEN_TTL)
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).expect("serialization should not fail"))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_secs()
+}
+
+impl TokenIssuer {
+    pub(crate) fn new(service: String, realm: Option<String>, ttl: Duration) -> Self {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key).expect("could not generate token signing key");
+
+        Self {
+            key: Secret::new(key),
+            service,
+            realm,
+            ttl,
+        }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(self.key.reveal()).expect("HMAC can take key of any size")
+    }
+
+    /// Issues a token for `subject`, granting the given access.
+    pub(crate) fn issue(&self, subject: &str, access: Vec<Access>) -> TokenResponse {
+        let now = unix_now();
+        let token = self.sign(&Claims {
+            iss: ISSUER.to_owned(),
+            sub: subject.to_owned(),
+            aud: self.service.clone(),
+            exp: now + self.ttl.as_secs(),
+            nbf: now,
+            iat: now,
+            access,
+        });
+
+        TokenResponse {
+            access_token: token.clone(),
+            token,
+            expires_in: self.ttl.as_secs(),
+        }
+    }
+
+    fn sign(&self, claims: &Claims) -> String {
+        let signing_input = format!("{}.{}", encode(&Header::default()), encode(claims));
+
+        let mut mac = self.mac();
+        mac.update(signing_input.as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        format!("{}.{}", signing_input, signature)
+    }
+
+    /// Verifies a token, returning its claims if it is authentic and currently valid.
+    pub(crate) fn verify(&self, token: &str) -> Option<Claims> {
+        let (signing_input, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signing_input.split_once('.')?;
+
+        let mut mac = self.mac();
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?)
+            .ok()?;
+
+        let header: Header =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header.alg != "HS256" {
+            return None;
+        }
+
+        let claims: Claims =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        let now = unix_now();
+        if claims.iss != ISSUER
+            || claims.aud != self.service
+            || claims.nbf > now + LEEWAY
+            || claims.exp + LEEWAY <= now
+        {
+            return None;
+        }
+
+        Some(claims)
+    }
+
+    /// Returns the URL of the token endpoint, as advertised to clients.
+    fn realm(&self, headers: &HeaderMap) -> String {
+        if let Some(ref realm) = self.realm {
+            return realm.clone();
+        }
+
+        // The header values end up inside a quoted string, so they must not contain quotes.
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.contains(['"', '\\']))
+        };
+        let scheme = header("x-forwarded-proto").unwrap_or("http");
+        let host = header("host").unwrap_or("localhost");
+
+        format!("{}://{}{}", scheme, host, TOKEN_PATH)
+    }
+
+    /// Builds a `WWW-Authenticate` challenge pointing clients to the token endpoint.
+    pub(crate) fn challenge(
+        &self,
+        headers: &HeaderMap,
+        scope: Option<&Access>,
+        error: Option<&str>,
+    ) -> String {
+        let mut challenge = format!(
+            "Bearer realm=\"{}\",service=\"{}\"",
+            self.realm(headers),
+            self.service
+        );
+
+        if let Some(scope) = scope {
+            challenge.push_str(&format!(",scope=\"{}\"", scope));
+        }
+
+        if let Some(error) = error {
+            challenge.push_str(&format!(",error=\"{}\"", error));
+        }
+
+        challenge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+
+    use super::{unix_now, Access, Action, Claims, TokenIssuer, DEFAULT_SERVICE, ISSUER};
+
+    #[test]
+    fn scopes_are_parsed() {
+        let access = Access::parse_scope("repository:tests/sample:pull,push,delete").unwrap();
+        assert_eq!(access.repository_name(), Some(("tests", "sample")));
+        assert_eq!(access.actions(), [Action::Pull, Action::Push]);
+        assert_eq!(access.to_string(), "repository:tests/sample:pull,push");
+
+        let access = Access::parse_scope("registry:catalog:*").unwrap();
+        assert_eq!(access.repository_name(), None);
+        assert!(access.actions().is_empty());
+
+        assert!(Access::parse_scope("repository").is_none());
+    }
+
+    #[test]
+    fn tokens_are_verified() {
+        let issuer = TokenIssuer::default();
+        let access = vec![Access::repository(
+            "tests/sample".to_owned(),
+            vec![Action::Pull],
+        )];
+        let response = issuer.issue("ci", access);
+
+        let claims = issuer
+            .verify(&response.token)
+            .expect("token should be valid");
+        assert_eq!(claims.subject(), "ci");
+        assert!(claims.allows("tests/sample", Action::Pull));
+        assert!(!claims.allows("tests/sample", Action::Push));
+        assert!(!claims.allows("tests/other", Action::Pull));
+
+        // Tokens of another instance, or altered ones, are rejected.
+        assert!(TokenIssuer::default().verify(&response.token).is_none());
+        let (signing_input, _) = response.token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", signing_input, "AAAA");
+        assert!(issuer.verify(&forged).is_none());
+        assert!(issuer.verify("not-a-token").is_none());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let issuer = TokenIssuer::default();
+        let now = unix_now();
+        let claims = |exp| Claims {
+            iss: ISSUER.to_owned(),
+            sub: "ci".to_owned(),
+            aud: DEFAULT_SERVICE.to_owned(),
+            exp,
+            nbf: now - 600,
+            iat: now - 600,
+            access: Vec::new(),
+        };
+
+        // Slightly expired tokens are accepted, as clocks may be off a little.
+        assert!(issuer.verify(&issuer.sign(&claims(now - 1))).is_some());
+        assert!(issuer.verify(&issuer.sign(&claims(now - 300))).is_none());
+    }
+
+    #[test]
+    fn challenge_points_to_token_endpoint() {
+        let issuer = TokenIssuer::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "registry.example.com".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+
+        let scope = Access::repository("tests/sample".to_owned(), vec![Action::Pull, Action::Push]);
+        assert_eq!(
+            issuer.challenge(&headers, Some(&scope), Some("insufficient_scope")),
+            "Bearer realm=\"https://registry.example.com/_rockslide/registry/token\",\
+             service=\"rockslide\",scope=\"repository:tests/sample:pull,push\",\
+             error=\"insufficient_scope\""
+        );
+    }
+}
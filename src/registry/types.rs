@@ -1,11 +1,17 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 use axum::{
     body::Body,
-    http::header::CONTENT_TYPE,
+    http::{header::CONTENT_TYPE, StatusCode},
     response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+use super::{storage::Digest, ImageDigest};
+
+pub(crate) const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub(crate) const DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -13,10 +19,29 @@ pub(crate) struct ContentDescriptor {
     media_type: String,
     digest: String, // TODO: Use digest type
     size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     urls: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     artifact_type: Option<String>,
+    // Only present on manifests referenced by an image index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Platform {
+    architecture: String,
+    os: String,
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    os_version: Option<String>,
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
+    os_features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,49 +58,291 @@ pub(crate) struct ImageManifest {
     subject: Option<ContentDescriptor>,
 }
 
+/// An image index, also known as a (Docker) manifest list.
+///
+/// See https://github.com/opencontainers/image-spec/blob/main/image-index.md
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImageIndex {
+    schema_version: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+
+    manifests: Vec<ContentDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<ContentDescriptor>,
+}
+
+/// Any manifest that can be stored in the registry.
+#[derive(Debug)]
+pub(crate) enum Manifest {
+    Image(Box<ImageManifest>),
+    Index(Box<ImageIndex>),
+}
+
+impl ContentDescriptor {
+    fn new(
+        media_type: String,
+        digest: Digest,
+        size: u64,
+        artifact_type: Option<String>,
+        annotations: Option<HashMap<String, String>>,
+    ) -> Self {
+        Self {
+            media_type,
+            digest: ImageDigest::new(digest).to_string(),
+            size,
+            urls: None,
+            annotations,
+            data: None,
+            artifact_type,
+            platform: None,
+        }
+    }
+
+    /// Returns the digest of the described content, if it is a supported (sha256) digest.
+    pub(crate) fn digest(&self) -> Option<Digest> {
+        ImageDigest::from_str(&self.digest)
+            .ok()
+            .map(|image_digest| image_digest.digest)
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn artifact_type(&self) -> Option<&str> {
+        self.artifact_type.as_deref()
+    }
+
+    /// Checks whether the described content is meant for the given platform.
+    ///
+    /// Content without a variant runs on every variant of its architecture, e.g. `arm64` on `v8`.
+    pub(crate) fn is_for_platform(
+        &self,
+        os: &str,
+        architecture: &str,
+        variant: Option<&str>,
+    ) -> bool {
+        let Some(ref platform) = self.platform else {
+            return false;
+        };
+
+        platform.os == os
+            && platform.architecture == architecture
+            && match (variant, platform.variant.as_deref()) {
+                (Some(variant), Some(required)) => variant == required,
+                _ => true,
+            }
+    }
+
+    fn platform_variant(&self) -> Option<&str> {
+        self.platform
+            .as_ref()
+            .and_then(|platform| platform.variant.as_deref())
+    }
+}
+
 impl ImageManifest {
     pub(crate) fn media_type(&self) -> &str {
         self.media_type.as_ref()
     }
+
+    /// Returns descriptors of all blobs referenced by the manifest, i.e. its config and layers.
+    pub(crate) fn blob_descriptors(&self) -> impl Iterator<Item = &ContentDescriptor> {
+        std::iter::once(&self.config).chain(self.layers.iter())
+    }
+}
+
+impl ImageIndex {
+    pub(crate) fn new(manifests: Vec<ContentDescriptor>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX.to_owned()),
+            annotations: None,
+            artifact_type: None,
+            manifests,
+            subject: None,
+        }
+    }
+
+    pub(crate) fn media_type(&self) -> &str {
+        self.media_type.as_deref().unwrap_or(OCI_IMAGE_INDEX)
+    }
+
+    /// Returns descriptors of all manifests contained in the index.
+    pub(crate) fn manifests(&self) -> &[ContentDescriptor] {
+        &self.manifests
+    }
+
+    /// Finds the manifest for the given platform, preferring one built for its exact variant.
+    pub(crate) fn platform_manifest(
+        &self,
+        os: &str,
+        architecture: &str,
+        variant: Option<&str>,
+    ) -> Option<Digest> {
+        let mut candidates = self
+            .manifests
+            .iter()
+            .filter(|descriptor| descriptor.is_for_platform(os, architecture, variant));
+
+        let first = candidates.next()?;
+        std::iter::once(first)
+            .chain(candidates)
+            .find(|descriptor| descriptor.platform_variant() == variant)
+            .unwrap_or(first)
+            .digest()
+    }
+}
+
+impl Manifest {
+    /// Parses a manifest, determining its kind through its media type or, if absent, its fields.
+    pub(crate) fn from_slice(raw: &[u8]) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Probe {
+            media_type: Option<String>,
+            manifests: Option<IgnoredAny>,
+        }
+
+        let probe: Probe = serde_json::from_slice(raw)?;
+        let is_index = match probe.media_type.as_deref() {
+            Some(OCI_IMAGE_INDEX) | Some(DOCKER_MANIFEST_LIST) => true,
+            Some(_) => false,
+            None => probe.manifests.is_some(),
+        };
+
+        if is_index {
+            serde_json::from_slice(raw).map(|index| Manifest::Index(Box::new(index)))
+        } else {
+            serde_json::from_slice(raw).map(|image| Manifest::Image(Box::new(image)))
+        }
+    }
+
+    pub(crate) fn media_type(&self) -> &str {
+        match self {
+            Manifest::Image(image) => image.media_type(),
+            Manifest::Index(index) => index.media_type(),
+        }
+    }
+
+    /// Returns the manifest this manifest refers to, e.g. the image a signature is for.
+    pub(crate) fn subject(&self) -> Option<&ContentDescriptor> {
+        match self {
+            Manifest::Image(image) => image.subject.as_ref(),
+            Manifest::Index(index) => index.subject.as_ref(),
+        }
+    }
+
+    /// Creates a descriptor for this manifest, as listed by the referrers API.
+    ///
+    /// For image manifests without an artifact type, the config media type is used instead.
+    pub(crate) fn referrer_descriptor(&self, digest: Digest, size: u64) -> ContentDescriptor {
+        let (artifact_type, annotations) = match self {
+            Manifest::Image(image) => (
+                image
+                    .artifact_type
+                    .clone()
+                    .or_else(|| Some(image.config.media_type.clone())),
+                image.annotations.clone(),
+            ),
+            Manifest::Index(index) => (index.artifact_type.clone(), index.annotations.clone()),
+        };
+
+        ContentDescriptor::new(
+            self.media_type().to_owned(),
+            digest,
+            size,
+            artifact_type,
+            annotations,
+        )
+    }
 }
 
-// TODO: Return error as:
-// {
-//     "errors:" [{
-//             "code": <error identifier>,
-//             "message": <message describing condition>,
-//             "detail": <unstructured>
-//         },
-//         ...
-//     ]
-// }
+#[derive(Debug, Serialize)]
+pub(crate) struct TagList {
+    name: String,
+    tags: Vec<String>,
+}
+
+impl TagList {
+    pub(crate) fn new(name: String, tags: Vec<String>) -> Self {
+        Self { name, tags }
+    }
+}
 
+#[derive(Debug, Serialize)]
+pub(crate) struct Catalog {
+    repositories: Vec<String>,
+}
+
+impl Catalog {
+    pub(crate) fn new(repositories: Vec<String>) -> Self {
+        Self { repositories }
+    }
+}
+
+/// A single error, as returned inside [`OciErrors`].
+///
+/// See https://github.com/opencontainers/distribution-spec/blob/v1.0.1/spec.md#error-codes
 #[derive(Debug, Serialize)]
 pub(crate) struct OciError {
     code: ErrorCode,
     message: String,
-    // not supported: detail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
 }
 
+/// An error response body, containing one or more errors.
 #[derive(Debug, Serialize)]
 pub(crate) struct OciErrors {
     errors: Vec<OciError>,
 }
 
 impl OciErrors {
+    pub(crate) fn new(errors: Vec<OciError>) -> Self {
+        Self { errors }
+    }
+
     pub(crate) fn single(error: OciError) -> Self {
         Self {
             errors: vec![error],
         }
     }
+
+    /// Returns the HTTP status of the response, derived from the first error.
+    pub(crate) fn status(&self) -> StatusCode {
+        self.errors
+            .first()
+            .map(|error| error.code.status())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
 }
 
 impl OciError {
+    /// Creates a new error with the generic message of its code.
     pub(crate) fn new(code: ErrorCode) -> Self {
         Self {
             code,
             message: code.to_string(),
-        } // TODO: Use actual message
+            detail: None,
+        }
+    }
+
+    pub(crate) fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    pub(crate) fn with_detail<S: Into<String>>(mut self, detail: S) -> Self {
+        self.detail = Some(detail.into());
+        self
     }
 }
 
@@ -98,11 +365,32 @@ pub(crate) enum ErrorCode {
     Unsupported,
     #[serde(rename = "TOOMANYREQUESTS")]
     TooManyRequests,
+    /// Not part of the spec, used for internal errors.
+    Unknown,
 }
 
-// TOOD: Derive HTTP status from error code.
-
 impl ErrorCode {
+    /// Returns the HTTP status code usually associated with the error.
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::BlobUnknown
+            | ErrorCode::BlobUploadUnknown
+            | ErrorCode::ManifestUnknown
+            | ErrorCode::NameUnknown => StatusCode::NOT_FOUND,
+            ErrorCode::BlobUploadInvalid
+            | ErrorCode::DigestInvalid
+            | ErrorCode::ManifestBlobUnknown
+            | ErrorCode::ManifestInvalid
+            | ErrorCode::NameInvalid
+            | ErrorCode::SizeInvalid => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Denied => StatusCode::FORBIDDEN,
+            ErrorCode::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn message(&self) -> &'static str {
         match self {
             ErrorCode::BlobUnknown => "blob unknown to registry",
@@ -119,7 +407,18 @@ impl ErrorCode {
             ErrorCode::Denied => "requested access to the resource is denied",
             ErrorCode::Unsupported => "the operation is unsupported",
             ErrorCode::TooManyRequests => "too many requests",
+            ErrorCode::Unknown => "unknown error",
+        }
+    }
+}
+
+impl Display for OciError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(ref detail) = self.detail {
+            write!(f, ": {}", detail)?;
         }
+        Ok(())
     }
 }
 
@@ -129,20 +428,42 @@ impl Display for ErrorCode {
     }
 }
 
+pub(crate) fn json_response<T: Serialize>(value: &T) -> Response {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_string(value).expect("serialization should not fail"),
+        ))
+        .expect("did not expect body construction to fail")
+}
+
+impl IntoResponse for TagList {
+    fn into_response(self) -> Response {
+        json_response(&self)
+    }
+}
+
+impl IntoResponse for Catalog {
+    fn into_response(self) -> Response {
+        json_response(&self)
+    }
+}
+
 impl IntoResponse for OciErrors {
     fn into_response(self) -> Response {
-        Response::builder()
-            .header(CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                serde_json::to_string(&self).expect("serialization should not fail"),
-            ))
-            .expect("did not expect body construction to fail")
+        (self.status(), json_response(&self)).into_response()
+    }
+}
+
+impl IntoResponse for OciError {
+    fn into_response(self) -> Response {
+        OciErrors::single(self).into_response()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ImageManifest;
+    use super::{ImageManifest, Manifest};
 
     #[test]
     fn simple_example_schema_parse() {
@@ -165,4 +486,109 @@ mod tests {
 
         let _manifest: ImageManifest = serde_json::from_str(raw).expect("could not parse manifest");
     }
+
+    #[test]
+    fn manifest_list_parse() {
+        let raw = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                    "size": 7143,
+                    "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                    "platform": {
+                        "architecture": "ppc64le",
+                        "os": "linux"
+                    }
+                },
+                {
+                    "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                    "size": 7682,
+                    "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+                    "platform": {
+                        "architecture": "amd64",
+                        "os": "linux",
+                        "features": ["sse4"]
+                    }
+                }
+            ]
+        }"#;
+
+        let Manifest::Index(index) = Manifest::from_slice(raw.as_bytes()).expect("could not parse")
+        else {
+            panic!("expected an index");
+        };
+
+        assert_eq!(index.manifests().len(), 2);
+        assert_eq!(
+            index
+                .platform_manifest("linux", "amd64", None)
+                .expect("missing amd64 manifest")
+                .to_string(),
+            "5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"
+        );
+        assert!(index.platform_manifest("windows", "amd64", None).is_none());
+    }
+
+    #[test]
+    fn platform_variants_are_matched() {
+        let raw = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 7143,
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "platform": {
+                        "architecture": "arm",
+                        "os": "linux",
+                        "variant": "v6"
+                    }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 7143,
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "platform": {
+                        "architecture": "arm",
+                        "os": "linux",
+                        "variant": "v7"
+                    }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 7143,
+                    "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                    "platform": {
+                        "architecture": "arm64",
+                        "os": "linux"
+                    }
+                }
+            ]
+        }"#;
+
+        let Manifest::Index(index) = Manifest::from_slice(raw.as_bytes()).expect("could not parse")
+        else {
+            panic!("expected an index");
+        };
+
+        for (architecture, variant, expected) in [
+            ("arm", Some("v6"), Some('1')),
+            ("arm", Some("v7"), Some('2')),
+            ("arm", Some("v5"), None),
+            ("arm", None, Some('1')),
+            ("arm64", Some("v8"), Some('3')),
+            ("arm64", None, Some('3')),
+        ] {
+            assert_eq!(
+                index
+                    .platform_manifest("linux", architecture, variant)
+                    .map(|digest| digest.to_string().chars().next().unwrap()),
+                expected,
+                "{architecture}/{variant:?}"
+            );
+        }
+    }
 }
@@ -0,0 +1,731 @@
+//! User accounts with hashed passwords and roles.
+//!
+//! Accounts are kept in a TOML file next to the registry storage, with passwords stored as bcrypt
+//! hashes only. Usernames without an account fall back to the master key, which keeps existing
+//! setups and the container orchestrator working.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    async_trait,
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sec::Secret;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use super::{
+    auth::{Admin, AuthProvider, Role, UnverifiedCredentials},
+    database::{DatabaseFile, RecordError},
+    pattern::Pattern,
+    token::Action,
+    types,
+};
+use crate::config::MasterKey;
+
+/// Name of the file holding the accounts, inside the storage path.
+const USERS_FILE: &str = "users.toml";
+
+/// Work factor of password hashes. Tests use the minimum, as hashing is deliberately slow.
+#[cfg(not(test))]
+const HASH_COST: u32 = bcrypt::DEFAULT_COST;
+#[cfg(test)]
+const HASH_COST: u32 = 4;
+
+/// Number of verified credentials remembered, the oldest ones are forgotten first.
+const VERIFIED_CAPACITY: usize = 1024;
+
+/// Time after which verified credentials are hashed again.
+const VERIFIED_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Prefix of usernames reserved for rockslide itself, e.g. for the container runtime.
+const RESERVED_PREFIX: &str = "rockslide";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct UserRecord {
+    password_hash: String,
+    role: Role,
+    #[serde(default)]
+    disabled: bool,
+    /// Repositories the user may access. Without any grants, the role applies to all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    grants: Vec<Grant>,
+}
+
+impl UserRecord {
+    fn allows(&self, name: &str, action: Action) -> bool {
+        !self.disabled
+            && (self.grants.is_empty()
+                || self.grants.iter().any(|grant| grant.allows(name, action)))
+    }
+}
+
+/// Access to all repositories matching a pattern, e.g. pulling from `shared/*`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Grant {
+    repository: Pattern,
+    /// Pushing includes pulling.
+    access: Action,
+}
+
+impl Grant {
+    #[cfg(test)]
+    pub(crate) fn new(repository: &str, access: Action) -> Self {
+        Self {
+            repository: Pattern::new(repository),
+            access,
+        }
+    }
+
+    fn allows(&self, name: &str, action: Action) -> bool {
+        (self.access == Action::Push || action == Action::Pull) && self.repository.matches(name)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    #[serde(default)]
+    users: BTreeMap<String, UserRecord>,
+}
+
+/// A user, as listed through the admin API.
+#[derive(Debug, Serialize)]
+pub(crate) struct UserSummary {
+    username: String,
+    role: Role,
+    disabled: bool,
+    grants: Vec<Grant>,
+}
+
+/// Credentials of a newly created or rotated user, only ever shown once.
+#[derive(Debug, Serialize)]
+pub(crate) struct IssuedPassword {
+    username: String,
+    password: String,
+}
+
+/// Account database, persisted to [`USERS_FILE`].
+#[derive(Debug)]
+pub(crate) struct UserStore {
+    file: DatabaseFile,
+    master_key: MasterKey,
+    users: RwLock<BTreeMap<String, UserRecord>>,
+    /// Credentials that passed verification, sparing us from hashing on every request.
+    verified: Mutex<VerifiedCache>,
+}
+
+/// Recently verified credentials, kept as MACs under a key generated at startup.
+///
+/// Plain digests would allow brute-forcing passwords far faster than bcrypt, should the memory
+/// of the process ever leak.
+#[derive(Debug)]
+struct VerifiedCache {
+    key: Secret<[u8; 32]>,
+    verified_at: HashMap<[u8; 32], Instant>,
+}
+
+impl VerifiedCache {
+    fn new() -> Self {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).expect("could not generate verification cache key");
+
+        Self {
+            key: Secret::new(key),
+            verified_at: HashMap::new(),
+        }
+    }
+
+    fn mac(&self, creds: &UnverifiedCredentials) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.reveal())
+            .expect("HMAC can take key of any size");
+        mac.update(creds.username.as_bytes());
+        mac.update(&[0]);
+        mac.update(creds.password.reveal_str().as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn contains(&self, mac: &[u8; 32]) -> bool {
+        self.verified_at
+            .get(mac)
+            .is_some_and(|verified_at| verified_at.elapsed() < VERIFIED_TTL)
+    }
+
+    fn insert(&mut self, mac: [u8; 32]) {
+        self.verified_at
+            .retain(|_, verified_at| verified_at.elapsed() < VERIFIED_TTL);
+
+        if self.verified_at.len() >= VERIFIED_CAPACITY {
+            let oldest = self
+                .verified_at
+                .iter()
+                .min_by_key(|(_, verified_at)| **verified_at)
+                .map(|(mac, _)| *mac);
+            if let Some(oldest) = oldest {
+                self.verified_at.remove(&oldest);
+            }
+        }
+
+        self.verified_at.insert(mac, Instant::now());
+    }
+
+    fn clear(&mut self) {
+        self.verified_at.clear();
+    }
+}
+
+impl UserStore {
+    /// Loads the accounts stored below `storage_path`, starting out empty if there are none.
+    pub(crate) fn open(storage_path: &Path, master_key: MasterKey) -> anyhow::Result<Self> {
+        let file = DatabaseFile::new(storage_path, USERS_FILE, "user database");
+        let users = file.load::<UsersFile>()?.users;
+
+        Ok(Self {
+            file,
+            master_key,
+            users: RwLock::new(users),
+            verified: Mutex::new(VerifiedCache::new()),
+        })
+    }
+
+    pub(crate) fn make_router(self: Arc<Self>) -> Router {
+        Router::new()
+            .route("/_rockslide/registry/users", get(users_list))
+            .route("/_rockslide/registry/users", post(users_add))
+            .route(
+                "/_rockslide/registry/users/:username/rotate",
+                post(users_rotate),
+            )
+            .route(
+                "/_rockslide/registry/users/:username/grants",
+                put(users_grants),
+            )
+            .route(
+                "/_rockslide/registry/users/:username/disable",
+                post(users_disable),
+            )
+            .route(
+                "/_rockslide/registry/users/:username/enable",
+                post(users_enable),
+            )
+            .with_state(self)
+    }
+
+    pub(crate) async fn list(&self) -> Vec<UserSummary> {
+        self.users
+            .read()
+            .await
+            .iter()
+            .map(|(username, record)| UserSummary {
+                username: username.clone(),
+                role: record.role,
+                disabled: record.disabled,
+                grants: record.grants.clone(),
+            })
+            .collect()
+    }
+
+    /// Creates a new user with a random password, which is returned.
+    pub(crate) async fn add(
+        &self,
+        username: &str,
+        role: Role,
+        grants: Vec<Grant>,
+    ) -> Result<Secret<String>, RecordError> {
+        if !is_valid_username(username) {
+            return Err(RecordError::InvalidName("user", username.to_owned()));
+        }
+
+        let mut users = self.users.write().await;
+        if users.contains_key(username) {
+            return Err(RecordError::Exists("user", username.to_owned()));
+        }
+
+        let password = generate_password();
+        let mut updated = users.clone();
+        updated.insert(
+            username.to_owned(),
+            UserRecord {
+                password_hash: hash_password(&password).await?,
+                role,
+                disabled: false,
+                grants,
+            },
+        );
+        self.save(&updated).await?;
+        *users = updated;
+        self.forget_verified();
+
+        info!(%username, ?role, "added user");
+        Ok(password)
+    }
+
+    /// Replaces the password of a user with a new random one, which is returned.
+    pub(crate) async fn rotate(&self, username: &str) -> Result<Secret<String>, RecordError> {
+        let password = generate_password();
+        let password_hash = hash_password(&password).await?;
+
+        self.modify(username, |record| record.password_hash = password_hash)
+            .await?;
+
+        info!(%username, "rotated user password");
+        Ok(password)
+    }
+
+    /// Disables or re-enables a user. Disabled users cannot authenticate.
+    pub(crate) async fn set_disabled(
+        &self,
+        username: &str,
+        disabled: bool,
+    ) -> Result<(), RecordError> {
+        self.modify(username, |record| record.disabled = disabled)
+            .await?;
+
+        info!(%username, disabled, "changed user status");
+        Ok(())
+    }
+
+    /// Replaces the repositories a user may access.
+    pub(crate) async fn set_grants(
+        &self,
+        username: &str,
+        grants: Vec<Grant>,
+    ) -> Result<(), RecordError> {
+        let count = grants.len();
+        self.modify(username, |record| record.grants = grants)
+            .await?;
+
+        info!(%username, grants = count, "changed user grants");
+        Ok(())
+    }
+
+    /// Applies `change` to an existing user and persists the result.
+    async fn modify(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut UserRecord),
+    ) -> Result<(), RecordError> {
+        let mut users = self.users.write().await;
+
+        let mut updated = users.clone();
+        change(
+            updated
+                .get_mut(username)
+                .ok_or_else(|| RecordError::Unknown("user", username.to_owned()))?,
+        );
+        self.save(&updated).await?;
+        *users = updated;
+        self.forget_verified();
+
+        Ok(())
+    }
+
+    /// Writes the accounts to disk, replacing the previous file atomically.
+    async fn save(&self, users: &BTreeMap<String, UserRecord>) -> anyhow::Result<()> {
+        self.file
+            .save(&UsersFile {
+                users: users.clone(),
+            })
+            .await
+    }
+
+    fn forget_verified(&self) {
+        self.verified
+            .lock()
+            .expect("verification cache lock poisoned")
+            .clear();
+    }
+}
+
+#[async_trait]
+impl AuthProvider for UserStore {
+    async fn check_credentials(&self, creds: &UnverifiedCredentials) -> bool {
+        // Held until verification completes, so rotation cannot race a cache insertion.
+        let users = self.users.read().await;
+
+        let Some(record) = users.get(&creds.username) else {
+            return self.master_key.check_credentials(creds).await;
+        };
+
+        if record.disabled {
+            return false;
+        }
+
+        let mac = {
+            let verified = self
+                .verified
+                .lock()
+                .expect("verification cache lock poisoned");
+            let mac = verified.mac(creds);
+            if verified.contains(&mac) {
+                return true;
+            }
+            mac
+        };
+
+        let password = creds.password.clone();
+        let password_hash = record.password_hash.clone();
+        let valid = tokio::task::spawn_blocking(move || {
+            bcrypt::verify(password.reveal_str(), &password_hash).unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false);
+
+        if valid {
+            self.verified
+                .lock()
+                .expect("verification cache lock poisoned")
+                .insert(mac);
+        }
+
+        valid
+    }
+
+    /// Users without an account authenticated through the master key, granting full access.
+    async fn role(&self, username: &str) -> Role {
+        self.users
+            .read()
+            .await
+            .get(username)
+            .map(|record| record.role)
+            .unwrap_or(Role::Admin)
+    }
+
+    async fn has_access_to(
+        &self,
+        username: &str,
+        namespace: &str,
+        image: &str,
+        action: Action,
+    ) -> bool {
+        let name = format!("{}/{}", namespace, image);
+
+        self.users
+            .read()
+            .await
+            .get(username)
+            .is_none_or(|record| record.allows(&name, action))
+    }
+}
+
+/// Checks a username against `[a-z0-9][a-z0-9._-]*`, rejecting names reserved for rockslide.
+pub(super) fn is_valid_username(username: &str) -> bool {
+    let mut chars = username.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+        && !username.starts_with(RESERVED_PREFIX)
+}
+
+pub(super) fn generate_password() -> Secret<String> {
+    let mut raw = [0; 24];
+    getrandom::getrandom(&mut raw).expect("could not generate password");
+    Secret::new(BASE64_URL_SAFE_NO_PAD.encode(raw))
+}
+
+async fn hash_password(password: &Secret<String>) -> anyhow::Result<String> {
+    let password = password.clone();
+
+    tokio::task::spawn_blocking(move || bcrypt::hash(password.reveal_str(), HASH_COST))
+        .await
+        .context("password hashing panicked")?
+        .context("could not hash password")
+}
+
+#[derive(Debug, Deserialize)]
+struct NewUser {
+    username: String,
+    role: Role,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+async fn users_list(State(store): State<Arc<UserStore>>, _admin: Admin) -> Response {
+    types::json_response(&store.list().await)
+}
+
+async fn users_add(
+    State(store): State<Arc<UserStore>>,
+    _admin: Admin,
+    Json(new_user): Json<NewUser>,
+) -> Result<Response, RecordError> {
+    let password = store
+        .add(&new_user.username, new_user.role, new_user.grants)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        types::json_response(&IssuedPassword {
+            username: new_user.username,
+            password: password.reveal_str().to_owned(),
+        }),
+    )
+        .into_response())
+}
+
+async fn users_rotate(
+    State(store): State<Arc<UserStore>>,
+    UrlPath(username): UrlPath<String>,
+    _admin: Admin,
+) -> Result<Response, RecordError> {
+    let password = store.rotate(&username).await?;
+
+    Ok(types::json_response(&IssuedPassword {
+        username,
+        password: password.reveal_str().to_owned(),
+    }))
+}
+
+async fn users_grants(
+    State(store): State<Arc<UserStore>>,
+    UrlPath(username): UrlPath<String>,
+    _admin: Admin,
+    Json(grants): Json<Vec<Grant>>,
+) -> Result<StatusCode, RecordError> {
+    store.set_grants(&username, grants).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn users_disable(
+    State(store): State<Arc<UserStore>>,
+    UrlPath(username): UrlPath<String>,
+    _admin: Admin,
+) -> Result<StatusCode, RecordError> {
+    store.set_disabled(&username, true).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn users_enable(
+    State(store): State<Arc<UserStore>>,
+    UrlPath(username): UrlPath<String>,
+    _admin: Admin,
+) -> Result<StatusCode, RecordError> {
+    store.set_disabled(&username, false).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use sec::Secret;
+    use tempdir::TempDir;
+
+    use super::{Grant, RecordError, UserStore, VerifiedCache, USERS_FILE, VERIFIED_CAPACITY};
+    use crate::{
+        config::MasterKey,
+        registry::{
+            auth::{AuthProvider, Role, UnverifiedCredentials},
+            token::Action,
+        },
+    };
+
+    fn creds(username: &str, password: &Secret<String>) -> UnverifiedCredentials {
+        UnverifiedCredentials {
+            username: username.to_owned(),
+            password: password.clone(),
+        }
+    }
+
+    fn open(tmp: &TempDir) -> UserStore {
+        UserStore::open(tmp.path(), MasterKey::new_key("master-key".to_owned()))
+            .expect("could not open user store")
+    }
+
+    #[tokio::test]
+    async fn users_authenticate_with_their_own_password() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let store = open(&tmp);
+
+        let password = store
+            .add("alice", Role::Push, Vec::new())
+            .await
+            .expect("add failed");
+        assert!(store.check_credentials(&creds("alice", &password)).await);
+        // Cached credentials are accepted as well.
+        assert!(store.check_credentials(&creds("alice", &password)).await);
+        assert_eq!(store.role("alice").await, Role::Push);
+
+        // The master key no longer works for a registered user.
+        let master = Secret::new("master-key".to_owned());
+        assert!(!store.check_credentials(&creds("alice", &master)).await);
+        assert!(!store.check_credentials(&creds("bob", &password)).await);
+
+        // Everyone else still authenticates through the master key, as an administrator.
+        assert!(store.check_credentials(&creds("bob", &master)).await);
+        assert_eq!(store.role("bob").await, Role::Admin);
+
+        assert!(matches!(
+            store.add("alice", Role::Pull, Vec::new()).await,
+            Err(RecordError::Exists(..))
+        ));
+        for invalid in ["", "Alice", "-alice", "al/ice", "rockslide-podman"] {
+            assert!(matches!(
+                store.add(invalid, Role::Pull, Vec::new()).await,
+                Err(RecordError::InvalidName(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn rotation_and_disabling_revoke_access() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let store = open(&tmp);
+
+        let old_password = store
+            .add("alice", Role::Pull, Vec::new())
+            .await
+            .expect("add failed");
+        assert!(
+            store
+                .check_credentials(&creds("alice", &old_password))
+                .await
+        );
+
+        let new_password = store.rotate("alice").await.expect("rotate failed");
+        assert!(
+            !store
+                .check_credentials(&creds("alice", &old_password))
+                .await
+        );
+        assert!(
+            store
+                .check_credentials(&creds("alice", &new_password))
+                .await
+        );
+
+        store
+            .set_disabled("alice", true)
+            .await
+            .expect("disable failed");
+        assert!(
+            !store
+                .check_credentials(&creds("alice", &new_password))
+                .await
+        );
+        assert!(
+            !store
+                .has_access_to("alice", "tests", "sample", Action::Pull)
+                .await
+        );
+
+        store
+            .set_disabled("alice", false)
+            .await
+            .expect("enable failed");
+        assert!(
+            store
+                .check_credentials(&creds("alice", &new_password))
+                .await
+        );
+
+        assert!(matches!(
+            store.rotate("nobody").await,
+            Err(RecordError::Unknown(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn grants_limit_repositories() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+        let store = open(&tmp);
+
+        store
+            .add("alice", Role::Push, Vec::new())
+            .await
+            .expect("add failed");
+        assert!(
+            store
+                .has_access_to("alice", "any", "thing", Action::Push)
+                .await
+        );
+
+        store
+            .set_grants(
+                "alice",
+                vec![
+                    Grant::new("shared/*", Action::Pull),
+                    Grant::new("team-a.example.com/*", Action::Push),
+                ],
+            )
+            .await
+            .expect("setting grants failed");
+
+        for (namespace, image, action, expected) in [
+            ("shared", "base", Action::Pull, true),
+            ("shared", "base", Action::Push, false),
+            ("team-a.example.com", "index", Action::Pull, true),
+            ("team-a.example.com", "index", Action::Push, true),
+            ("team-b.example.com", "index", Action::Pull, false),
+        ] {
+            assert_eq!(
+                store.has_access_to("alice", namespace, image, action).await,
+                expected,
+                "{} {}/{}",
+                action,
+                namespace,
+                image
+            );
+        }
+
+        // Grants survive a restart.
+        assert!(
+            !open(&tmp)
+                .has_access_to("alice", "team-b.example.com", "index", Action::Pull)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn users_are_persisted_as_hashes() {
+        let tmp = TempDir::new("rockslide-test").expect("could not create temporary directory");
+
+        let password = open(&tmp)
+            .add("alice", Role::Admin, Vec::new())
+            .await
+            .expect("add failed");
+
+        let contents =
+            std::fs::read_to_string(tmp.path().join(USERS_FILE)).expect("could not read file");
+        assert!(contents.contains("[users.alice]"));
+        assert!(!contents.contains(password.reveal_str()));
+
+        let reopened = open(&tmp);
+        assert!(reopened.check_credentials(&creds("alice", &password)).await);
+        assert_eq!(reopened.role("alice").await, Role::Admin);
+    }
+
+    #[test]
+    fn verification_cache_is_bounded_and_keyed() {
+        let password = Secret::new("password".to_owned());
+        let mut cache = VerifiedCache::new();
+
+        // Entries depend on the key generated at startup, not just the credentials.
+        let mac = cache.mac(&creds("alice", &password));
+        assert_ne!(mac, VerifiedCache::new().mac(&creds("alice", &password)));
+        cache.insert(mac);
+        assert!(cache.contains(&mac));
+
+        for n in 0..VERIFIED_CAPACITY {
+            cache.insert(cache.mac(&creds(&format!("user{n}"), &password)));
+        }
+        assert_eq!(cache.verified_at.len(), VERIFIED_CAPACITY);
+        assert!(!cache.contains(&mac));
+        assert!(cache.contains(&cache.mac(&creds("user1", &password))));
+    }
+}
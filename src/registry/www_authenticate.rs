@@ -45,9 +45,19 @@ pub(crate) fn basic_auth_response(input: &[u8]) -> IResult<&[u8], BasicAuthRespo
     Ok((input, basic))
 }
 
+/// Parses a bearer token authorization, returning the token.
+pub(crate) fn bearer_token(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let input = skip_whitespace(input);
+
+    let (input, _) = tag_no_case("bearer")(input)?;
+    let input = skip_whitespace(input);
+
+    take_while1(|c: u8| !c.is_ascii_whitespace())(input)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::registry::www_authenticate::{basic_auth_response, BasicAuthResponse};
+    use crate::registry::www_authenticate::{basic_auth_response, bearer_token, BasicAuthResponse};
 
     #[test]
     fn can_parse_known_response() {
@@ -76,4 +86,14 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn can_parse_bearer_token() {
+        assert_eq!(
+            bearer_token(b"Bearer eyJhbGciOiJIUzI1NiJ9.e30.c2ln"),
+            Ok((&b""[..], &b"eyJhbGciOiJIUzI1NiJ9.e30.c2ln"[..]))
+        );
+        assert!(bearer_token(b"Basic YWxhZGRpbjpvcGVuc2VzYW1l").is_err());
+        assert!(bearer_token(b"Bearer ").is_err());
+    }
 }
@@ -23,7 +23,8 @@ use tracing::{info, trace, warn};
 use crate::{
     container_orchestrator::{ContainerOrchestrator, PublishedContainer, RuntimeConfig},
     registry::{
-        storage::ImageLocation, AuthProvider, ManifestReference, Reference, UnverifiedCredentials,
+        storage::ImageLocation, token::Action, AuthProvider, ManifestReference, Reference, Role,
+        UnverifiedCredentials,
     },
 };
 
@@ -454,7 +455,7 @@ async fn route_request(
                 .await
                 .expect("infallible");
 
-            // Any internal URL is subject to requiring auth through the master key.
+            // Any internal URL is subject to requiring auth.
             if !rp.auth_provider.check_credentials(&creds).await {
                 return Err(AppError::AuthFailure {
                     realm: "internal",
@@ -476,6 +477,27 @@ async fn route_request(
                 return Err(AppError::InternalUrlInvalid);
             }
 
+            // Configuration is reserved to administrators. Deploy tokens may configure what they
+            // may deploy, i.e. push to the tag that is being configured.
+            let authorized = if rp.auth_provider.is_deploy_token(&creds.username).await {
+                rp.auth_provider
+                    .has_access_to(&creds.username, parts[0], parts[1], Action::Push)
+                    .await
+                    && rp
+                        .auth_provider
+                        .may_change_tag(&creds.username, parts[0], parts[1], parts[2])
+                        .await
+            } else {
+                rp.auth_provider.role(&creds.username).await >= Role::Admin
+            };
+
+            if !authorized {
+                return Err(AppError::AuthFailure {
+                    realm: "internal",
+                    status: StatusCode::FORBIDDEN,
+                });
+            }
+
             let manifest_reference = ManifestReference::new(
                 ImageLocation::new(parts[0].to_owned(), parts[1].to_owned()),
                 Reference::new_tag(parts[2]),